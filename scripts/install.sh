@@ -4,7 +4,9 @@ if ! cat /etc/os-release|grep Debian;then
     exit 1
 fi
 
-apt install -y ipmitool screen
+# in-band 模式通过 /dev/ipmi0 访问 BMC，不再需要 ipmitool
+modprobe ipmi_devintf ipmi_si || true
+printf "ipmi_devintf\nipmi_si\n" > /etc/modules-load.d/smartfan-ipmi.conf
 
 
 ## get current path
@@ -21,10 +23,14 @@ After=network.target
 Requires=network.target
 
 [Service]
-ExecStart=$current_path/start_with_screen.sh # 没有用
+Type=notify
+NotifyAccess=main
+# 读数成功后才算启动完成；循环 60 秒没有响应（卡住）就重启，BMC 读取失败时由全速保护处理
+WatchdogSec=60
+# 不带界面运行，日志用 journalctl -u smartfan 查看
+ExecStart=$current_path/smartfan run
 WorkingDirectory=$current_path
 Restart=always
-Fork=yes
 RestartSec=5
 User=root
 
@@ -0,0 +1,133 @@
+//! Classifies readings against the thresholds and status the BMC reports with them.
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AlarmSpeed;
+use crate::sensor_result::SensorResult;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Display, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    #[default]
+    #[display("ok")]
+    Ok,
+    #[display("non-critical")]
+    NonCritical,
+    #[display("critical")]
+    Critical,
+    #[display("non-recoverable")]
+    NonRecoverable,
+}
+
+impl Severity {
+    /// ipmitool 的状态列：ok、nc、cr、nr，其他（ns、na）视为 ok
+    pub fn from_status(status: &str) -> Severity {
+        match status {
+            "nc" => Severity::NonCritical,
+            "cr" => Severity::Critical,
+            "nr" => Severity::NonRecoverable,
+            _ => Severity::Ok,
+        }
+    }
+}
+
+/// The classification of one reading.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub sensor: String,
+    pub value: Option<f64>,
+    pub unit: Option<String>,
+    pub severity: Severity,
+    /// 由下限阈值触发，例如风扇转速过低
+    pub low: bool,
+}
+
+impl Alarm {
+    fn is_temperature(&self) -> bool {
+        self.unit.as_deref().is_some_and(|u| u.contains("degrees C"))
+    }
+}
+
+/// The worse of what the thresholds say about the value and what the status column says.
+pub fn evaluate(x: &SensorResult) -> Alarm {
+    let t = &x.thresholds;
+    let (mut severity, mut low) = (Severity::Ok, false);
+    if let Some(v) = x.value {
+        let levels = [
+            (Severity::NonRecoverable, t.lnr, t.unr),
+            (Severity::Critical, t.lc, t.uc),
+            (Severity::NonCritical, t.lnc, t.unc),
+        ];
+        for (level, lower, upper) in levels {
+            if upper.is_some_and(|u| v >= u) {
+                severity = level;
+                break;
+            }
+            if lower.is_some_and(|l| v <= l) {
+                (severity, low) = (level, true);
+                break;
+            }
+        }
+    }
+    let status = x.status.as_deref().map_or(Severity::Ok, Severity::from_status);
+    if status > severity {
+        (severity, low) = (status, false);
+    }
+    Alarm {
+        sensor: x.sensor_name.clone(),
+        value: x.value,
+        unit: x.unit.clone(),
+        severity,
+        low,
+    }
+}
+
+pub fn evaluate_all(sensor_results: &[SensorResult]) -> Vec<Alarm> {
+    sensor_results.iter().map(evaluate).collect()
+}
+
+/// The highest `alarm_speeds` duty whose severity a temperature has reached, going up.
+pub fn override_speed(alarms: &[Alarm], rules: &[AlarmSpeed]) -> Option<u8> {
+    let worst = alarms
+        .iter()
+        .filter(|a| a.is_temperature() && !a.low)
+        .map(|a| a.severity)
+        .max()?;
+    rules
+        .iter()
+        .filter(|r| r.severity != Severity::Ok && worst >= r.severity)
+        .map(|r| r.speed)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarm(line: &str) -> Alarm {
+        evaluate(&SensorResult::from_line(line).unwrap())
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let ok = alarm("CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000");
+        assert_eq!(ok.severity, Severity::Ok);
+        let warm = alarm("CPU1_Temp | 95.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000");
+        assert_eq!(warm.severity, Severity::NonCritical);
+        let hot = alarm("CPU1_Temp | 100.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000");
+        assert_eq!(hot.severity, Severity::Critical);
+        let slow = alarm("Fan1 RPM | 300.000 | RPM | ok | na | 360.000 | 600.000 | na | na | na");
+        assert_eq!((slow.severity, slow.low), (Severity::Critical, true));
+        // 没有阈值时看状态列
+        let psu = alarm("PSU1_Status | na | discrete | nr | na | na | na | na | na | na");
+        assert_eq!(psu.severity, Severity::NonRecoverable);
+
+        let rules: Vec<AlarmSpeed> = serde_yaml::from_str("[{severity: non-critical, speed: 60}, {severity: critical, speed: 100}]").unwrap();
+        assert_eq!(override_speed(std::slice::from_ref(&ok), &rules), None);
+        assert_eq!(override_speed(&[ok.clone(), warm], &rules), Some(60));
+        assert_eq!(override_speed(&[ok.clone(), hot], &rules), Some(100));
+        // 风扇低转速不触发
+        assert_eq!(override_speed(&[ok, slow], &rules), None);
+    }
+}
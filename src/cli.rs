@@ -0,0 +1,155 @@
+//! One-shot subcommands: read the sensors, set or release the fans, check config.yaml, export
+//! the data log.
+//! They use config.yaml in the working directory and the same transports as the loop.
+
+use std::io;
+
+use crate::alarm::{self, Alarm, Severity};
+use crate::config::DataFormat;
+use crate::ipmi::{self, IpmiTransport};
+use crate::profile::{self, FanControl};
+use crate::{control, datalog, sensor};
+
+/// Prints every reading with its severity, as a table or as JSON.
+pub fn sensors(json: bool) -> io::Result<()> {
+    let config = crate::load_config(&crate::config_path())?;
+    let mut transport = ipmi::connect(&config)?;
+    let alarms = alarm::evaluate_all(&sensor::get_all_sensor_data(transport.as_mut())?);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&alarms)?);
+    } else {
+        print!("{}", table(&alarms));
+    }
+    Ok(())
+}
+
+fn table(alarms: &[Alarm]) -> String {
+    let width = alarms.iter().map(|a| a.sensor.chars().count()).max().unwrap_or(0).max("Sensor".len());
+    let mut out = format!("{:<width$}  {:>10}  {:<12}  {}\n", "Sensor", "Value", "Unit", "Status");
+    for a in alarms {
+        let value = a.value.map_or("na".to_string(), |v| v.to_string());
+        let status = match (a.severity, a.low) {
+            (Severity::Ok, _) => "ok".to_string(),
+            (severity, true) => format!("{} (low)", severity),
+            (severity, false) => severity.to_string(),
+        };
+        out += &format!("{:<width$}  {:>10}  {:<12}  {}\n", a.sensor, value, a.unit.as_deref().unwrap_or(""), status);
+    }
+    out
+}
+
+/// Sets every fan, or the fans of one zone, to `speed`. The BMC keeps that speed after exit.
+pub fn set(speed: u8, zone: Option<&str>) -> io::Result<()> {
+    if speed > 100 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("speed {} is over 100", speed)));
+    }
+    refuse_if_running("ctl override")?;
+    let (mut transport, mut fan_control) = connect()?;
+    match zone {
+        Some(zone) => {
+            fan_control.set_zone_speed(transport.as_mut(), zone, speed)?;
+            println!("zone {}: {}%", zone, speed);
+        }
+        None => {
+            fan_control.set_speed(transport.as_mut(), speed, 0)?;
+            println!("fans: {}%", speed);
+        }
+    }
+    Ok(())
+}
+
+/// Hands the fans back to the BMC, e.g. after `set`.
+pub fn auto() -> io::Result<()> {
+    refuse_if_running("ctl pause")?;
+    let (mut transport, mut fan_control) = connect()?;
+    fan_control.force_auto(transport.as_mut())?;
+    println!("Fan control handed back to the BMC");
+    Ok(())
+}
+
+/// Parses and validates config.yaml and finds the profile it would use, without the BMC.
+pub fn check_config() -> io::Result<()> {
+    let path = crate::config_path();
+    let config = crate::load_config(&path)?;
+    println!("{}: ok", path);
+    println!(
+        "mode: {}, inputs: {}, zones: {}, alarm speeds: {}",
+        config.mode,
+        config.inputs.len(),
+        config.zones.len(),
+        config.alarm_speeds.len()
+    );
+    // 不连接 BMC，按 profile 或 server_model 检查将使用的风扇配置
+    let mut forced = crate::load_config(&path)?;
+    forced.force_profile = true;
+    let profile = profile::select(&forced, None)?;
+    if config.force_profile {
+        println!("profile: {}", profile.name);
+    } else {
+        println!("profile: {} (once the BMC reports a model it supports)", profile.name);
+    }
+    Ok(())
+}
+
+/// Writes the data log records in `[from, to)` to stdout, in `format` or the log's own.
+pub fn export(from: Option<&str>, to: Option<&str>, format: Option<DataFormat>) -> io::Result<()> {
+    let config = crate::load_config(&crate::config_path())?;
+    let Some(data_log) = config.data_log else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "config.yaml 中未配置 data_log/data_log is not configured"));
+    };
+    let from = from.map(datalog::parse_time).transpose()?;
+    let to = to.map(datalog::parse_time).transpose()?;
+    let records = datalog::read_all(&data_log)?;
+    let count = datalog::export(&records, from, to, format.unwrap_or(data_log.format), &mut io::stdout().lock())?;
+    eprintln!("Exported {} of {} records", count, records.len());
+    Ok(())
+}
+
+/// The transport and the profile for the server, as the loop picks them.
+fn connect() -> io::Result<(Box<dyn IpmiTransport>, FanControl)> {
+    let config = crate::load_config(&crate::config_path())?;
+    let mut transport = ipmi::connect(&config)?;
+    let identity = transport
+        .identify()
+        .inspect_err(|e| log::warn!("Failed to detect server model: {}", e))
+        .ok();
+    let profile = profile::select(&config, identity.as_ref())?;
+    Ok((transport, FanControl::new(profile)))
+}
+
+/// A running loop would undo whatever a one-shot command sets on its next poll.
+fn refuse_if_running(instead: &str) -> io::Result<()> {
+    let path = control::socket_path();
+    if control::request(&path, &control::Request::Status).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("smartfan 正在运行，请使用 smartfan {}/smartfan is running at {}, use `smartfan {}`", instead, path, instead),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_table() {
+        let sensors = testutil::sensors(&[
+            "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "FAN1_Speed | 300.000 | RPM | cr | na | 600.000 | na | na | na | na",
+            "PSU2_Status | na | discrete | ns | na | na | na | na | na | na",
+        ]);
+        let lines: Vec<String> = table(&alarm::evaluate_all(&sensors)).lines().map(|l| l.trim_end().to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Sensor            Value  Unit          Status",
+                "CPU1_Temp            52  degrees C     ok",
+                "FAN1_Speed          300  RPM           critical (low)",
+                "PSU2_Status          na  discrete      ok",
+            ]
+        );
+    }
+}
@@ -1,11 +1,388 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::alarm::Severity;
+use crate::curve::FanCurve;
+use crate::profile::Profile;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub mode: String,
     pub server_model: String,
+    /// 风扇配置的名字，不填时按 BMC 报告的厂商和型号选择
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// 检测不到型号或型号与 profile 不符时仍然发送风扇命令，此时按 profile 或 server_model 选择
+    #[serde(default)]
+    pub force_profile: bool,
+    /// 自定义的风扇配置，与内置配置同名时优先
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    /// 调用 ipmitool 而不是内置的 RMCP+ 客户端或 /dev/ipmi0
+    #[serde(default)]
+    pub use_ipmitool: bool,
+    /// in-band 模式使用的设备，默认 /dev/ipmi0
+    #[serde(default)]
+    pub ipmi_device: Option<String>,
     pub ipmi: IpmiHostInfo,
-    pub fan_speeds: Vec<FanSpeed>,
+    /// redfish 模式的设置，用户名和密码沿用 ipmi 部分
+    #[serde(default)]
+    pub redfish: Option<RedfishConfig>,
+    /// fan_speeds 阶梯表或 curve 曲线
+    #[serde(flatten)]
+    pub fan_curve: FanCurve,
+    /// 决定主转速的温度传感器，不填时使用最热的 CPU
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    /// 按 zone 单独设置的成员风扇、转速表和温度输入，没有列出的 zone 使用 fan_speeds
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    /// 温度比降档点再低这么多度才降速
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// 转速变化后至少保持这么多秒才能降速
+    #[serde(default)]
+    pub min_dwell_secs: u64,
+    /// curve（默认）按转速表，pid 按目标温度调节主转速
+    #[serde(default)]
+    pub controller: ControllerMode,
+    /// controller: pid 的目标温度和参数
+    #[serde(default)]
+    pub pid: Option<PidConfig>,
+    /// 读取失败或温度异常时全速运转
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    /// 温度达到 BMC 阈值时的最低转速
+    #[serde(default)]
+    pub alarm_speeds: Vec<AlarmSpeed>,
+    /// 风扇停转、转速过低或不随转速升高的检测
+    #[serde(default)]
+    pub fan_check: FanCheckConfig,
+    /// 控制 socket 的路径，默认为工作目录下的 smartfan.sock
+    #[serde(default)]
+    pub control_socket: Option<String>,
+    /// Prometheus 的 /metrics 接口，不配置时不启动
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// 保存每次读数的 SQLite 数据库
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// 每次读数追加到 CSV 或 JSON Lines 文件，供离线分析，不配置时不记录
+    #[serde(default)]
+    pub data_log: Option<DataLogConfig>,
+    /// 每次读数推送到 InfluxDB 或 Graphite
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// 发布到 MQTT，并通过 Home Assistant 自动发现
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+}
+
+impl Config {
+    /// Checks what serde cannot: the fan curves, the PID settings and the ranges of the other
+    /// sections. With `controller: pid` the main curve may be left out, the PID sets the main duty.
+    /// The profile's output limits are checked when it is selected.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hysteresis < 0.0 {
+            return Err(format!("hysteresis {} must not be negative", self.hysteresis));
+        }
+        // PID 模式的主转速不用曲线，只有 inputs 还可能需要它
+        if !(self.controller == ControllerMode::Pid && self.fan_curve.is_empty() && self.inputs.is_empty()) {
+            validate_curves(&self.fan_curve, &self.inputs)?;
+        }
+        for zone in &self.zones {
+            // zone 没有自己的转速表时沿用 fan_speeds
+            let curve = if zone.fan_curve.is_empty() { &self.fan_curve } else { &zone.fan_curve };
+            validate_curves(curve, &zone.inputs).map_err(|e| format!("zone {}: {}", zone.name, e))?;
+        }
+        if let Some(rule) = self.alarm_speeds.iter().find(|r| r.speed > 100) {
+            return Err(format!("alarm speed {} is over 100", rule.speed));
+        }
+        if self.history.downsample_secs == 0 {
+            return Err("history.downsample_secs must be positive".to_string());
+        }
+        if self.sinks.iter().any(|s| s.batch_size == 0) {
+            return Err("sink batch_size must be at least 1".to_string());
+        }
+        if self.mqtt.as_ref().is_some_and(|m| !(1..=crate::control::MAX_OVERRIDE_MINUTES).contains(&m.override_minutes)) {
+            return Err(format!("mqtt.override_minutes must be 1 to {}", crate::control::MAX_OVERRIDE_MINUTES));
+        }
+        if self.fan_check.failed_zone_speed > 100 {
+            return Err(format!("fan_check.failed_zone_speed {} is over 100", self.fan_check.failed_zone_speed));
+        }
+        if self.safety.max_read_failures == 0 {
+            return Err("safety.max_read_failures must be at least 1".to_string());
+        }
+        if self.controller == ControllerMode::Pid {
+            let Some(pid) = &self.pid else {
+                return Err("controller: pid requires a pid section".to_string());
+            };
+            if pid.setpoint.is_nan() || pid.setpoint <= 0.0 || pid.setpoint > 100.0 {
+                return Err(format!("pid setpoint {} must be above 0 and at most 100 ℃", pid.setpoint));
+            }
+            if [pid.kp, pid.ki, pid.kd].iter().any(|g| !g.is_finite() || *g < 0.0) {
+                return Err("pid gains must not be negative".to_string());
+            }
+            if pid.kp == 0.0 && pid.ki == 0.0 && pid.kd == 0.0 {
+                return Err("pid needs at least one of kp, ki, kd above 0".to_string());
+            }
+            if pid.max_slew.is_some_and(|s| s.is_nan() || s <= 0.0) {
+                return Err("pid max_slew must be positive".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The owner's curve may be left out when every input brings its own.
+fn validate_curves(curve: &FanCurve, inputs: &[InputConfig]) -> Result<(), String> {
+    if !curve.is_empty() || inputs.is_empty() || inputs.iter().any(|i| i.fan_curve.is_empty()) {
+        curve.validate()?;
+    }
+    for input in inputs {
+        Regex::new(&input.sensor).map_err(|e| format!("input {}: {}", input.sensor, e))?;
+        if !input.fan_curve.is_empty() {
+            input.fan_curve.validate().map_err(|e| format!("input {}: {}", input.sensor, e))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControllerMode {
+    #[default]
+    Curve,
+    Pid,
+}
+
+/// 输出 = kp * 误差 + ki * 误差积分 + kd * 误差变化率，误差为 CPU 温度减去 setpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PidConfig {
+    /// 目标 CPU 温度 ℃
+    pub setpoint: f64,
+    pub kp: f64,
+    #[serde(default)]
+    pub ki: f64,
+    #[serde(default)]
+    pub kd: f64,
+    /// 转速每秒最多变化多少 %，不填时不限制
+    #[serde(default)]
+    pub max_slew: Option<f64>,
+}
+
+/// Any temperature at `severity` or worse (by its upper thresholds or the status column)
+/// raises every zone to at least `speed`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlarmSpeed {
+    /// non-critical (unc)、critical (uc) 或 non-recoverable (unr)
+    pub severity: Severity,
+    pub speed: u8,
+}
+
+/// 连续读取失败、没有 CPU 温度或任一传感器达到 uc 阈值时，风扇全速运转
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SafetyConfig {
+    /// 连续失败多少次后全速
+    #[serde(default = "default_max_read_failures")]
+    pub max_read_failures: u32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            max_read_failures: default_max_read_failures(),
+        }
+    }
+}
+
+fn default_max_read_failures() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanCheckConfig {
+    pub enabled: bool,
+    /// 低于这个转速% 时风扇可能本来就停转，不做检查
+    pub min_duty: u8,
+    /// 转速低于同组风扇中位数的这个比例时视为故障
+    pub min_peer_ratio: f64,
+    /// 转速% 升高至少这么多而 RPM 没有升高时视为故障
+    pub min_step: u8,
+    /// 有风扇故障的 zone 使用的转速
+    pub failed_zone_speed: u8,
+}
+
+impl Default for FanCheckConfig {
+    fn default() -> Self {
+        FanCheckConfig {
+            enabled: true,
+            min_duty: 20,
+            min_peer_ratio: 0.5,
+            min_step: 20,
+            failed_zone_speed: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// 默认关闭，开启后每次读数都写入数据库
+    pub enabled: bool,
+    /// 数据库文件，相对于工作目录
+    pub path: String,
+    /// 超过这么多天的数据删除
+    pub retention_days: u32,
+    /// 超过这么多小时的数据按 downsample_secs 合并
+    pub raw_hours: u32,
+    pub downsample_secs: u64,
+    /// 界面启动时在历史图中显示最近这么多小时，0 表示不加载
+    pub chart_hours: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: false,
+            path: "smartfan.db".to_string(),
+            retention_days: 30,
+            raw_hours: 24,
+            downsample_secs: 300,
+            chart_hours: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl std::str::FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(DataFormat::Csv),
+            "jsonl" => Ok(DataFormat::Jsonl),
+            _ => Err(format!("unknown format {}, expected csv or jsonl", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataLogConfig {
+    /// 日志文件，相对于工作目录；轮转后的旧文件为 path.1、path.2 ...
+    pub path: String,
+    #[serde(default)]
+    pub format: DataFormat,
+    /// 超过这么多 MB 时轮转
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// 保留的旧文件数
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_keep() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// 每次请求最多发送的点数
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// 目标不可用时最多缓存的点数，超出时丢弃最早的
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    /// InfluxDB v2 的 /api/v2/write
+    Influx {
+        /// 例如 http://10.0.0.5:8086
+        url: String,
+        org: String,
+        bucket: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Graphite plaintext 协议，例如 10.0.0.5:2003
+    Graphite {
+        address: String,
+        #[serde(default = "default_graphite_prefix")]
+        prefix: String,
+    },
+}
+
+fn default_batch_size() -> usize {
+    1000
+}
+
+fn default_buffer_size() -> usize {
+    100_000
+}
+
+fn default_graphite_prefix() -> String {
+    "smartfan".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// 主题中区分服务器的名字，默认取 ipmi.host
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// 状态和命令主题的前缀：<prefix>/<node_id>/state、<prefix>/<node_id>/set
+    #[serde(default = "default_mqtt_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// 通过命令主题设置的转速保持这么多分钟
+    #[serde(default = "default_override_minutes")]
+    pub override_minutes: u64,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_prefix() -> String {
+    "smartfan".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_override_minutes() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    /// 监听地址，例如 0.0.0.0:9632
+    pub listen: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,10 +390,152 @@ pub struct IpmiHostInfo {
     pub host: String,
     pub username: String,
     pub password: String,
+    /// RMCP+ 加密套件，3 (SHA1) 或 17 (SHA256)
+    #[serde(default = "default_cipher_suite")]
+    pub cipher_suite: u8,
 }
 
+fn default_cipher_suite() -> u8 {
+    3
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedfishConfig {
+    /// 默认 https://<ipmi.host>
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub verify_tls: bool,
+    /// 设置风扇的 OEM 请求，不配置时自动识别 iDRAC
+    #[serde(default)]
+    pub fan_control: Option<RedfishFanControl>,
+}
+
+/// An OEM request that sets the fan duty. `{duty}` and `{zone}` in `body` are replaced before sending.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedfishFanControl {
+    #[serde(default = "default_fan_control_method")]
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+fn default_fan_control_method() -> String {
+    "PATCH".to_string()
+}
+
+/// A fan zone with its own curve. Zones of the profile can be given other member fans here,
+/// and zones the profile does not know can be added with an `id`.
+///
+/// ```yaml
+/// zones:
+///   - name: disks
+///     id: 2                   # set_duty 中的 {zone}
+///     fans: ['^FAN[56]']
+///     curve: [[30, 20], [50, 100]]
+///     inputs:
+///       - sensor: ^NVMe
+/// ```
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneConfig {
+    /// 风扇配置中 zone 的名字或编号
+    pub name: String,
+    /// profile 中没有的 zone 需要填写编号
+    #[serde(default)]
+    pub id: Option<u8>,
+    /// 属于这个 zone 的风扇传感器，正则表达式，覆盖 profile 中的设置
+    #[serde(default)]
+    pub fans: Vec<String>,
+    /// 不填时使用 fan_speeds
+    #[serde(flatten)]
+    pub fan_curve: FanCurve,
+    /// 这个 zone 的温度传感器，不填时使用最热的 CPU
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+}
+
+/// A temperature that feeds a curve. The duty of a zone is the highest of its inputs' outputs,
+/// so any input can speed the fans up.
+///
+/// ```yaml
+/// inputs:
+///   - sensor: CPU\d_Temp
+///   - sensor: ^NVMe          # 硬盘 50℃ 按 CPU 75℃ 算
+///     offset: 25
+///   - sensor: Inlet Temp     # 自己的曲线
+///     curve: [[25, 20], [35, 60], [40, 100]]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputConfig {
+    /// 传感器名或正则表达式，多个传感器匹配时取最高温度
+    pub sensor: String,
+    /// 送入曲线的温度为 读数 * weight + offset
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// 这个输入自己的转速表，不填时使用所在 zone（或主）的转速表
+    #[serde(flatten)]
+    pub fan_curve: FanCurve,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FanSpeed {
     pub temp_range: [f64; 2],
     pub speed: u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Result<Config, String> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {{host: bmc, username: admin, password: admin}}
+{}",
+            extra
+        ))
+        .unwrap();
+        config.validate().map(|()| config)
+    }
+
+    #[test]
+    fn test_validate_pid() {
+        // PID 不需要主曲线，曲线模式需要
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: 2}").is_ok());
+        assert!(config("").unwrap_err().contains("fan_speeds or curve"));
+        // inputs 没有自己的曲线时仍然需要主曲线
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: 2}\ninputs: [{sensor: NVMe}]").is_err());
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: 2}\ninputs: [{sensor: NVMe, curve: [[40, 20], [70, 100]]}]").is_ok());
+
+        assert!(config("controller: pid").unwrap_err().contains("pid section"));
+        assert!(config("controller: pid\npid: {setpoint: 0, kp: 2}").unwrap_err().contains("setpoint"));
+        assert!(config("controller: pid\npid: {setpoint: 150, kp: 2}").unwrap_err().contains("setpoint"));
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: -1}").unwrap_err().contains("gains"));
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: 0}").unwrap_err().contains("at least one"));
+        assert!(config("controller: pid\npid: {setpoint: 65, kp: 2, max_slew: 0}").unwrap_err().contains("max_slew"));
+
+        // 输出范围在选择 profile 时检查
+        let custom = config(
+            "controller: pid
+pid: {setpoint: 65, kp: 2}
+profile: narrow
+force_profile: true
+profiles:
+  - name: narrow
+    set_duty: 0x2e 0x30 0x00 {zone} {duty}
+    min_duty: 80
+    max_duty: 20
+",
+        )
+        .unwrap();
+        let err = crate::profile::select(&custom, None).unwrap_err();
+        assert!(err.to_string().contains("min_duty 80 <= max_duty 20"));
+    }
+}
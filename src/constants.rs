@@ -1,2 +1,3 @@
 pub static IN_BAND: &str = "in-band";
 pub static OUT_BAND: &str = "out-band";
+pub static REDFISH: &str = "redfish";
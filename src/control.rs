@@ -0,0 +1,428 @@
+//! Local control socket of a running instance, one JSON object per line each way.
+//!
+//! `{"cmd":"status"}` returns the last reading, the duty and the active profile. The commands
+//! `override`, `pause`, `resume`, `profile` and `reload` change what the loop does. The loop
+//! itself answers every request between polls; the socket threads only pass them on.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::{Duration, Instant};
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::alarm::Alarm;
+use crate::fan_check::FanFault;
+use crate::sensor_result::SensorResult;
+use crate::{sensor, Message, UIMessage};
+
+/// 配置里没有 control_socket 时使用，相对于工作目录
+pub const DEFAULT_SOCKET: &str = "smartfan.sock";
+
+/// 手动转速最长保持一天
+pub const MAX_OVERRIDE_MINUTES: u64 = 24 * 60;
+
+#[derive(Debug, Clone, PartialEq, Display, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    #[display("status")]
+    Status,
+    /// 所有风扇固定为 speed%，minutes 分钟后恢复自动
+    #[display("override {}% for {} min", speed, minutes)]
+    Override { speed: u8, minutes: u64 },
+    /// 不再发送风扇命令，交给 BMC 控制
+    #[display("pause")]
+    Pause,
+    /// 取消暂停和手动转速
+    #[display("resume")]
+    Resume,
+    #[display("profile {}", name)]
+    Profile { name: String },
+    /// 重新读取 config.yaml，连接设置除外
+    #[display("reload")]
+    Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl Response {
+    pub fn ok(message: String) -> Response {
+        Response { ok: true, message: Some(message), status: None }
+    }
+
+    pub fn error(message: String) -> Response {
+        Response { ok: false, message: Some(message), status: None }
+    }
+
+    pub fn status(status: Status) -> Response {
+        Response { ok: true, message: None, status: Some(status) }
+    }
+}
+
+/// What the loop is doing, as returned by `status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub profile: String,
+    pub server_model: Option<String>,
+    pub paused: bool,
+    /// 手动转速和剩余秒数
+    pub manual: Option<(u8, u64)>,
+    pub setpoint: Option<f64>,
+    /// 全速运转的原因
+    pub fail_safe: Option<String>,
+    /// 还没有成功读取过时为空
+    pub reading: Option<Reading>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.server_model {
+            Some(model) => writeln!(f, "profile: {} ({})", self.profile, model)?,
+            None => writeln!(f, "profile: {}", self.profile)?,
+        }
+        match (self.paused, self.manual) {
+            (true, _) => writeln!(f, "control: paused, the BMC controls the fans")?,
+            (false, Some((speed, secs))) => writeln!(f, "control: manual {}% for {} more seconds", speed, secs)?,
+            (false, None) => writeln!(f, "control: automatic")?,
+        }
+        if let Some(setpoint) = self.setpoint {
+            writeln!(f, "setpoint: {}℃", setpoint)?;
+        }
+        if let Some(fault) = &self.fail_safe {
+            writeln!(f, "fail-safe: {}", fault)?;
+        }
+        let Some(reading) = &self.reading else {
+            return write!(f, "no reading yet");
+        };
+        write!(f, "{} temp: {}℃", reading.time, reading.temperature)?;
+        if let Some(speed) = reading.speed {
+            write!(f, ", speed: {}%", speed)?;
+        }
+        for (zone, speed) in &reading.zone_speeds {
+            write!(f, ", zone {}: {}%", zone, speed)?;
+        }
+        writeln!(f)?;
+        for (fan, rpm) in &reading.fans {
+            writeln!(f, "{}: {} RPM", fan, rpm)?;
+        }
+        for (sensor, watts) in &reading.power {
+            writeln!(f, "{}: {} W", sensor, watts)?;
+        }
+        for alarm in reading.alarms.iter().filter(|a| a.severity != crate::alarm::Severity::Ok) {
+            writeln!(f, "alarm: {} {}", alarm.sensor, alarm.severity)?;
+        }
+        for fault in &reading.fan_faults {
+            writeln!(f, "fan failure: {}", fault)?;
+        }
+        Ok(())
+    }
+}
+
+/// One successful sensor read and what was done with it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    pub time: String,
+    pub temperature: f64,
+    /// 主转速，暂停时为空
+    pub speed: Option<u8>,
+    /// zone id、转速
+    pub zone_speeds: Vec<(u8, u8)>,
+    /// 有温度的 CPU 数、CPU 插槽数
+    pub cpus: (usize, usize),
+    pub fans: Vec<(String, f64)>,
+    pub zones: Vec<sensor::ZoneFans>,
+    pub power: Vec<(String, f64)>,
+    pub alarms: Vec<Alarm>,
+    pub fan_faults: Vec<FanFault>,
+    /// 完整的读数，只在进程内使用
+    #[serde(skip)]
+    pub sensors: Vec<SensorResult>,
+}
+
+/// A manual duty that expires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Manual {
+    pub speed: u8,
+    pub until: Instant,
+}
+
+impl Manual {
+    /// None when the deadline does not fit in an [`Instant`].
+    pub fn new(speed: u8, minutes: u64, now: Instant) -> Option<Manual> {
+        let until = now.checked_add(Duration::from_secs(minutes.checked_mul(60)?))?;
+        Some(Manual { speed, until })
+    }
+
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.until.saturating_duration_since(now)
+    }
+}
+
+/// A request from a socket client, and where its answer goes.
+pub type Call = (Request, oneshot::Sender<Response>);
+
+/// `control_socket` from config.yaml in the working directory, or [`DEFAULT_SOCKET`].
+pub fn socket_path() -> String {
+    crate::load_config(&crate::config_path())
+        .ok()
+        .and_then(|c| c.control_socket)
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string())
+}
+
+/// Removes the socket file when the loop ends.
+pub struct Server {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    path: String,
+}
+
+#[cfg(unix)]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on `path` and passes every request to `calls`. Each client gets its own thread.
+/// Fails when another instance is already listening there; a stale file is replaced.
+#[cfg(unix)]
+pub fn serve(path: &str, calls: Sender<Call>) -> io::Result<Server> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if std::fs::metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another instance", path)));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // 可以修改转速，只允许同一用户访问
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let calls = calls.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handle_client(stream, calls) {
+                    log::debug!("control client: {}", e);
+                }
+            });
+        }
+    });
+    Ok(Server { path: path.to_string() })
+}
+
+#[cfg(not(unix))]
+pub fn serve(_path: &str, _calls: Sender<Call>) -> io::Result<Server> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "控制 socket 只支持 unix/the control socket needs unix"))
+}
+
+#[cfg(unix)]
+fn handle_client(stream: std::os::unix::net::UnixStream, calls: Sender<Call>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (tx, rx) = oneshot::channel();
+                if calls.blocking_send((request, tx)).is_err() {
+                    return Ok(());
+                }
+                rx.blocking_recv().unwrap_or_else(|_| Response::error("loop stopped".to_string()))
+            }
+            Err(e) => Response::error(format!("bad request: {}", e)),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+/// Sends one request to the instance listening on `path` and waits for its answer.
+#[cfg(unix)]
+pub fn request(path: &str, request: &Request) -> io::Result<Response> {
+    let stream = std::os::unix::net::UnixStream::connect(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("smartfan 没有运行或 {} 不可用/cannot connect to {}: {}", path, path, e)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(unix))]
+pub fn request(_path: &str, _request: &Request) -> io::Result<Response> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "控制 socket 只支持 unix/the control socket needs unix"))
+}
+
+/// Feeds the TUI from a running instance instead of a loop of its own: polls `status` every
+/// second and forwards the TUI's commands. Returns when the TUI goes away.
+pub fn attach(path: &str, send_to_ui: Sender<Message>, mut receive_from_ui: Receiver<UIMessage>) {
+    let log = |level, msg: String| send_to_ui.blocking_send(Message::build_log(level, msg)).is_ok();
+    let mut last = Status {
+        profile: String::new(),
+        server_model: None,
+        paused: false,
+        manual: None,
+        setpoint: None,
+        fail_safe: None,
+        reading: None,
+    };
+    let mut connected = None;
+    loop {
+        loop {
+            match receive_from_ui.try_recv() {
+                Ok(UIMessage::Control(request)) => {
+                    let sent = match request_result(path, &request) {
+                        Ok(msg) => log(log::Level::Info, format!("{}: {}", request, msg)),
+                        Err(e) => log(log::Level::Error, format!("{}: {}", request, e)),
+                    };
+                    if !sent {
+                        return;
+                    }
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return,
+            }
+        }
+        let status = request(path, &Request::Status)
+            .and_then(|r| r.status.ok_or_else(|| io::Error::other(r.message.unwrap_or_default())));
+        match status {
+            Ok(status) => {
+                if connected != Some(true) {
+                    log(log::Level::Info, format!("Attached to {}", path));
+                    connected = Some(true);
+                }
+                for msg in status_messages(&last, &status) {
+                    if send_to_ui.blocking_send(msg).is_err() {
+                        return;
+                    }
+                }
+                last = status;
+            }
+            Err(e) => {
+                if connected != Some(false) && !log(log::Level::Error, e.to_string()) {
+                    return;
+                }
+                connected = Some(false);
+            }
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// The message of a successful response, or its error.
+fn request_result(path: &str, req: &Request) -> io::Result<String> {
+    let response = request(path, req)?;
+    let message = response.message.unwrap_or_default();
+    if response.ok {
+        Ok(message)
+    } else {
+        Err(io::Error::other(message))
+    }
+}
+
+/// What changed between two `status` answers, as the messages the loop would have sent.
+pub fn status_messages(last: &Status, status: &Status) -> Vec<Message> {
+    let mut messages = vec![];
+    let time_str = chrono::Local::now().format("%H:%M:%S").to_string();
+    if status.server_model != last.server_model {
+        if let Some(model) = &status.server_model {
+            messages.push(Message::ServerModel(time_str.clone(), model.clone()));
+        }
+    }
+    if status.setpoint != last.setpoint {
+        if let Some(setpoint) = status.setpoint {
+            messages.push(Message::Setpoint(time_str.clone(), setpoint));
+        }
+    }
+    if status.profile != last.profile {
+        messages.push(Message::build_log(log::Level::Info, format!("Using fan profile {}", status.profile)));
+    }
+    if status.paused != last.paused || status.manual.map(|m| m.0) != last.manual.map(|m| m.0) {
+        let state = match (status.paused, status.manual) {
+            (true, _) => "Control paused, the BMC is in charge".to_string(),
+            (false, Some((speed, secs))) => format!("Manual override {}% for {} more seconds", speed, secs),
+            (false, None) => "Automatic control".to_string(),
+        };
+        messages.push(Message::build_log(log::Level::Info, state));
+    }
+    if status.fail_safe != last.fail_safe {
+        let msg = match &status.fail_safe {
+            Some(fault) => (log::Level::Error, format!("Fail-safe engaged, fans at 100%: {}", fault)),
+            None => (log::Level::Warn, "Fail-safe released".to_string()),
+        };
+        messages.push(Message::build_log(msg.0, msg.1));
+    }
+    let Some(reading) = &status.reading else {
+        return messages;
+    };
+    if last.reading.as_ref().map(|r| &r.time) == Some(&reading.time) {
+        return messages;
+    }
+    let time = reading.time.clone();
+    messages.push(Message::Alarms(time.clone(), reading.alarms.clone()));
+    messages.push(Message::FanFaults(time.clone(), reading.fan_faults.clone()));
+    messages.push(Message::GotCpuAndFansSpeed(time.clone(), reading.cpus, reading.fans.clone()));
+    if let Some(speed) = reading.speed {
+        messages.push(Message::SetFanSpeed(time.clone(), reading.temperature, speed));
+        messages.push(Message::ZoneFans(time.clone(), reading.zones.clone()));
+    }
+    messages.push(Message::Power(time, reading.power.clone()));
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol() {
+        let request: Request = serde_json::from_str(r#"{"cmd":"override","speed":60,"minutes":10}"#).unwrap();
+        assert_eq!(request, Request::Override { speed: 60, minutes: 10 });
+        assert_eq!(serde_json::to_string(&Request::Status).unwrap(), r#"{"cmd":"status"}"#);
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"explode"}"#).is_err());
+        assert_eq!(serde_json::to_string(&Response::ok("paused".to_string())).unwrap(), r#"{"ok":true,"message":"paused"}"#);
+
+        let now = Instant::now();
+        let manual = Manual::new(60, 10, now).unwrap();
+        assert_eq!(manual.remaining(now), Duration::from_secs(600));
+        assert_eq!(manual.remaining(now + Duration::from_secs(700)), Duration::ZERO);
+        assert_eq!(Manual::new(60, u64::MAX, now), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket() {
+        let path = std::env::temp_dir().join(format!("smartfan-control-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let (tx, mut rx) = mpsc::channel::<Call>(1);
+        let server = serve(&path, tx.clone()).unwrap();
+        // 同一路径不能再启动一个
+        assert_eq!(serve(&path, tx).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+        let calls = std::thread::spawn(move || {
+            while let Some((request, reply)) = rx.blocking_recv() {
+                let _ = reply.send(Response::ok(request.to_string()));
+            }
+        });
+
+        let response = request(&path, &Request::Override { speed: 60, minutes: 10 }).unwrap();
+        assert_eq!(response, Response::ok("override 60% for 10 min".to_string()));
+        assert!(request_result(&path, &Request::Pause).is_ok());
+        drop(server);
+        assert!(request(&path, &Request::Status).is_err());
+        drop(calls);
+    }
+}
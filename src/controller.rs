@@ -0,0 +1,231 @@
+//! Turns a fan curve into the duty to send, with hysteresis and a minimum dwell time so the
+//! fans do not hunt around a step boundary, or runs a PID loop towards a temperature setpoint.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ControllerMode, PidConfig};
+use crate::curve::FanCurve;
+use crate::profile::Profile;
+
+#[derive(Debug, Default)]
+struct State {
+    duty: u8,
+    changed_at: Option<Instant>,
+}
+
+/// Remembers the last duty of every curve (the main one and each zone's) between polls.
+/// With `controller: pid` the main duty comes from a [`Pid`] instead of the curve.
+#[derive(Debug, Default)]
+pub struct Controller {
+    hysteresis: f64,
+    min_dwell: Duration,
+    states: HashMap<String, State>,
+    pid: Option<Pid>,
+}
+
+impl Controller {
+    pub fn new(hysteresis: f64, min_dwell: Duration) -> Controller {
+        Controller {
+            hysteresis,
+            min_dwell,
+            states: HashMap::new(),
+            pid: None,
+        }
+    }
+
+    pub fn from_config(config: &Config, profile: &Profile) -> Controller {
+        let mut controller = Controller::new(config.hysteresis, Duration::from_secs(config.min_dwell_secs));
+        if config.controller == ControllerMode::Pid {
+            if let Some(pid) = &config.pid {
+                controller.pid = Some(Pid::new(pid, profile.min_duty, profile.max_duty));
+            }
+        }
+        controller
+    }
+
+    /// The temperature the PID controller aims for, if it is enabled.
+    pub fn setpoint(&self) -> Option<f64> {
+        self.pid.as_ref().map(|p| p.config.setpoint)
+    }
+
+    /// The main duty: from the PID controller when enabled, otherwise from `curve`.
+    pub fn update_main(&mut self, curve: &FanCurve, temp: f64, now: Instant) -> u8 {
+        match &mut self.pid {
+            Some(pid) => pid.update(temp, now),
+            None => self.update("", curve, temp, now),
+        }
+    }
+
+    /// The duty for the curve called `key` at `temp`.
+    ///
+    /// Speeding up happens at once. Slowing down waits until the temperature is `hysteresis`
+    /// degrees below the point where the curve would have dropped, and until `min_dwell` has
+    /// passed since the last change.
+    pub fn update(&mut self, key: &str, curve: &FanCurve, temp: f64, now: Instant) -> u8 {
+        let target = curve.speed(temp);
+        let Some(state) = self.states.get_mut(key) else {
+            self.states.insert(
+                key.to_string(),
+                State {
+                    duty: target,
+                    changed_at: Some(now),
+                },
+            );
+            return target;
+        };
+
+        let duty = if target >= state.duty {
+            target
+        } else {
+            let dwelling = state.changed_at.is_some_and(|t| now.duration_since(t) < self.min_dwell);
+            if dwelling {
+                state.duty
+            } else {
+                // 温度再高 hysteresis 度时仍然低于当前转速，才降速
+                curve.speed(temp + self.hysteresis).clamp(target, state.duty)
+            }
+        };
+        if duty != state.duty {
+            state.duty = duty;
+            state.changed_at = Some(now);
+        }
+        duty
+    }
+}
+
+/// PID regulator on the CPU temperature. A positive error (too hot) raises the duty.
+#[derive(Debug)]
+pub struct Pid {
+    config: PidConfig,
+    min: f64,
+    max: f64,
+    integral: f64,
+    last: Option<(Instant, f64, f64)>,
+}
+
+impl Pid {
+    pub fn new(config: &PidConfig, min_duty: u8, max_duty: u8) -> Pid {
+        Pid {
+            config: config.clone(),
+            min: min_duty as f64,
+            max: max_duty as f64,
+            integral: 0.0,
+            last: None,
+        }
+    }
+
+    pub fn update(&mut self, temp: f64, now: Instant) -> u8 {
+        let c = &self.config;
+        let error = temp - c.setpoint;
+        let (dt, derivative) = match self.last {
+            Some((t, last_error, _)) => {
+                let dt = now.duration_since(t).as_secs_f64();
+                (dt, if dt > 0.0 { (error - last_error) / dt } else { 0.0 })
+            }
+            None => (0.0, 0.0),
+        };
+
+        let integral = self.integral + error * dt;
+        let unclamped = c.kp * error + c.ki * integral + c.kd * derivative;
+        let mut output = unclamped.clamp(self.min, self.max);
+        // 抗积分饱和：输出饱和时只允许积分朝退出饱和的方向变化
+        let saturated_high = unclamped > self.max && error > 0.0;
+        let saturated_low = unclamped < self.min && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = integral;
+        }
+
+        if let (Some(max_slew), Some((_, _, last_output))) = (c.max_slew, self.last) {
+            let step = max_slew * dt;
+            output = output.clamp(last_output - step, last_output + step);
+        }
+        self.last = Some((now, error, output));
+        output.round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FanSpeed;
+
+    fn steps() -> FanCurve {
+        FanCurve {
+            fan_speeds: [(0.0, 50.0, 20), (50.0, 60.0, 30), (60.0, 100.0, 40)]
+                .iter()
+                .map(|(from, to, speed)| FanSpeed {
+                    temp_range: [*from, *to],
+                    speed: *speed,
+                })
+                .collect(),
+            curve: vec![],
+        }
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let curve = steps();
+        let mut controller = Controller::new(3.0, Duration::ZERO);
+        let now = Instant::now();
+
+        assert_eq!(controller.update("", &curve, 61.0, now), 40);
+        // 在边界附近来回不会降速
+        assert_eq!(controller.update("", &curve, 59.0, now), 40);
+        assert_eq!(controller.update("", &curve, 60.5, now), 40);
+        assert_eq!(controller.update("", &curve, 57.5, now), 40);
+        assert_eq!(controller.update("", &curve, 56.9, now), 30);
+        // 升速不受影响
+        assert_eq!(controller.update("", &curve, 60.0, now), 40);
+        // 每条曲线各自记录
+        assert_eq!(controller.update("zone", &curve, 20.0, now), 20);
+    }
+
+    #[test]
+    fn test_pid() {
+        let config = PidConfig {
+            setpoint: 60.0,
+            kp: 2.0,
+            ki: 0.1,
+            kd: 0.0,
+            max_slew: None,
+        };
+        let mut pid = Pid::new(&config, 10, 80);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // 比例项，并限制在 profile 的转速范围内
+        assert_eq!(pid.update(70.0, at(0)), 20);
+        assert_eq!(pid.update(55.0, at(0)), 10);
+        assert_eq!(pid.update(120.0, at(0)), 80);
+
+        // 一直饱和时积分不会继续累积，温度回落后马上降速
+        for i in 1..=20 {
+            assert_eq!(pid.update(120.0, at(i * 10)), 80);
+        }
+        assert!(pid.update(61.0, at(210)) < 80);
+
+        // 升降速率限制，每秒 1%
+        let config = PidConfig {
+            max_slew: Some(1.0),
+            ..config
+        };
+        let mut pid = Pid::new(&config, 0, 100);
+        assert_eq!(pid.update(60.0, at(0)), 0);
+        assert_eq!(pid.update(100.0, at(15)), 15);
+        assert_eq!(pid.update(100.0, at(30)), 30);
+    }
+
+    #[test]
+    fn test_min_dwell() {
+        let curve = steps();
+        let mut controller = Controller::new(0.0, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert_eq!(controller.update("", &curve, 65.0, start), 40);
+        assert_eq!(controller.update("", &curve, 55.0, start + Duration::from_secs(30)), 40);
+        assert_eq!(controller.update("", &curve, 40.0, start + Duration::from_secs(61)), 20);
+        assert_eq!(controller.update("", &curve, 55.0, start + Duration::from_secs(62)), 30);
+        assert_eq!(controller.update("", &curve, 40.0, start + Duration::from_secs(100)), 30);
+    }
+}
@@ -0,0 +1,158 @@
+//! Temperature to fan duty mapping: the `fan_speeds` step table or a `curve` of points.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::FanSpeed;
+
+/// Either form may be used in config.yaml, but not both at once.
+///
+/// ```yaml
+/// fan_speeds:            # steps: [from, to) -> speed
+///   - temp_range: [0, 50]
+///     speed: 20
+/// curve: [[40, 15], [60, 30], [80, 100]]   # (temperature, speed), interpolated
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FanCurve {
+    #[serde(default)]
+    pub fan_speeds: Vec<FanSpeed>,
+    #[serde(default)]
+    pub curve: Vec<(f64, u8)>,
+}
+
+impl FanCurve {
+    pub fn is_empty(&self) -> bool {
+        self.fan_speeds.is_empty() && self.curve.is_empty()
+    }
+
+    /// The duty for `temp`. Points are interpolated linearly and clamped at both ends;
+    /// a step table uses its first step below the table and 100 above it.
+    pub fn speed(&self, temp: f64) -> u8 {
+        if !self.curve.is_empty() {
+            return interpolate(&self.curve, temp);
+        }
+        for fan_speed in &self.fan_speeds {
+            if fan_speed.temp_range[0] <= temp && temp < fan_speed.temp_range[1] {
+                return fan_speed.speed;
+            }
+        }
+        match self.fan_speeds.iter().min_by(|a, b| a.temp_range[0].total_cmp(&b.temp_range[0])) {
+            Some(first) if temp < first.temp_range[0] => first.speed,
+            _ => 100,
+        }
+    }
+
+    /// Rejects empty curves, gaps and overlaps between steps, unordered points and speeds over 100.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.fan_speeds.is_empty() && !self.curve.is_empty() {
+            return Err("use either fan_speeds or curve, not both".to_string());
+        }
+        if self.is_empty() {
+            return Err("fan_speeds or curve is required".to_string());
+        }
+        if let Some(speed) = self
+            .fan_speeds
+            .iter()
+            .map(|s| s.speed)
+            .chain(self.curve.iter().map(|(_, s)| *s))
+            .find(|s| *s > 100)
+        {
+            return Err(format!("speed {} is over 100", speed));
+        }
+
+        let mut steps: Vec<&FanSpeed> = self.fan_speeds.iter().collect();
+        steps.sort_by(|a, b| a.temp_range[0].total_cmp(&b.temp_range[0]));
+        for step in &steps {
+            if step.temp_range[0] >= step.temp_range[1] {
+                return Err(format!("temp_range {:?} is empty", step.temp_range));
+            }
+        }
+        for pair in steps.windows(2) {
+            let (a, b) = (pair[0].temp_range, pair[1].temp_range);
+            if b[0] > a[1] {
+                return Err(format!("gap between temp_range {:?} and {:?}", a, b));
+            }
+            if b[0] < a[1] {
+                return Err(format!("temp_range {:?} overlaps {:?}", a, b));
+            }
+        }
+
+        for pair in self.curve.windows(2) {
+            if pair[1].0 <= pair[0].0 {
+                return Err(format!("curve temperatures must increase: {} then {}", pair[0].0, pair[1].0));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn interpolate(points: &[(f64, u8)], temp: f64) -> u8 {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    if temp <= first.0 {
+        return first.1;
+    }
+    if temp >= last.0 {
+        return last.1;
+    }
+    for pair in points.windows(2) {
+        let ((t0, s0), (t1, s1)) = (pair[0], pair[1]);
+        if temp <= t1 {
+            let speed = s0 as f64 + (s1 as f64 - s0 as f64) * (temp - t0) / (t1 - t0);
+            return speed.round() as u8;
+        }
+    }
+    last.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(ranges: &[(f64, f64, u8)]) -> FanCurve {
+        FanCurve {
+            fan_speeds: ranges
+                .iter()
+                .map(|(from, to, speed)| FanSpeed {
+                    temp_range: [*from, *to],
+                    speed: *speed,
+                })
+                .collect(),
+            curve: vec![],
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let curve = FanCurve {
+            fan_speeds: vec![],
+            curve: vec![(40.0, 10), (60.0, 30), (80.0, 100)],
+        };
+        assert!(curve.validate().is_ok());
+        assert_eq!(curve.speed(20.0), 10);
+        assert_eq!(curve.speed(50.0), 20);
+        assert_eq!(curve.speed(61.0), 34);
+        assert_eq!(curve.speed(95.0), 100);
+    }
+
+    #[test]
+    fn test_steps() {
+        let table = steps(&[(5.0, 40.0, 10), (40.0, 60.0, 20)]);
+        assert!(table.validate().is_ok());
+        assert_eq!(table.speed(0.0), 10);
+        assert_eq!(table.speed(40.0), 20);
+        assert_eq!(table.speed(60.0), 100);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(steps(&[(0.0, 40.0, 10), (45.0, 60.0, 20)]).validate().unwrap_err().contains("gap"));
+        assert!(steps(&[(0.0, 50.0, 10), (45.0, 60.0, 20)]).validate().unwrap_err().contains("overlaps"));
+        assert!(steps(&[(0.0, 40.0, 120)]).validate().is_err());
+        assert!(FanCurve::default().validate().is_err());
+        let unordered = FanCurve {
+            fan_speeds: vec![],
+            curve: vec![(60.0, 30), (40.0, 10)],
+        };
+        assert!(unordered.validate().is_err());
+    }
+}
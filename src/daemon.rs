@@ -0,0 +1,31 @@
+//! Headless mode: the control loop without the TUI, for running under systemd.
+//! Everything the TUI would show goes to the log, which ends up in the journal via stderr.
+
+use std::io;
+
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::{Message, UIMessage};
+
+/// Runs the loop until SIGINT or SIGTERM. Fails when the config, the connection or the
+/// profile selection fails, so the caller can exit non-zero.
+pub async fn run() -> io::Result<()> {
+    let (tx, rx) = mpsc::channel::<Message>(100);
+    // 保留发送端，否则控制循环会认为 UI 已经退出
+    let (_ui_tx, ui_rx) = mpsc::channel::<UIMessage>(1);
+    let logger = tokio::spawn(log_messages(rx));
+    let result = crate::init_loop(tx, ui_rx).await;
+    let _ = logger.await;
+    result
+}
+
+/// Log lines at their own level, safety events at theirs and the readings at debug.
+pub async fn log_messages(mut receive: Receiver<Message>) {
+    while let Some(msg) = receive.recv().await {
+        match msg {
+            Message::Log(_, level, text) => log::log!(level, "{}", text),
+            Message::Safety(_, event) => log::log!(event.level(), "{}", event),
+            msg => log::debug!("{}", msg),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Raw data for offline analysis: one record per successful poll, appended to a rotating CSV or
+//! JSON Lines file. A sensor always keeps the same column (`<sensor>.value`, `.unit`,
+//! `.status`); when new sensors or zones appear the CSV file is rotated so the header can grow.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{DataFormat, DataLogConfig};
+use crate::control::Reading;
+
+const FIXED_COLUMNS: [&str; 3] = ["timestamp", "temperature", "speed"];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SensorRecord {
+    pub value: Option<f64>,
+    pub unit: Option<String>,
+    pub status: Option<String>,
+}
+
+/// One poll: when, what was read and what was sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// RFC 3339
+    pub timestamp: String,
+    pub temperature: f64,
+    /// 主转速，暂停时为空
+    pub speed: Option<u8>,
+    /// 每个 zone 最后写入的转速
+    pub duties: BTreeMap<u8, u8>,
+    pub sensors: BTreeMap<String, SensorRecord>,
+}
+
+impl Record {
+    pub fn new(time: DateTime<Local>, reading: &Reading, duties: &[(u8, u8)]) -> Record {
+        Record {
+            timestamp: time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            temperature: reading.temperature,
+            speed: reading.speed,
+            duties: duties.iter().copied().collect(),
+            sensors: reading
+                .sensors
+                .iter()
+                .map(|s| {
+                    let record = SensorRecord {
+                        value: s.value,
+                        unit: s.unit.clone(),
+                        status: s.status.clone(),
+                    };
+                    (s.sensor_name.clone(), record)
+                })
+                .collect(),
+        }
+    }
+
+    fn time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.timestamp).ok()
+    }
+
+    /// CSV 的列名：固定列、各 zone 的转速、各传感器的值、单位和状态
+    fn columns(&self) -> Vec<String> {
+        let mut columns: Vec<String> = FIXED_COLUMNS.iter().map(|c| c.to_string()).collect();
+        columns.extend(self.duties.keys().map(|zone| format!("duty.{}", zone)));
+        for name in self.sensors.keys() {
+            columns.extend(["value", "unit", "status"].iter().map(|field| format!("{}.{}", name, field)));
+        }
+        columns
+    }
+
+    fn field(&self, column: &str) -> String {
+        let text = |v: Option<String>| v.unwrap_or_default();
+        match column {
+            "timestamp" => self.timestamp.clone(),
+            "temperature" => self.temperature.to_string(),
+            "speed" => text(self.speed.map(|s| s.to_string())),
+            _ => {
+                if let Some(zone) = column.strip_prefix("duty.").and_then(|z| z.parse::<u8>().ok()) {
+                    return text(self.duties.get(&zone).map(|d| d.to_string()));
+                }
+                let Some((name, field)) = column.rsplit_once('.') else {
+                    return String::new();
+                };
+                let Some(sensor) = self.sensors.get(name) else {
+                    return String::new();
+                };
+                match field {
+                    "value" => text(sensor.value.map(|v| v.to_string())),
+                    "unit" => text(sensor.unit.clone()),
+                    "status" => text(sensor.status.clone()),
+                    _ => String::new(),
+                }
+            }
+        }
+    }
+
+    /// The inverse of [`Record::field`] over a whole CSV row.
+    fn from_row(columns: &[String], row: &csv::StringRecord) -> Option<Record> {
+        let mut record = Record {
+            timestamp: String::new(),
+            temperature: 0.0,
+            speed: None,
+            duties: BTreeMap::new(),
+            sensors: BTreeMap::new(),
+        };
+        for (column, value) in columns.iter().zip(row.iter()) {
+            match column.as_str() {
+                "timestamp" => record.timestamp = value.to_string(),
+                "temperature" => record.temperature = value.parse().ok()?,
+                "speed" => record.speed = value.parse().ok(),
+                _ if value.is_empty() => {}
+                _ => {
+                    if let Some(zone) = column.strip_prefix("duty.").and_then(|z| z.parse::<u8>().ok()) {
+                        record.duties.insert(zone, value.parse().ok()?);
+                        continue;
+                    }
+                    let Some((name, field)) = column.rsplit_once('.') else { continue };
+                    let sensor = record.sensors.entry(name.to_string()).or_default();
+                    match field {
+                        "value" => sensor.value = value.parse().ok(),
+                        "unit" => sensor.unit = Some(value.to_string()),
+                        "status" => sensor.status = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Some(record)
+    }
+}
+
+/// The log file being appended to.
+pub struct DataLog {
+    config: DataLogConfig,
+    /// 当前 CSV 文件的列
+    columns: Vec<String>,
+}
+
+impl DataLog {
+    /// Continues the existing file at `config.path`, with its CSV header.
+    pub fn open(config: &DataLogConfig) -> io::Result<DataLog> {
+        let columns = match config.format {
+            DataFormat::Csv => read_header(Path::new(&config.path))?,
+            DataFormat::Jsonl => vec![],
+        };
+        Ok(DataLog {
+            config: config.clone(),
+            columns,
+        })
+    }
+
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let line = match self.config.format {
+            DataFormat::Jsonl => serde_json::to_string(record)? + "\n",
+            DataFormat::Csv => {
+                let new: Vec<String> = record.columns().into_iter().filter(|c| !self.columns.contains(c)).collect();
+                if !new.is_empty() {
+                    // 表头变化时换新文件，已有的列保持原来的名字和顺序
+                    if !self.columns.is_empty() {
+                        self.rotate()?;
+                    }
+                    self.columns.extend(new);
+                }
+                let mut line = String::new();
+                if file_size(&self.config.path) == 0 {
+                    line += &csv_line(self.columns.iter().map(|c| c.as_str()))?;
+                }
+                let fields: Vec<String> = self.columns.iter().map(|c| record.field(c)).collect();
+                line + &csv_line(fields.iter().map(|f| f.as_str()))?
+            }
+        };
+        OpenOptions::new().create(true).append(true).open(&self.config.path)?.write_all(line.as_bytes())?;
+        if file_size(&self.config.path) >= self.config.max_size_mb * 1024 * 1024 {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// path -> path.1 -> path.2 ...，最多保留 keep 个旧文件
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for i in (1..self.config.keep).rev() {
+                let from = format!("{}.{}", path, i);
+                if Path::new(&from).exists() {
+                    fs::rename(&from, format!("{}.{}", path, i + 1))?;
+                }
+            }
+            fs::rename(path, format!("{}.1", path))?;
+        }
+        // 新文件的表头沿用现有的列，列名不变
+        Ok(())
+    }
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map_or(0, |m| m.len())
+}
+
+fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> io::Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(fields).map_err(io::Error::other)?;
+    String::from_utf8(writer.into_inner().map_err(|e| io::Error::other(e.to_string()))?).map_err(io::Error::other)
+}
+
+fn read_header(path: &Path) -> io::Result<Vec<String>> {
+    let Ok(file) = File::open(path) else {
+        return Ok(vec![]);
+    };
+    let mut reader = csv::Reader::from_reader(file);
+    match reader.headers() {
+        Ok(header) => Ok(header.iter().map(|c| c.to_string()).collect()),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Every record in the log and its rotated files, oldest first.
+pub fn read_all(config: &DataLogConfig) -> io::Result<Vec<Record>> {
+    let mut paths: Vec<String> = (1..=config.keep).rev().map(|i| format!("{}.{}", config.path, i)).collect();
+    paths.push(config.path.clone());
+    let mut records = vec![];
+    for path in paths.iter().filter(|p| Path::new(p).exists()) {
+        match config.format {
+            DataFormat::Jsonl => {
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    if let Ok(record) = serde_json::from_str(&line) {
+                        records.push(record);
+                    }
+                }
+            }
+            DataFormat::Csv => {
+                let mut reader = csv::Reader::from_path(path).map_err(io::Error::other)?;
+                let columns: Vec<String> = reader.headers().map_err(io::Error::other)?.iter().map(|c| c.to_string()).collect();
+                for row in reader.records() {
+                    // 写了一半的最后一行跳过
+                    if let Some(record) = row.ok().and_then(|row| Record::from_row(&columns, &row)) {
+                        records.push(record);
+                    }
+                }
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Writes the records between `from` and `to` in `format`. Returns how many were written.
+/// CSV gets the columns of every exported record, in the order they first appear.
+pub fn export(
+    records: &[Record],
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    format: DataFormat,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let selected: Vec<&Record> = records
+        .iter()
+        .filter(|r| {
+            let time = r.time();
+            from.is_none_or(|from| time.is_some_and(|t| t >= from)) && to.is_none_or(|to| time.is_some_and(|t| t < to))
+        })
+        .collect();
+    match format {
+        DataFormat::Jsonl => {
+            for record in &selected {
+                writeln!(out, "{}", serde_json::to_string(record)?)?;
+            }
+        }
+        DataFormat::Csv => {
+            let mut columns: Vec<String> = vec![];
+            for column in selected.iter().flat_map(|r| r.columns()) {
+                if !columns.contains(&column) {
+                    columns.push(column);
+                }
+            }
+            if columns.is_empty() {
+                columns = FIXED_COLUMNS.iter().map(|c| c.to_string()).collect();
+            }
+            out.write_all(csv_line(columns.iter().map(|c| c.as_str()))?.as_bytes())?;
+            for record in &selected {
+                let fields: Vec<String> = columns.iter().map(|c| record.field(c)).collect();
+                out.write_all(csv_line(fields.iter().map(|f| f.as_str()))?.as_bytes())?;
+            }
+        }
+    }
+    Ok(selected.len())
+}
+
+/// RFC 3339, or local `YYYY-MM-DD[ HH:MM[:SS]]`.
+pub fn parse_time(text: &str) -> io::Result<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time);
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(text, f).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)));
+    naive
+        .and_then(|n| Local.from_local_datetime(&n).earliest())
+        .map(|t| t.fixed_offset())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("无法识别的时间 {}/cannot parse time {:?}, use YYYY-MM-DD[ HH:MM[:SS]] or RFC 3339", text, text),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn reading(lines: &[&str]) -> Reading {
+        testutil::reading().sensors(lines).build()
+    }
+
+    #[test]
+    fn test_csv_log() {
+        let dir = std::env::temp_dir().join(format!("smartfan-datalog-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config = DataLogConfig {
+            path: dir.join("data.csv").to_str().unwrap().to_string(),
+            format: DataFormat::Csv,
+            max_size_mb: 1,
+            keep: 3,
+        };
+        let cpu = "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000";
+        let fan = "FAN1_Speed | 5400.000 | RPM | ok | na | na | na | na | na | na";
+        let t0 = Local.with_ymd_and_hms(2026, 10, 1, 12, 0, 0).unwrap();
+        let mut log = DataLog::open(&config).unwrap();
+        log.append(&Record::new(t0, &reading(&[cpu]), &[(0, 30)])).unwrap();
+        // 重新打开后沿用表头
+        let mut log = DataLog::open(&config).unwrap();
+        log.append(&Record::new(t0 + chrono::Duration::minutes(1), &reading(&[cpu]), &[(0, 30)])).unwrap();
+        assert!(!Path::new(&format!("{}.1", config.path)).exists());
+        // 新传感器出现：换文件，原有的列不变
+        log.append(&Record::new(t0 + chrono::Duration::minutes(2), &reading(&[fan, cpu]), &[(0, 40)])).unwrap();
+        // 传感器消失：列保留为空
+        log.append(&Record::new(t0 + chrono::Duration::minutes(3), &reading(&[fan]), &[(0, 40)])).unwrap();
+
+        let old = fs::read_to_string(format!("{}.1", config.path)).unwrap();
+        assert_eq!(old.lines().next(), Some("timestamp,temperature,speed,duty.0,CPU1_Temp.value,CPU1_Temp.unit,CPU1_Temp.status"));
+        assert_eq!(old.lines().count(), 3);
+        let current = fs::read_to_string(&config.path).unwrap();
+        assert_eq!(
+            current.lines().next(),
+            Some("timestamp,temperature,speed,duty.0,CPU1_Temp.value,CPU1_Temp.unit,CPU1_Temp.status,FAN1_Speed.value,FAN1_Speed.unit,FAN1_Speed.status")
+        );
+        assert!(current.lines().last().unwrap().ends_with(",40,,,,5400,RPM,ok"));
+
+        let records = read_all(&config).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3].sensors.keys().collect::<Vec<_>>(), vec!["FAN1_Speed"]);
+        let mut out = vec![];
+        let from = parse_time("2026-10-01 12:01").unwrap();
+        let to = parse_time("2026-10-01 12:03").unwrap();
+        assert_eq!(export(&records, Some(from), Some(to), DataFormat::Jsonl, &mut out).unwrap(), 2);
+        let exported: Vec<Record> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(exported, records[1..3].to_vec());
+        let mut out = vec![];
+        assert_eq!(export(&records, None, None, DataFormat::Csv, &mut out).unwrap(), 4);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 5);
+        assert!(parse_time("yesterday").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! Compares fan RPM with the commanded duty to find stopped, slow and stuck fans.
+
+use std::collections::HashMap;
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, FanCheckConfig};
+use crate::profile::FanControl;
+use crate::sensor;
+use crate::sensor_result::SensorResult;
+
+#[derive(Debug, Clone, PartialEq, Display, Serialize, Deserialize)]
+pub enum FaultKind {
+    #[display("stopped at {}%", _0)]
+    Stopped(u8),
+    #[display("{} RPM while its peers run {} RPM at {}%", _0, _1, _2)]
+    BelowPeers(f64, f64, u8),
+    #[display("did not speed up from {}% to {}% ({} -> {} RPM)", _0, _1, _2, _3)]
+    NoSpeedUp(u8, u8, f64, f64),
+}
+
+/// A fan that is not doing what it was told. Stays until the fan behaves again.
+#[derive(Debug, Clone, PartialEq, Display, Serialize, Deserialize)]
+#[display("{} {}", fan, kind)]
+pub struct FanFault {
+    pub fan: String,
+    /// None 表示 profile 没有分 zone，所有风扇一起设置
+    pub zone: Option<u8>,
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum FanEvent {
+    #[display("Fan failure: {}", _0)]
+    Failed(FanFault),
+    #[display("Fan {} recovered", _0)]
+    Recovered(String),
+}
+
+struct Reading {
+    fan: String,
+    zone: Option<u8>,
+    duty: u8,
+    rpm: f64,
+}
+
+pub struct FanCheck {
+    config: FanCheckConfig,
+    /// 每个风扇上一次的 (转速%, RPM)
+    last: HashMap<String, (u8, f64)>,
+    faults: Vec<FanFault>,
+}
+
+impl FanCheck {
+    pub fn new(config: FanCheckConfig) -> FanCheck {
+        FanCheck {
+            config,
+            last: HashMap::new(),
+            faults: vec![],
+        }
+    }
+
+    pub fn from_config(config: &Config) -> FanCheck {
+        FanCheck::new(config.fan_check.clone())
+    }
+
+    /// The fans currently considered failed.
+    pub fn faults(&self) -> &[FanFault] {
+        &self.faults
+    }
+
+    /// Raises the zones of failed fans to `failed_zone_speed`: the other fans there have to make up for it.
+    pub fn boost(&self, speed: &mut u8, zone_speeds: &mut Vec<(u8, u8)>) {
+        let boost = self.config.failed_zone_speed;
+        for fault in &self.faults {
+            match fault.zone {
+                None => *speed = (*speed).max(boost),
+                Some(zone) => match zone_speeds.iter_mut().find(|(id, _)| *id == zone) {
+                    Some((_, s)) => *s = (*s).max(boost),
+                    None => zone_speeds.push((zone, (*speed).max(boost))),
+                },
+            }
+        }
+    }
+
+    /// Checks every fan against the duty that was in effect while `sensor_results` were read,
+    /// so call it before setting the next duty. Returns the failures and recoveries.
+    ///
+    /// A fan is compared with the other fans of its zone, or with the fans at the same duty
+    /// when it is alone in its zone. Below `min_duty` fans may legitimately stop and nothing is checked.
+    pub fn check(&mut self, sensor_results: &[SensorResult], fan_control: &FanControl) -> Vec<FanEvent> {
+        let readings = readings(sensor_results, fan_control);
+        let mut events = vec![];
+        for r in &readings {
+            let last = self.last.insert(r.fan.clone(), (r.duty, r.rpm));
+            if !self.config.enabled || r.duty < self.config.min_duty {
+                continue;
+            }
+            let mut kinds = vec![];
+            if r.rpm <= 0.0 {
+                kinds.push(FaultKind::Stopped(r.duty));
+            } else if let Some(peers) = peer_rpm(r, &readings) {
+                if r.rpm < peers * self.config.min_peer_ratio {
+                    kinds.push(FaultKind::BelowPeers(r.rpm, peers, r.duty));
+                }
+            }
+            let sped_up = match last {
+                Some((duty, rpm)) if r.duty >= duty.saturating_add(self.config.min_step) && r.rpm <= rpm => {
+                    kinds.push(FaultKind::NoSpeedUp(duty, r.duty, rpm, r.rpm));
+                    false
+                }
+                Some((duty, rpm)) => r.duty > duty && r.rpm > rpm,
+                None => false,
+            };
+
+            let existing = self.faults.iter().position(|f| f.fan == r.fan);
+            match (existing, kinds.into_iter().next()) {
+                (None, Some(kind)) => {
+                    let fault = FanFault {
+                        fan: r.fan.clone(),
+                        zone: r.zone,
+                        kind,
+                    };
+                    self.faults.push(fault.clone());
+                    events.push(FanEvent::Failed(fault));
+                }
+                // 没有转起来的风扇要等到再次升速时确认转速跟上了
+                (Some(i), None) if !matches!(self.faults[i].kind, FaultKind::NoSpeedUp(..)) || sped_up => {
+                    self.faults.remove(i);
+                    events.push(FanEvent::Recovered(r.fan.clone()));
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+/// Every fan with a reading, its zone and the duty last sent to that zone.
+fn readings(sensor_results: &[SensorResult], fan_control: &FanControl) -> Vec<Reading> {
+    let profile = fan_control.profile();
+    sensor_results
+        .iter()
+        .filter(|x| sensor::is_fan(x))
+        .filter_map(|x| {
+            let rpm = x.value?;
+            let (zone, duty) = if profile.zones.is_empty() {
+                (None, fan_control.duty(profile.all_zones.unwrap_or(0))?)
+            } else {
+                let zone = profile.zones.iter().find(|z| z.matches(&x.sensor_name))?;
+                (Some(zone.id), fan_control.duty(zone.id)?)
+            };
+            Some(Reading {
+                fan: x.sensor_name.clone(),
+                zone,
+                duty,
+                rpm,
+            })
+        })
+        .collect()
+}
+
+/// The median RPM of the other fans in the zone, or of the fans at the same duty.
+fn peer_rpm(fan: &Reading, readings: &[Reading]) -> Option<f64> {
+    let others = || readings.iter().filter(|r| r.fan != fan.fan);
+    let mut peers: Vec<f64> = others().filter(|r| r.zone == fan.zone).map(|r| r.rpm).collect();
+    if peers.is_empty() {
+        peers = others().filter(|r| r.duty == fan.duty).map(|r| r.rpm).collect();
+    }
+    if peers.is_empty() {
+        return None;
+    }
+    peers.sort_by(|a, b| a.total_cmp(b));
+    Some(peers[peers.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::mock::MockTransport;
+    use crate::testutil;
+
+    fn fans(rpms: &[f64]) -> Vec<SensorResult> {
+        let lines: Vec<String> = rpms
+            .iter()
+            .enumerate()
+            .map(|(i, rpm)| format!("FAN{}_Speed | {} | RPM | ok | na | na | na | na | na | na", i + 1, rpm))
+            .collect();
+        testutil::sensors(&lines)
+    }
+
+    #[test]
+    fn test_fan_check() {
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fan_control = FanControl::new(hr650x);
+        let mut transport = MockTransport::new();
+        let mut check = FanCheck::new(FanCheckConfig::default());
+
+        // 还没有设置过转速，无法判断
+        assert!(check.check(&fans(&[0.0; 6]), &fan_control).is_empty());
+
+        fan_control.set_speed(&mut transport, 30, 2).unwrap();
+        let events = check.check(&fans(&[5000.0, 5100.0, 0.0, 4900.0, 1500.0, 5000.0]), &fan_control);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].to_string(), "Fan failure: FAN3_Speed stopped at 30%");
+        assert_eq!(check.faults()[1].kind, FaultKind::BelowPeers(1500.0, 5000.0, 30));
+
+        // 故障风扇所在 zone 升到全速
+        let (mut speed, mut zone_speeds) = (30, vec![(3, 40)]);
+        check.boost(&mut speed, &mut zone_speeds);
+        assert_eq!(zone_speeds, vec![(3, 100), (5, 100)]);
+
+        // FAN2 升速后转速没有变化
+        fan_control.set_speeds(&mut transport, 30, &[(2, 60), (3, 100), (5, 100)], 2).unwrap();
+        let events = check.check(&fans(&[5000.0, 5100.0, 9000.0, 4900.0, 9000.0, 5000.0]), &fan_control);
+        assert_eq!(
+            events,
+            vec![
+                FanEvent::Failed(FanFault {
+                    fan: "FAN2_Speed".to_string(),
+                    zone: Some(2),
+                    kind: FaultKind::NoSpeedUp(30, 60, 5100.0, 5100.0),
+                }),
+                FanEvent::Recovered("FAN3_Speed".to_string()),
+                FanEvent::Recovered("FAN5_Speed".to_string()),
+            ]
+        );
+        // 转速正常但没有再次升速，故障保留
+        assert!(check.check(&fans(&[5000.0, 5100.0, 9000.0, 4900.0, 9000.0, 5000.0]), &fan_control).is_empty());
+        assert_eq!(check.faults().len(), 1);
+    }
+}
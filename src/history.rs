@@ -0,0 +1,243 @@
+//! Every successful iteration in a local SQLite database: the full sensor snapshot and the
+//! duty of each zone. Old iterations are averaged into buckets and dropped after the retention
+//! period. The TUI loads the last hours from it into the history chart at startup.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, TimeZone};
+use rusqlite::{params, Connection};
+
+use crate::config::HistoryConfig;
+use crate::control::Reading;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS iterations (
+    id INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL,
+    temperature REAL NOT NULL,
+    speed REAL,
+    mode TEXT NOT NULL,
+    -- 合并了多少次读数，0 表示原始数据
+    samples INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS iterations_time ON iterations (time);
+CREATE TABLE IF NOT EXISTS readings (
+    iteration INTEGER NOT NULL,
+    sensor TEXT NOT NULL,
+    value REAL,
+    unit TEXT,
+    status TEXT
+);
+CREATE INDEX IF NOT EXISTS readings_iteration ON readings (iteration);
+CREATE TABLE IF NOT EXISTS duties (
+    iteration INTEGER NOT NULL,
+    zone INTEGER NOT NULL,
+    duty REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS duties_iteration ON duties (iteration);
+";
+
+/// 与界面历史图的点数相同
+pub const CHART_POINTS: usize = 180;
+
+/// 每小时整理一次
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub struct History {
+    conn: Connection,
+    config: HistoryConfig,
+    maintained: Option<Instant>,
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("history: {}", e))
+}
+
+impl History {
+    /// Opens or creates the database at `config.path`.
+    pub fn open(config: &HistoryConfig) -> io::Result<History> {
+        let conn = Connection::open(&config.path).map_err(sql_error)?;
+        conn.execute_batch(SCHEMA).map_err(sql_error)?;
+        Ok(History {
+            conn,
+            config: config.clone(),
+            maintained: None,
+        })
+    }
+
+    /// Stores one iteration at unix time `time`.
+    pub fn record(&mut self, time: i64, reading: &Reading, duties: &[(u8, u8)], mode: &str) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(sql_error)?;
+        tx.execute(
+            "INSERT INTO iterations (time, temperature, speed, mode) VALUES (?1, ?2, ?3, ?4)",
+            params![time, reading.temperature, reading.speed, mode],
+        )
+        .map_err(sql_error)?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut insert = tx
+                .prepare("INSERT INTO readings (iteration, sensor, value, unit, status) VALUES (?1, ?2, ?3, ?4, ?5)")
+                .map_err(sql_error)?;
+            for s in &reading.sensors {
+                insert.execute(params![id, s.sensor_name, s.value, s.unit, s.status]).map_err(sql_error)?;
+            }
+            let mut insert = tx.prepare("INSERT INTO duties (iteration, zone, duty) VALUES (?1, ?2, ?3)").map_err(sql_error)?;
+            for (zone, duty) in duties {
+                insert.execute(params![id, zone, duty]).map_err(sql_error)?;
+            }
+        }
+        tx.commit().map_err(sql_error)
+    }
+
+    /// Downsamples and drops old data, at most once an hour. Returns whether it ran.
+    pub fn maintain_if_due(&mut self, time: i64) -> io::Result<bool> {
+        if self.maintained.is_some_and(|t| t.elapsed() < MAINTENANCE_INTERVAL) {
+            return Ok(false);
+        }
+        self.maintained = Some(Instant::now());
+        self.maintain(time)?;
+        Ok(true)
+    }
+
+    /// Averages raw iterations older than `raw_hours` into `downsample_secs` buckets and
+    /// deletes everything older than `retention_days`.
+    pub fn maintain(&mut self, time: i64) -> io::Result<()> {
+        let bucket = self.config.downsample_secs.max(1) as i64;
+        let raw_before = time - self.config.raw_hours as i64 * 3600;
+        let keep_after = time - self.config.retention_days as i64 * 86400;
+        let tx = self.conn.transaction().map_err(sql_error)?;
+        let buckets: Vec<i64> = {
+            let mut stmt = tx
+                .prepare("SELECT DISTINCT time / ?1 FROM iterations WHERE samples = 0 AND time < ?2")
+                .map_err(sql_error)?;
+            let rows = stmt.query_map(params![bucket, raw_before], |row| row.get(0)).map_err(sql_error)?;
+            rows.collect::<rusqlite::Result<_>>().map_err(sql_error)?
+        };
+        for b in buckets {
+            // 最后一个 bucket 可能跨过 raw_before，只合并它之前的部分
+            let (start, end) = (b * bucket, (b * bucket + bucket).min(raw_before));
+            let old = "SELECT id FROM iterations WHERE samples = 0 AND time >= ?1 AND time < ?2";
+            tx.execute(
+                "INSERT INTO iterations (time, temperature, speed, mode, samples)
+                 SELECT ?1, AVG(temperature), AVG(speed), MAX(mode), COUNT(*)
+                 FROM iterations WHERE samples = 0 AND time >= ?1 AND time < ?2",
+                params![start, end],
+            )
+            .map_err(sql_error)?;
+            let id = tx.last_insert_rowid();
+            tx.execute(
+                &format!(
+                    "INSERT INTO readings (iteration, sensor, value, unit, status)
+                     SELECT ?3, sensor, AVG(value), MAX(unit), NULL FROM readings
+                     WHERE iteration IN ({}) GROUP BY sensor",
+                    old
+                ),
+                params![start, end, id],
+            )
+            .map_err(sql_error)?;
+            tx.execute(
+                &format!(
+                    "INSERT INTO duties (iteration, zone, duty)
+                     SELECT ?3, zone, AVG(duty) FROM duties WHERE iteration IN ({}) GROUP BY zone",
+                    old
+                ),
+                params![start, end, id],
+            )
+            .map_err(sql_error)?;
+            for table in ["readings", "duties"] {
+                tx.execute(&format!("DELETE FROM {} WHERE iteration IN ({})", table, old), params![start, end])
+                    .map_err(sql_error)?;
+            }
+            tx.execute("DELETE FROM iterations WHERE samples = 0 AND time >= ?1 AND time < ?2", params![start, end])
+                .map_err(sql_error)?;
+        }
+        let expired = "SELECT id FROM iterations WHERE time < ?1";
+        for table in ["readings", "duties"] {
+            tx.execute(&format!("DELETE FROM {} WHERE iteration IN ({})", table, expired), params![keep_after])
+                .map_err(sql_error)?;
+        }
+        tx.execute("DELETE FROM iterations WHERE time < ?1", params![keep_after]).map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    /// Temperature and speed since `since`, averaged into at most `points` points for the chart.
+    /// Iterations while paused have no speed and are left out.
+    pub fn chart(&self, since: i64, until: i64, points: usize) -> io::Result<Vec<(String, f64, f64)>> {
+        let span = (until - since).max(0) + 1;
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT MIN(time), AVG(temperature), AVG(speed) FROM iterations
+                 WHERE time >= ?1 AND time <= ?2 AND speed IS NOT NULL
+                 GROUP BY (time - ?1) * ?3 / ?4 ORDER BY MIN(time)",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(params![since, until, points as i64, span], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?))
+            })
+            .map_err(sql_error)?;
+        rows.map(|row| {
+            let (time, temperature, speed) = row.map_err(sql_error)?;
+            let label = Local.timestamp_opt(time, 0).single().map_or(String::new(), |t| t.format("%m-%d %H:%M").to_string());
+            Ok((label, temperature, speed))
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn reading(temperature: f64, speed: u8) -> Reading {
+        let cpu = format!("CPU1_Temp | {} | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000", temperature);
+        testutil::reading().temperature(temperature).speed(speed).sensors(&[&cpu]).build()
+    }
+
+    fn count(history: &History, sql: &str) -> i64 {
+        history.conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_history() {
+        let config = HistoryConfig {
+            path: ":memory:".to_string(),
+            retention_days: 2,
+            raw_hours: 1,
+            downsample_secs: 300,
+            ..Default::default()
+        };
+        let mut history = History::open(&config).unwrap();
+        let now = 10 * 86400;
+        // 三天前、两小时前每 15 秒一次，以及刚才
+        for i in 0..4 {
+            history.record(now - 3 * 86400 + i * 15, &reading(50.0, 30), &[(0, 30)], "curve").unwrap();
+        }
+        for i in 0..40 {
+            let temp = if i < 20 { 40.0 } else { 60.0 };
+            history.record(now - 7200 + i * 15, &reading(temp, 20 + i as u8), &[(0, 20 + i as u8)], "curve").unwrap();
+        }
+        history.record(now - 60, &reading(55.0, 35), &[(0, 35)], "curve").unwrap();
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM iterations"), 45);
+
+        history.maintain(now).unwrap();
+        // 过期的删除，两小时前的 40 次合并为两个 5 分钟的点，最近的保留原样
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM iterations"), 3);
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM iterations WHERE samples = 20"), 2);
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM readings"), 3);
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM duties"), 3);
+        let avg: f64 = history.conn.query_row("SELECT value FROM readings JOIN iterations ON id = iteration ORDER BY time LIMIT 1", [], |r| r.get(0)).unwrap();
+        assert_eq!(avg, 40.0);
+        // 再次整理不改变已合并的数据
+        history.maintain(now).unwrap();
+        assert_eq!(count(&history, "SELECT COUNT(*) FROM iterations"), 3);
+
+        let chart = history.chart(now - 3 * 3600, now, 180).unwrap();
+        assert_eq!(chart.iter().map(|(_, t, _)| *t).collect::<Vec<_>>(), vec![40.0, 60.0, 55.0]);
+        assert_eq!(chart[2].2, 35.0);
+        assert_eq!(history.chart(now - 3 * 3600, now, 1).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+//! Server identification from Get Device ID (`ipmitool mc info`) and the FRU inventory (`ipmitool fru`).
+
+use std::fmt;
+use std::io;
+
+use crate::ipmi::sdr::RawFn;
+use crate::ipmi::{NETFN_APP, NETFN_STORAGE};
+
+const CMD_GET_DEVICE_ID: u8 = 0x01;
+const CMD_GET_FRU_INVENTORY_AREA_INFO: u8 = 0x10;
+const CMD_READ_FRU_DATA: u8 = 0x11;
+
+const FRU_CHUNK_LEN: u8 = 32;
+/// 只需要 board 和 product 区域，它们一般都在前 1 KiB
+const FRU_MAX_LEN: usize = 1024;
+
+/// What the BMC says about the server it sits in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerIdentity {
+    /// IANA 企业编号，Lenovo 19046、Dell 674、Supermicro 10876
+    pub manufacturer_id: Option<u32>,
+    pub product_id: Option<u16>,
+    pub manufacturer: String,
+    pub product: String,
+    pub board: String,
+}
+
+impl ServerIdentity {
+    /// Every name the server goes by, for matching against profile models.
+    pub fn names(&self) -> Vec<&str> {
+        [self.manufacturer.as_str(), self.product.as_str(), self.board.as_str()]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+}
+
+impl fmt::Display for ServerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match (self.manufacturer.is_empty(), self.product.is_empty()) {
+            (false, false) => format!("{} {}", self.manufacturer, self.product),
+            (true, false) => self.product.clone(),
+            (false, true) => self.manufacturer.clone(),
+            (true, true) => self.board.clone(),
+        };
+        if !name.is_empty() {
+            return write!(f, "{}", name);
+        }
+        match (self.manufacturer_id, self.product_id) {
+            (Some(m), Some(p)) => write!(f, "manufacturer {} product 0x{:04x}", m, p),
+            _ => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Reads the device ID and, when the BMC has one, the FRU inventory of the board.
+/// A missing or unreadable FRU is not an error, the IDs alone are enough to pick a profile.
+pub fn identify(raw: &mut RawFn) -> io::Result<ServerIdentity> {
+    let rsp = raw(NETFN_APP, CMD_GET_DEVICE_ID, &[])?;
+    if rsp.len() < 11 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Get Device ID response too short"));
+    }
+    let mut identity = ServerIdentity {
+        manufacturer_id: Some(u32::from_le_bytes([rsp[6], rsp[7], rsp[8] & 0x0f, 0])),
+        product_id: Some(u16::from_le_bytes([rsp[9], rsp[10]])),
+        ..Default::default()
+    };
+    if let Ok(fru) = read_fru(raw) {
+        parse_fru(&fru, &mut identity);
+    }
+    Ok(identity)
+}
+
+fn read_fru(raw: &mut RawFn) -> io::Result<Vec<u8>> {
+    let info = raw(NETFN_STORAGE, CMD_GET_FRU_INVENTORY_AREA_INFO, &[0])?;
+    if info.len() < 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "FRU area info response too short"));
+    }
+    let size = (u16::from_le_bytes([info[0], info[1]]) as usize).min(FRU_MAX_LEN);
+    // bit 0: 按字（word）访问
+    let words = info[2] & 0x01 != 0;
+
+    let mut fru = Vec::with_capacity(size);
+    while fru.len() < size {
+        let len = (size - fru.len()).min(FRU_CHUNK_LEN as usize) as u8;
+        let offset = if words { fru.len() / 2 } else { fru.len() } as u16;
+        let count = if words { len / 2 } else { len };
+        let [lo, hi] = offset.to_le_bytes();
+        let rsp = raw(NETFN_STORAGE, CMD_READ_FRU_DATA, &[0, lo, hi, count])?;
+        if rsp.len() < 2 {
+            break;
+        }
+        fru.extend(&rsp[1..]);
+    }
+    Ok(fru)
+}
+
+/// 按 IPMI FRU 规范解析 common header 中的 board 与 product 区域
+fn parse_fru(fru: &[u8], identity: &mut ServerIdentity) {
+    if fru.len() < 8 || fru[0] & 0x0f != 0x01 {
+        return;
+    }
+    let board = fru[3] as usize * 8;
+    if board != 0 {
+        // 版本、长度、语言、3 字节生产日期，然后是 manufacturer、product name
+        let fields = fields(fru, board + 6, 2);
+        if let [manufacturer, product] = fields.as_slice() {
+            identity.manufacturer = manufacturer.clone();
+            identity.board = product.clone();
+        }
+    }
+    let product = fru[4] as usize * 8;
+    if product != 0 {
+        // 版本、长度、语言，然后是 manufacturer、product name
+        let fields = fields(fru, product + 3, 2);
+        if let [manufacturer, name] = fields.as_slice() {
+            if !manufacturer.is_empty() {
+                identity.manufacturer = manufacturer.clone();
+            }
+            identity.product = name.clone();
+        }
+    }
+}
+
+/// Decodes up to `count` type/length encoded strings starting at `offset`.
+fn fields(fru: &[u8], mut offset: usize, count: usize) -> Vec<String> {
+    let mut fields = vec![];
+    while fields.len() < count {
+        let Some(&type_len) = fru.get(offset) else {
+            break;
+        };
+        if type_len == 0xc1 {
+            break;
+        }
+        let len = (type_len & 0x3f) as usize;
+        let Some(bytes) = fru.get(offset + 1..offset + 1 + len) else {
+            break;
+        };
+        let value = match type_len >> 6 {
+            0x02 => six_bit_ascii(bytes),
+            0x03 => bytes.iter().map(|&b| b as char).collect(),
+            _ => String::new(),
+        };
+        fields.push(value.trim().to_string());
+        offset += 1 + len;
+    }
+    fields
+}
+
+/// 6-bit ASCII：每 3 字节打包 4 个字符，0 对应空格 (0x20)
+fn six_bit_ascii(bytes: &[u8]) -> String {
+    let mut bits: u32 = 0;
+    let mut nbits = 0;
+    let mut s = String::new();
+    for &b in bytes {
+        bits |= (b as u32) << nbits;
+        nbits += 8;
+        while nbits >= 6 {
+            s.push(((bits & 0x3f) as u8 + 0x20) as char);
+            bits >>= 6;
+            nbits -= 6;
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::sim::SimBmc;
+
+    #[test]
+    fn test_identify() {
+        let bmc = SimBmc::default();
+        bmc.set_device_id(19046, 0x0365);
+        bmc.set_fru(fru(&["Lenovo", "HR650X"], &["Lenovo", "ThinkSystem HR650X"]));
+        let mut raw = |netfn: u8, cmd: u8, data: &[u8]| bmc.handle(netfn, cmd, data);
+
+        let identity = identify(&mut raw).unwrap();
+        assert_eq!(identity.manufacturer_id, Some(19046));
+        assert_eq!(identity.product_id, Some(0x0365));
+        assert_eq!(identity.board, "HR650X");
+        assert_eq!(identity.to_string(), "Lenovo ThinkSystem HR650X");
+
+        // 剩余 256、512、768 字节时不能截断为 0
+        let mut image = fru(&["Dell Inc.", "0599V5"], &["Dell Inc.", "PowerEdge R730"]);
+        image.resize(FRU_MAX_LEN, 0xff);
+        bmc.set_fru(image.clone());
+        assert_eq!(read_fru(&mut raw).unwrap(), image);
+        assert_eq!(identify(&mut raw).unwrap().to_string(), "Dell Inc. PowerEdge R730");
+
+        assert_eq!(six_bit_ascii(&[0x29, 0xdc, 0xa6]), "IPMI");
+    }
+
+    /// Builds a FRU image with a board and a product area.
+    fn fru(board: &[&str], product: &[&str]) -> Vec<u8> {
+        let area = |header: &[u8], strings: &[&str]| {
+            let mut a = header.to_vec();
+            for s in strings {
+                a.push(0xc0 | s.len() as u8);
+                a.extend(s.as_bytes());
+            }
+            a.push(0xc1);
+            while !a.len().is_multiple_of(8) {
+                a.push(0);
+            }
+            a[1] = (a.len() / 8) as u8;
+            a
+        };
+        let board = area(&[0x01, 0, 0, 0, 0, 0], board);
+        let product = area(&[0x01, 0, 0], product);
+        let mut fru = vec![0x01, 0, 0, 1, 1 + (board.len() / 8) as u8, 0, 0, 0];
+        fru.extend(board);
+        fru.extend(product);
+        fru
+    }
+}
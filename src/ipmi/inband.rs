@@ -0,0 +1,306 @@
+//! In-band access through the OpenIPMI character device (`/dev/ipmi0`), without ipmitool.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use libc::{c_int, c_long, c_short, c_uint, c_ulong};
+
+use crate::ipmi::{check_completion, sdr, IpmiTransport};
+use crate::sensor_result::SensorResult;
+
+pub const DEFAULT_DEVICE: &str = "/dev/ipmi0";
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const IPMI_IOC_MAGIC: c_ulong = b'i' as c_ulong;
+const IPMI_SYSTEM_INTERFACE_ADDR_TYPE: c_int = 0x0c;
+const IPMI_BMC_CHANNEL: c_short = 0x0f;
+const IPMI_RESPONSE_RECV_TYPE: c_int = 1;
+const IPMI_MAX_MSG_LENGTH: usize = 272;
+
+#[repr(C)]
+struct IpmiMsg {
+    netfn: u8,
+    cmd: u8,
+    data_len: u16,
+    data: *mut u8,
+}
+
+#[repr(C)]
+struct IpmiReq {
+    addr: *mut u8,
+    addr_len: c_uint,
+    msgid: c_long,
+    msg: IpmiMsg,
+}
+
+#[repr(C)]
+struct IpmiRecv {
+    recv_type: c_int,
+    addr: *mut u8,
+    addr_len: c_uint,
+    msgid: c_long,
+    msg: IpmiMsg,
+}
+
+#[repr(C)]
+struct IpmiSystemInterfaceAddr {
+    addr_type: c_int,
+    channel: c_short,
+    lun: u8,
+}
+
+/// linux/ioctl.h 中的 _IOC(dir, 'i', nr, size)
+const fn ioc(dir: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    (dir << 30) | ((size as c_ulong) << 16) | (IPMI_IOC_MAGIC << 8) | nr
+}
+
+const IOC_READ: c_ulong = 2;
+const IOC_READ_WRITE: c_ulong = 3;
+const IPMICTL_RECEIVE_MSG_TRUNC: c_ulong = ioc(IOC_READ_WRITE, 11, std::mem::size_of::<IpmiRecv>());
+const IPMICTL_SEND_COMMAND: c_ulong = ioc(IOC_READ, 13, std::mem::size_of::<IpmiReq>());
+
+/// The kernel side of in-band requests, split out so tests can replace the character device.
+pub trait IpmiDevice: Send {
+    fn send(&mut self, msgid: i32, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<()>;
+
+    /// Waits for a response and returns (msgid, data), where data starts with the completion code.
+    fn receive(&mut self, timeout: Duration) -> io::Result<(i32, Vec<u8>)>;
+}
+
+/// The OpenIPMI character device driven through `IPMICTL_SEND_COMMAND` / `IPMICTL_RECEIVE_MSG_TRUNC`.
+pub struct OpenIpmiDevice {
+    file: File,
+}
+
+impl OpenIpmiDevice {
+    pub fn open(path: &Path) -> io::Result<OpenIpmiDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path).map_err(|e| {
+            let hint = match e.kind() {
+                io::ErrorKind::NotFound => "加载 ipmi_devintf 与 ipmi_si 模块（modprobe ipmi_devintf ipmi_si），或者改用 out-band 模式/load the ipmi_devintf and ipmi_si modules, or use out-band mode",
+                io::ErrorKind::PermissionDenied => "需要 root 权限/run smartfan as root",
+                _ => "",
+            };
+            io::Error::new(e.kind(), format!("Cannot open IPMI device {}: {}. {}", path.display(), e, hint))
+        })?;
+        Ok(OpenIpmiDevice { file })
+    }
+}
+
+impl IpmiDevice for OpenIpmiDevice {
+    fn send(&mut self, msgid: i32, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<()> {
+        let mut addr = IpmiSystemInterfaceAddr {
+            addr_type: IPMI_SYSTEM_INTERFACE_ADDR_TYPE,
+            channel: IPMI_BMC_CHANNEL,
+            lun: 0,
+        };
+        let mut data = data.to_vec();
+        let mut req = IpmiReq {
+            addr: &mut addr as *mut _ as *mut u8,
+            addr_len: std::mem::size_of::<IpmiSystemInterfaceAddr>() as c_uint,
+            msgid: c_long::from(msgid),
+            msg: IpmiMsg {
+                netfn,
+                cmd,
+                data_len: data.len() as u16,
+                data: data.as_mut_ptr(),
+            },
+        };
+        // SAFETY: req and the buffers it points to outlive the call, the kernel copies them.
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), IPMICTL_SEND_COMMAND as _, &mut req) };
+        if ret < 0 {
+            return Err(ioctl_error(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> io::Result<(i32, Vec<u8>)> {
+        let fd = self.file.as_raw_fd();
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: pfd is a valid pollfd for the duration of the call.
+        let ready = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as c_int) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if ready == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "IPMI device did not respond/BMC 无响应"));
+        }
+
+        let mut addr = [0u8; 64];
+        let mut data = [0u8; IPMI_MAX_MSG_LENGTH];
+        let mut recv = IpmiRecv {
+            recv_type: 0,
+            addr: addr.as_mut_ptr(),
+            addr_len: addr.len() as c_uint,
+            msgid: 0,
+            msg: IpmiMsg {
+                netfn: 0,
+                cmd: 0,
+                data_len: data.len() as u16,
+                data: data.as_mut_ptr(),
+            },
+        };
+        // SAFETY: recv points at stack buffers whose sizes are passed along.
+        let ret = unsafe { libc::ioctl(fd, IPMICTL_RECEIVE_MSG_TRUNC as _, &mut recv) };
+        if ret < 0 {
+            return Err(ioctl_error(io::Error::last_os_error()));
+        }
+        if recv.recv_type != IPMI_RESPONSE_RECV_TYPE {
+            // 事件或命令消息，不是我们的响应
+            return Ok((-1, vec![]));
+        }
+        let len = (recv.msg.data_len as usize).min(data.len());
+        Ok((i32::try_from(recv.msgid).unwrap_or(-1), data[..len].to_vec()))
+    }
+}
+
+fn ioctl_error(e: io::Error) -> io::Error {
+    if e.raw_os_error() == Some(libc::ENOTTY) {
+        return io::Error::new(io::ErrorKind::Unsupported, "Not an OpenIPMI device/不是 IPMI 设备");
+    }
+    e
+}
+
+/// In-band transport over an [`IpmiDevice`].
+pub struct InBand<D: IpmiDevice = OpenIpmiDevice> {
+    device: D,
+    msgid: i32,
+    sdr: Option<Vec<sdr::SensorRecord>>,
+}
+
+impl InBand<OpenIpmiDevice> {
+    pub fn open(path: &Path) -> io::Result<InBand<OpenIpmiDevice>> {
+        Ok(InBand::with_device(OpenIpmiDevice::open(path)?))
+    }
+}
+
+impl<D: IpmiDevice> InBand<D> {
+    pub fn with_device(device: D) -> InBand<D> {
+        InBand {
+            device,
+            msgid: 0,
+            sdr: None,
+        }
+    }
+
+    fn request(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.msgid = self.msgid.wrapping_add(1) & 0x7fff_ffff;
+        self.device.send(self.msgid, netfn, cmd, data)?;
+        loop {
+            let (msgid, rsp) = self.device.receive(RESPONSE_TIMEOUT)?;
+            if msgid != self.msgid {
+                continue;
+            }
+            let Some((&cc, rsp)) = rsp.split_first() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty IPMI response"));
+            };
+            check_completion(netfn, cmd, cc)?;
+            return Ok(rsp.to_vec());
+        }
+    }
+}
+
+impl<D: IpmiDevice> IpmiTransport for InBand<D> {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let mut cache = self.sdr.take();
+        let result = sdr::read_sensors(&mut |netfn, cmd, data: &[u8]| self.request(netfn, cmd, data), &mut cache);
+        self.sdr = cache;
+        result
+    }
+
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.request(netfn, cmd, data)
+    }
+
+    fn set_lower_thresholds(&mut self, sensor: &str, lower: [f64; 3]) -> io::Result<()> {
+        let mut cache = self.sdr.take();
+        let result = sdr::set_lower_thresholds(
+            &mut |netfn, cmd, data: &[u8]| self.request(netfn, cmd, data),
+            &mut cache,
+            sensor,
+            lower,
+        );
+        self.sdr = cache;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::sim::{full_sensor_record, SimBmc};
+    use std::collections::VecDeque;
+
+    /// Answers from a [`SimBmc`], with an unrelated message slipped in before every response.
+    struct FakeDevice {
+        bmc: SimBmc,
+        pending: VecDeque<(i32, Vec<u8>)>,
+    }
+
+    impl IpmiDevice for FakeDevice {
+        fn send(&mut self, msgid: i32, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<()> {
+            let (cc, data) = self.bmc.respond(netfn, cmd, data);
+            let mut rsp = vec![cc];
+            rsp.extend(data);
+            self.pending.push_back((msgid - 100, vec![0]));
+            self.pending.push_back((msgid, rsp));
+            Ok(())
+        }
+
+        fn receive(&mut self, _timeout: Duration) -> io::Result<(i32, Vec<u8>)> {
+            self.pending
+                .pop_front()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))
+        }
+    }
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // 与 64 位 Linux 上 ipmitool 使用的值一致
+        if std::mem::size_of::<c_long>() == 8 {
+            assert_eq!(IPMICTL_SEND_COMMAND, 0x8028690d);
+            assert_eq!(IPMICTL_RECEIVE_MSG_TRUNC, 0xc030690b);
+        }
+    }
+
+    #[test]
+    fn test_missing_device() {
+        let err = InBand::open(Path::new("/nonexistent/ipmi0")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("ipmi_devintf"));
+    }
+
+    #[test]
+    fn test_not_an_ipmi_device() {
+        let path = std::env::temp_dir().join(format!("smartfan-fake-ipmi-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let mut transport = InBand::open(&path).unwrap();
+        let err = transport.raw(0x06, 0x01, &[]).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_fake_device() {
+        let mut bmc = SimBmc::default();
+        bmc.add_sensor(full_sensor_record(1, 0x01, "CPU1_Temp", 1, 1, 0, 0), 41);
+        let mut transport = InBand::with_device(FakeDevice {
+            bmc: bmc.clone(),
+            pending: VecDeque::new(),
+        });
+
+        let sensors = transport.sensors().unwrap();
+        assert_eq!(sensors.len(), 1);
+        assert_eq!(sensors[0].value, Some(41.0));
+
+        transport.raw(0x2e, 0x30, &[0, 0, 20]).unwrap();
+        assert_eq!(bmc.raw_log(), vec![(0x2e, 0x30, vec![0, 0, 20])]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::process::{Command, Output};
+
+use crate::ipmi::{CompletionError, IpmiTransport};
+use crate::sensor_result::SensorResult;
+
+/// Runs the `ipmitool` binary for every request. Arguments are passed as they are, without a
+/// shell, so sensor names and passwords cannot break the command line.
+pub struct IpmiTool {
+    program: String,
+    /// 接口、主机和账号等每次都要带的参数
+    args: Vec<String>,
+}
+
+impl IpmiTool {
+    /// Talks to the local BMC through whatever interface ipmitool picks (usually `/dev/ipmi0`).
+    pub fn in_band() -> IpmiTool {
+        IpmiTool {
+            program: "ipmitool".to_string(),
+            args: vec![],
+        }
+    }
+
+    pub fn lanplus(host: &str, username: &str, password: &str) -> IpmiTool {
+        IpmiTool {
+            program: "ipmitool".to_string(),
+            args: ["-I", "lanplus", "-H", host, "-U", username, "-P", password].iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn run(&self, args: &[String]) -> io::Result<Output> {
+        let output = Command::new(&self.program).args(&self.args).args(args).output()?;
+        if !output.status.success() {
+            // 不带账号参数，避免密码出现在日志里
+            return Err(io::Error::other(format!(
+                "Error executing command: {} {}. Error: {}",
+                self.program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(output)
+    }
+}
+
+impl IpmiTransport for IpmiTool {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let output = self.run(&["sensor".to_string()])?;
+        Ok(parse_sensor_output(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut args = vec!["raw".to_string(), format!("0x{:02x}", netfn), format!("0x{:02x}", cmd)];
+        args.extend(data.iter().map(|b| format!("0x{:02x}", b)));
+        let output = self.run(&args).map_err(|e| match raw_completion_code(&e.to_string()) {
+            Some(cc) => io::Error::other(CompletionError { netfn, cmd, cc }),
+            None => e,
+        })?;
+        parse_raw_output(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn set_lower_thresholds(&mut self, sensor: &str, lower: [f64; 3]) -> io::Result<()> {
+        let mut args = vec!["sensor".to_string(), "thresh".to_string(), sensor.to_string(), "lower".to_string()];
+        args.extend(lower.iter().map(|v| v.to_string()));
+        self.run(&args)?;
+        Ok(())
+    }
+}
+
+// CPU1_Temp        | 34.000     | degrees C  | ok    | na        | na        | na        | 93.000    | 100.000   | 105.000
+// CPU2_Temp        | 0.000      | degrees C  | ok    | na        | na        | na        | 100.000   | 102.000   | 104.000
+// CPU1_VR_Temp     | 30.000     | degrees C  | ok    | na        | na        | na        | 112.000   | 123.000   | 133.000
+// CPU2_VR_Temp     | 15.000     | degrees C  | ok    | na        | na        | na        | 112.000   | 123.000   | 133.000
+pub fn parse_sensor_output(output: &str) -> Vec<SensorResult> {
+    output
+        .lines()
+        .filter_map(|line| SensorResult::from_line(line).ok())
+        .collect()
+}
+
+/// `Unable to send RAW command (channel=0x0 netfn=0xa lun=0x0 cmd=0x23 rsp=0xc5): ...`
+fn raw_completion_code(stderr: &str) -> Option<u8> {
+    let start = stderr.find("rsp=0x")? + "rsp=0x".len();
+    u8::from_str_radix(stderr.get(start..start + 2)?, 16).ok()
+}
+
+/// `ipmitool raw` 把响应数据按十六进制打印，例如 ` 01 51 00`
+pub fn parse_raw_output(output: &str) -> io::Result<Vec<u8>> {
+    output
+        .split_whitespace()
+        .map(|s| {
+            u8::from_str_radix(s, 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unexpected ipmitool raw output: {}", output.trim()),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_raw_output() {
+        assert_eq!(parse_raw_output(" 20 81 05\n 02\n").unwrap(), vec![0x20, 0x81, 0x05, 0x02]);
+        assert_eq!(parse_raw_output("").unwrap(), Vec::<u8>::new());
+        assert!(parse_raw_output("Unable to send RAW command").is_err());
+        assert_eq!(
+            raw_completion_code("Unable to send RAW command (channel=0x0 netfn=0xa lun=0x0 cmd=0x23 rsp=0xc5): Reservation cancelled or invalid"),
+            Some(0xc5)
+        );
+    }
+
+    /// 参数原样传给程序，不经过 shell
+    #[cfg(unix)]
+    #[test]
+    fn test_arguments_without_shell() {
+        let tool = IpmiTool {
+            program: "printf".to_string(),
+            args: vec!["%s|".to_string()],
+        };
+        let args: Vec<String> = ["sensor", "thresh", "FAN'1; echo pwned", "lower"].iter().map(|a| a.to_string()).collect();
+        let output = tool.run(&args).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "sensor|thresh|FAN'1; echo pwned|lower|");
+    }
+}
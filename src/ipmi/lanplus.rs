@@ -0,0 +1,698 @@
+//! IPMI v2.0 RMCP+ (`ipmitool -I lanplus`) client.
+//!
+//! The session is opened lazily on the first request, re-opened after it has been idle longer
+//! than the BMC would keep it, and closed when the [`Session`] is dropped.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::ipmi::{check_completion, checksum, sdr, IpmiTransport, BMC_SLAVE_ADDR, NETFN_APP, REMOTE_SWID};
+use crate::sensor_result::SensorResult;
+
+pub const DEFAULT_PORT: u16 = 623;
+
+pub(crate) const RMCP_HEADER: [u8; 4] = [0x06, 0x00, 0xff, 0x07];
+pub(crate) const AUTH_TYPE_NONE: u8 = 0x00;
+pub(crate) const AUTH_TYPE_RMCP_PLUS: u8 = 0x06;
+
+pub(crate) const PAYLOAD_IPMI: u8 = 0x00;
+pub(crate) const PAYLOAD_OPEN_SESSION_REQUEST: u8 = 0x10;
+pub(crate) const PAYLOAD_OPEN_SESSION_RESPONSE: u8 = 0x11;
+pub(crate) const PAYLOAD_RAKP1: u8 = 0x12;
+pub(crate) const PAYLOAD_RAKP2: u8 = 0x13;
+pub(crate) const PAYLOAD_RAKP3: u8 = 0x14;
+pub(crate) const PAYLOAD_RAKP4: u8 = 0x15;
+const PAYLOAD_ENCRYPTED: u8 = 0x80;
+const PAYLOAD_AUTHENTICATED: u8 = 0x40;
+
+pub(crate) const CMD_GET_CHANNEL_AUTH_CAPABILITIES: u8 = 0x38;
+pub(crate) const CMD_SET_SESSION_PRIVILEGE: u8 = 0x3b;
+pub(crate) const CMD_CLOSE_SESSION: u8 = 0x3c;
+
+pub(crate) const PRIV_ADMIN: u8 = 0x04;
+/// RAKP1 角色字段的 bit 4：只按用户名查找，不校验权限级别
+const NAME_ONLY_LOOKUP: u8 = 0x10;
+/// Kuid 固定为 20 字节，密码不足时补零
+const PASSWORD_LEN: usize = 20;
+
+/// BMC 一般在 60 秒没有流量后关闭会话，提前重新建立
+const SESSION_IDLE_LIMIT: Duration = Duration::from_secs(45);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRIES: usize = 3;
+
+/// Authentication, integrity and confidentiality algorithms negotiated for a session.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CipherSuite {
+    /// RAKP-HMAC-SHA1, HMAC-SHA1-96, AES-CBC-128, the ipmitool default
+    Suite3,
+    /// RAKP-HMAC-SHA256, HMAC-SHA256-128, AES-CBC-128
+    Suite17,
+}
+
+impl CipherSuite {
+    pub fn from_id(id: u8) -> io::Result<CipherSuite> {
+        match id {
+            3 => Ok(CipherSuite::Suite3),
+            17 => Ok(CipherSuite::Suite17),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported cipher suite {}, use 3 or 17", id),
+            )),
+        }
+    }
+
+    /// (authentication, integrity, confidentiality) algorithm numbers
+    pub(crate) fn algorithms(self) -> (u8, u8, u8) {
+        match self {
+            CipherSuite::Suite3 => (0x01, 0x01, 0x01),
+            CipherSuite::Suite17 => (0x03, 0x04, 0x01),
+        }
+    }
+
+    pub(crate) fn hmac(self, key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            CipherSuite::Suite3 => {
+                let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("hmac accepts any key length");
+                parts.iter().for_each(|p| mac.update(p));
+                mac.finalize().into_bytes().to_vec()
+            }
+            CipherSuite::Suite17 => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac accepts any key length");
+                parts.iter().for_each(|p| mac.update(p));
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Length of the truncated HMAC used for packet integrity and the RAKP4 check value.
+    pub(crate) fn integrity_len(self) -> usize {
+        match self {
+            CipherSuite::Suite3 => 12,
+            CipherSuite::Suite17 => 16,
+        }
+    }
+}
+
+/// K1、K2 的输入：规范和 ipmitool 固定用 20 字节，与 SIK 长度无关（套件 17 的 SIK 是 32 字节）
+const CONST_1: [u8; 20] = [0x01; 20];
+const CONST_2: [u8; 20] = [0x02; 20];
+
+/// Keys derived from the session integrity key once RAKP has completed.
+#[derive(Clone)]
+pub(crate) struct SessionKeys {
+    pub suite: CipherSuite,
+    pub k1: Vec<u8>,
+    pub k2: Vec<u8>,
+}
+
+impl SessionKeys {
+    pub fn derive(suite: CipherSuite, sik: &[u8]) -> SessionKeys {
+        SessionKeys {
+            suite,
+            k1: suite.hmac(sik, &[&CONST_1]),
+            k2: suite.hmac(sik, &[&CONST_2]),
+        }
+    }
+
+    fn cipher(&self) -> Aes128 {
+        Aes128::new(GenericArray::from_slice(&self.k2[..16]))
+    }
+
+    fn integrity(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = self.suite.hmac(&self.k1, &[data]);
+        mac.truncate(self.suite.integrity_len());
+        mac
+    }
+
+    /// AES-CBC-128, IV 放在密文前面，填充为 1, 2, 3... 再加一个填充长度字节
+    pub fn encrypt(&self, payload: &[u8]) -> Vec<u8> {
+        let mut iv = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut iv);
+        let mut plain = payload.to_vec();
+        let pad = (16 - (payload.len() + 1) % 16) % 16;
+        plain.extend((1..=pad as u8).collect::<Vec<_>>());
+        plain.push(pad as u8);
+
+        let cipher = self.cipher();
+        let mut out = iv.to_vec();
+        let mut prev = iv;
+        for chunk in plain.chunks(16) {
+            let mut block = [0u8; 16];
+            for i in 0..16 {
+                block[i] = chunk[i] ^ prev[i];
+            }
+            let mut block = GenericArray::from(block);
+            cipher.encrypt_block(&mut block);
+            prev.copy_from_slice(&block);
+            out.extend_from_slice(&block);
+        }
+        out
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < 32 || !data.len().is_multiple_of(16) {
+            return Err(invalid("Encrypted payload has an invalid length"));
+        }
+        let cipher = self.cipher();
+        let mut prev: [u8; 16] = data[..16].try_into().expect("16 byte IV");
+        let mut plain = vec![];
+        for chunk in data[16..].chunks(16) {
+            let mut block = GenericArray::clone_from_slice(chunk);
+            cipher.decrypt_block(&mut block);
+            plain.extend(block.iter().zip(prev.iter()).map(|(b, p)| b ^ p));
+            prev.copy_from_slice(chunk);
+        }
+        let pad = *plain.last().expect("at least one block") as usize;
+        if pad + 1 > plain.len() {
+            return Err(invalid("Encrypted payload has an invalid pad length"));
+        }
+        plain.truncate(plain.len() - pad - 1);
+        Ok(plain)
+    }
+}
+
+/// Builds an RMCP+ packet. Without keys the payload is sent in the clear, as during session setup.
+pub(crate) fn seal(keys: Option<&SessionKeys>, payload_type: u8, session_id: u32, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let (payload_type, payload) = match keys {
+        Some(k) => (payload_type | PAYLOAD_ENCRYPTED | PAYLOAD_AUTHENTICATED, k.encrypt(payload)),
+        None => (payload_type, payload.to_vec()),
+    };
+
+    let mut packet = RMCP_HEADER.to_vec();
+    packet.push(AUTH_TYPE_RMCP_PLUS);
+    packet.push(payload_type);
+    packet.extend(session_id.to_le_bytes());
+    packet.extend(seq.to_le_bytes());
+    packet.extend((payload.len() as u16).to_le_bytes());
+    packet.extend(&payload);
+
+    if let Some(k) = keys {
+        // 完整性填充：从认证类型到 next header 的长度要是 4 的倍数
+        let session_len = packet.len() - RMCP_HEADER.len();
+        let pad = (4 - (session_len + 2) % 4) % 4;
+        packet.extend(vec![0xff; pad]);
+        packet.push(pad as u8);
+        packet.push(0x07);
+        let auth_code = k.integrity(&packet[RMCP_HEADER.len()..]);
+        packet.extend(auth_code);
+    }
+    packet
+}
+
+/// An RMCP+ packet after the integrity check and decryption.
+pub(crate) struct Opened {
+    pub payload_type: u8,
+    pub session_id: u32,
+    pub payload: Vec<u8>,
+}
+
+pub(crate) fn open(keys: Option<&SessionKeys>, packet: &[u8]) -> io::Result<Opened> {
+    if packet.len() < 16 || packet[..4] != RMCP_HEADER || packet[4] != AUTH_TYPE_RMCP_PLUS {
+        return Err(invalid("Not an RMCP+ packet"));
+    }
+    let payload_type = packet[5];
+    let session_id = u32::from_le_bytes(packet[6..10].try_into().unwrap());
+    let len = u16::from_le_bytes([packet[14], packet[15]]) as usize;
+    if packet.len() < 16 + len {
+        return Err(invalid("RMCP+ packet is truncated"));
+    }
+    let mut payload = packet[16..16 + len].to_vec();
+
+    if payload_type & PAYLOAD_AUTHENTICATED != 0 {
+        let k = keys.ok_or_else(|| invalid("Authenticated packet outside of a session"))?;
+        let code_len = k.suite.integrity_len();
+        if packet.len() < 16 + len + 2 + code_len {
+            return Err(invalid("RMCP+ packet is missing its auth code"));
+        }
+        let (signed, auth_code) = packet.split_at(packet.len() - code_len);
+        if k.integrity(&signed[RMCP_HEADER.len()..]) != auth_code {
+            return Err(invalid("RMCP+ packet failed the integrity check"));
+        }
+    }
+    if payload_type & PAYLOAD_ENCRYPTED != 0 {
+        let k = keys.ok_or_else(|| invalid("Encrypted packet outside of a session"))?;
+        payload = k.decrypt(&payload)?;
+    }
+    Ok(Opened {
+        payload_type: payload_type & 0x3f,
+        session_id,
+        payload,
+    })
+}
+
+/// IPMI LAN 请求：rsAddr, netFn/rsLUN, chk1, rqAddr, rqSeq/rqLUN, cmd, data, chk2
+pub(crate) fn encode_request(netfn: u8, cmd: u8, rq_seq: u8, data: &[u8]) -> Vec<u8> {
+    let mut msg = vec![BMC_SLAVE_ADDR, netfn << 2];
+    msg.push(checksum(&msg));
+    msg.extend([REMOTE_SWID, rq_seq << 2, cmd]);
+    msg.extend(data);
+    msg.push(checksum(&msg[3..]));
+    msg
+}
+
+/// 解析 IPMI LAN 响应，返回 (netfn, rq_seq, cmd, completion code, data)
+pub(crate) fn decode_response(msg: &[u8]) -> io::Result<(u8, u8, u8, u8, Vec<u8>)> {
+    if msg.len() < 8 {
+        return Err(invalid("IPMI response is too short"));
+    }
+    if checksum(&msg[..2]) != msg[2] || checksum(&msg[3..msg.len() - 1]) != msg[msg.len() - 1] {
+        return Err(invalid("IPMI response has a bad checksum"));
+    }
+    Ok((msg[1] >> 2, msg[4] >> 2, msg[5], msg[6], msg[7..msg.len() - 1].to_vec()))
+}
+
+pub(crate) fn password_key(password: &str) -> Vec<u8> {
+    let mut key = password.as_bytes().to_vec();
+    key.resize(PASSWORD_LEN, 0);
+    key
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn rakp_status_str(code: u8) -> &'static str {
+    match code {
+        0x01 => "Insufficient resources to create a session",
+        0x02 => "Invalid session ID",
+        0x03 => "Invalid payload type",
+        0x04 => "Invalid authentication algorithm",
+        0x05 => "Invalid integrity algorithm",
+        0x06 => "No matching authentication payload",
+        0x07 => "No matching integrity payload",
+        0x08 => "Inactive session ID",
+        0x09 => "Invalid role",
+        0x0a => "Unauthorized role or privilege level requested",
+        0x0b => "Insufficient resources to create a session at the requested role",
+        0x0c => "Invalid name length",
+        0x0d => "Unauthorized name",
+        0x0e => "Unauthorized GUID",
+        0x0f => "Invalid integrity check value",
+        0x10 => "Invalid confidentiality algorithm",
+        0x11 => "No cipher suite match with proposed security algorithms",
+        0x12 => "Illegal or unrecognized parameter",
+        _ => "Unknown status",
+    }
+}
+
+fn rakp_error(step: &str, code: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} failed: {} (0x{:02x})", step, rakp_status_str(code), code),
+    )
+}
+
+struct Active {
+    keys: SessionKeys,
+    console_id: u32,
+    bmc_id: u32,
+    seq: u32,
+}
+
+/// An RMCP+ session with one BMC.
+pub struct Session {
+    socket: UdpSocket,
+    username: String,
+    password: String,
+    suite: CipherSuite,
+    active: Option<Active>,
+    rq_seq: u8,
+    last_used: Instant,
+    sdr: Option<Vec<sdr::SensorRecord>>,
+}
+
+impl Session {
+    /// Binds a local socket for `host` (optionally `host:port`). No traffic is sent until the first request.
+    pub fn new(host: &str, username: &str, password: &str, suite: CipherSuite) -> io::Result<Session> {
+        let addr = resolve(host)?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        Ok(Session {
+            socket,
+            username: username.to_string(),
+            password: password.to_string(),
+            suite,
+            active: None,
+            rq_seq: 0,
+            last_used: Instant::now(),
+            sdr: None,
+        })
+    }
+
+    /// Sends a raw request, (re)establishing the session first when needed.
+    fn raw_request(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.keep_alive()?;
+        let result = self.request(netfn, cmd, data);
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock {
+                // BMC 可能已经丢弃了会话，重建后再试一次
+                self.active = None;
+                self.keep_alive()?;
+                return self.request(netfn, cmd, data);
+            }
+        }
+        result
+    }
+
+    /// Reads all threshold sensors. The SDR repository is only walked once per session object.
+    fn read_sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let mut cache = self.sdr.take();
+        let result = sdr::read_sensors(&mut |netfn, cmd, data: &[u8]| self.raw_request(netfn, cmd, data), &mut cache);
+        self.sdr = cache;
+        result
+    }
+
+    /// Opens a new session when there is none or the current one has been idle too long.
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        if self.active.is_some() && self.last_used.elapsed() < SESSION_IDLE_LIMIT {
+            return Ok(());
+        }
+        if self.active.is_some() {
+            self.close();
+        }
+        self.establish()
+    }
+
+    fn establish(&mut self) -> io::Result<()> {
+        self.get_channel_auth_capabilities()?;
+
+        let mut rng = rand::thread_rng();
+        let console_id = rng.next_u32() | 1;
+        let tag = (rng.next_u32() & 0xff) as u8;
+        let (auth, integrity, conf) = self.suite.algorithms();
+
+        // Open Session
+        let mut req = vec![tag, 0, 0, 0];
+        req.extend(console_id.to_le_bytes());
+        req.extend([0x00, 0, 0, 0x08, auth, 0, 0, 0]);
+        req.extend([0x01, 0, 0, 0x08, integrity, 0, 0, 0]);
+        req.extend([0x02, 0, 0, 0x08, conf, 0, 0, 0]);
+        let rsp = self.exchange(PAYLOAD_OPEN_SESSION_REQUEST, &req, PAYLOAD_OPEN_SESSION_RESPONSE, tag)?;
+        if rsp.len() > 1 && rsp[1] != 0 {
+            return Err(rakp_error("Open Session", rsp[1]));
+        }
+        if rsp.len() < 12 {
+            return Err(invalid("Open Session response is too short"));
+        }
+        let bmc_id = u32::from_le_bytes(rsp[8..12].try_into().unwrap());
+
+        // RAKP 1/2
+        let mut rm = [0u8; 16];
+        rng.fill_bytes(&mut rm);
+        let role = PRIV_ADMIN | NAME_ONLY_LOOKUP;
+        let username = self.username.clone();
+        let user = username.as_bytes();
+        let mut req = vec![tag, 0, 0, 0];
+        req.extend(bmc_id.to_le_bytes());
+        req.extend(rm);
+        req.extend([role, 0, 0, user.len() as u8]);
+        req.extend(user);
+        let rsp = self.exchange(PAYLOAD_RAKP1, &req, PAYLOAD_RAKP2, tag)?;
+        if rsp.len() > 1 && rsp[1] != 0 {
+            return Err(rakp_error("RAKP 2", rsp[1]));
+        }
+        let hash_len = self.suite.hmac(&[], &[]).len();
+        if rsp.len() < 40 + hash_len {
+            return Err(invalid("RAKP 2 response is too short"));
+        }
+        let rc = &rsp[8..24];
+        let guid = &rsp[24..40];
+        let kuid = password_key(&self.password);
+        let ulen = [user.len() as u8];
+        let expected = self.suite.hmac(
+            &kuid,
+            &[&console_id.to_le_bytes(), &bmc_id.to_le_bytes(), &rm, rc, guid, &[role], &ulen, user],
+        );
+        if rsp[40..40 + hash_len] != expected[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "RAKP 2 HMAC is invalid, check the BMC username and password/用户名或密码错误",
+            ));
+        }
+        let sik = self.suite.hmac(&kuid, &[&rm, rc, &[role], &ulen, user]);
+
+        // RAKP 3/4
+        let mut req = vec![tag, 0, 0, 0];
+        req.extend(bmc_id.to_le_bytes());
+        req.extend(self.suite.hmac(&kuid, &[rc, &console_id.to_le_bytes(), &[role], &ulen, user]));
+        let rsp = self.exchange(PAYLOAD_RAKP3, &req, PAYLOAD_RAKP4, tag)?;
+        if rsp.len() > 1 && rsp[1] != 0 {
+            return Err(rakp_error("RAKP 4", rsp[1]));
+        }
+        let mut icv = self.suite.hmac(&sik, &[&rm, &bmc_id.to_le_bytes(), guid]);
+        icv.truncate(self.suite.integrity_len());
+        if rsp.len() < 8 + icv.len() || rsp[8..8 + icv.len()] != icv[..] {
+            return Err(invalid("RAKP 4 integrity check value is invalid"));
+        }
+
+        self.active = Some(Active {
+            keys: SessionKeys::derive(self.suite, &sik),
+            console_id,
+            bmc_id,
+            seq: 0,
+        });
+        self.last_used = Instant::now();
+        self.request(NETFN_APP, CMD_SET_SESSION_PRIVILEGE, &[PRIV_ADMIN])?;
+        log::info!("RMCP+ session 0x{:08x} established", bmc_id);
+        Ok(())
+    }
+
+    /// Get Channel Authentication Capabilities，使用 IPMI 1.5 无会话格式发送
+    fn get_channel_auth_capabilities(&mut self) -> io::Result<()> {
+        self.rq_seq = (self.rq_seq + 1) & 0x3f;
+        // 0x8e：当前信道，并请求 IPMI v2.0 扩展数据
+        let msg = encode_request(NETFN_APP, CMD_GET_CHANNEL_AUTH_CAPABILITIES, self.rq_seq, &[0x8e, PRIV_ADMIN]);
+        let mut packet = RMCP_HEADER.to_vec();
+        packet.push(AUTH_TYPE_NONE);
+        packet.extend([0u8; 8]);
+        packet.push(msg.len() as u8);
+        packet.extend(msg);
+
+        let mut buf = [0u8; 1024];
+        for _ in 0..RETRIES {
+            self.socket.send(&packet)?;
+            match self.socket.recv(&mut buf) {
+                Ok(n) if n > 14 && buf[..4] == RMCP_HEADER && buf[4] == AUTH_TYPE_NONE => {
+                    let (_, _, _, cc, data) = decode_response(&buf[14..n])?;
+                    check_completion(NETFN_APP, CMD_GET_CHANNEL_AUTH_CAPABILITIES, cc)?;
+                    // byte 2 bit 7: 支持 IPMI v2.0 扩展能力
+                    if data.len() > 1 && data[1] & 0x80 == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "BMC does not support IPMI v2.0/RMCP+",
+                        ));
+                    }
+                    return Ok(());
+                }
+                Ok(_) => continue,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(timeout("Get Channel Authentication Capabilities"))
+    }
+
+    /// Sends a session setup payload and waits for the matching response by message tag.
+    fn exchange(&mut self, payload_type: u8, payload: &[u8], expected: u8, tag: u8) -> io::Result<Vec<u8>> {
+        let packet = seal(None, payload_type, 0, 0, payload);
+        let mut buf = [0u8; 1024];
+        for _ in 0..RETRIES {
+            self.socket.send(&packet)?;
+            loop {
+                match self.socket.recv(&mut buf) {
+                    Ok(n) => {
+                        let Ok(opened) = open(None, &buf[..n]) else { continue };
+                        if opened.payload_type == expected && opened.payload.first() == Some(&tag) {
+                            return Ok(opened.payload);
+                        }
+                    }
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(timeout("RMCP+ session setup"))
+    }
+
+    fn request(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let active = self
+            .active
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No active RMCP+ session"))?;
+        self.rq_seq = (self.rq_seq + 1) & 0x3f;
+        let msg = encode_request(netfn, cmd, self.rq_seq, data);
+
+        let mut buf = [0u8; 1024];
+        for _ in 0..RETRIES {
+            active.seq = active.seq.wrapping_add(1).max(1);
+            let packet = seal(Some(&active.keys), PAYLOAD_IPMI, active.bmc_id, active.seq, &msg);
+            self.socket.send(&packet)?;
+            loop {
+                match self.socket.recv(&mut buf) {
+                    Ok(n) => {
+                        let Ok(opened) = open(Some(&active.keys), &buf[..n]) else { continue };
+                        if opened.payload_type != PAYLOAD_IPMI || opened.session_id != active.console_id {
+                            continue;
+                        }
+                        let (rsp_netfn, rsp_seq, rsp_cmd, cc, rsp_data) = decode_response(&opened.payload)?;
+                        if rsp_netfn != netfn | 1 || rsp_seq != self.rq_seq || rsp_cmd != cmd {
+                            continue;
+                        }
+                        self.last_used = Instant::now();
+                        check_completion(netfn, cmd, cc)?;
+                        return Ok(rsp_data);
+                    }
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(timeout(&format!("IPMI request netfn 0x{:02x} cmd 0x{:02x}", netfn, cmd)))
+    }
+
+    fn close(&mut self) {
+        if let Some(bmc_id) = self.active.as_ref().map(|a| a.bmc_id) {
+            let _ = self.request(NETFN_APP, CMD_CLOSE_SESSION, &bmc_id.to_le_bytes());
+        }
+        self.active = None;
+    }
+}
+
+impl IpmiTransport for Session {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        self.read_sensors()
+    }
+
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.raw_request(netfn, cmd, data)
+    }
+
+    fn set_lower_thresholds(&mut self, sensor: &str, lower: [f64; 3]) -> io::Result<()> {
+        let mut cache = self.sdr.take();
+        let result = sdr::set_lower_thresholds(
+            &mut |netfn, cmd, data: &[u8]| self.raw_request(netfn, cmd, data),
+            &mut cache,
+            sensor,
+            lower,
+        );
+        self.sdr = cache;
+        result
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Accepts `host`, `host:port`, an IP address or a socket address; the port defaults to 623.
+fn resolve(host: &str) -> io::Result<SocketAddr> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+    let addrs = if host.contains(':') {
+        host.to_socket_addrs()?
+    } else {
+        (host, DEFAULT_PORT).to_socket_addrs()?
+    };
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Cannot resolve BMC host {}", host)))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn timeout(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out/BMC 无响应", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::sim::{full_sensor_record, SimBmc, SimLan};
+
+    fn sim_bmc() -> SimBmc {
+        let mut bmc = SimBmc::default();
+        bmc.add_sensor(full_sensor_record(1, 0x01, "CPU1_Temp", 1, 1, 0, 0), 34);
+        bmc.add_sensor(full_sensor_record(2, 0x02, "FAN1_Speed", 18, 100, 0, 0), 54);
+        bmc
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let keys = SessionKeys::derive(CipherSuite::Suite3, &[7u8; 20]);
+        for len in [0, 1, 15, 16, 17, 40] {
+            let payload: Vec<u8> = (0..len as u8).collect();
+            assert_eq!(keys.decrypt(&keys.encrypt(&payload)).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn test_derive_keys() {
+        // 已知答案：按 ipmitool lanplus_generate_k1/k2 的算法，HMAC(SIK, 20 字节 0x01/0x02)
+        let hex = |s: &str| (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+        let keys = SessionKeys::derive(CipherSuite::Suite17, &(0..32).collect::<Vec<u8>>());
+        assert_eq!(keys.k1, hex("8f34f198a044babe550f6217f57fc801e20bee40dd16b9712797aaf5bac3106b"));
+        assert_eq!(keys.k2, hex("4711da70e361cc4884e705f63bb297e3ba8e1c56e5de896cc0282feba64592f0"));
+        let keys = SessionKeys::derive(CipherSuite::Suite3, &(0..20).collect::<Vec<u8>>());
+        assert_eq!(keys.k1, hex("34e51c571c5c392460e6775dd5ecfa79f4a7f505"));
+        assert_eq!(keys.k2, hex("c13076ed1957a59e8c7abb2460d22c1a159de60a"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("127.0.0.1").unwrap().port(), DEFAULT_PORT);
+        assert_eq!(resolve("127.0.0.1:6230").unwrap().port(), 6230);
+        assert_eq!(resolve("::1").unwrap().port(), DEFAULT_PORT);
+    }
+
+    #[test]
+    fn test_session_against_sim() {
+        for suite in [CipherSuite::Suite3, CipherSuite::Suite17] {
+            let lan = SimLan::start(sim_bmc(), "admin", "secret");
+            let mut session = Session::new(&lan.addr().to_string(), "admin", "secret", suite).unwrap();
+
+            let sensors = session.sensors().unwrap();
+            assert_eq!(sensors.len(), 2);
+            assert_eq!(sensors[0].sensor_name, "CPU1_Temp");
+            assert_eq!(sensors[0].value, Some(34.0));
+            assert_eq!(sensors[1].value, Some(5400.0));
+
+            session.raw(0x2e, 0x30, &[0x00, 0x00, 30]).unwrap();
+            assert_eq!(lan.bmc().raw_log(), vec![(0x2e, 0x30, vec![0x00, 0x00, 30])]);
+
+            drop(session);
+            assert!(lan.bmc().session_closed());
+        }
+    }
+
+    #[test]
+    fn test_session_wrong_password() {
+        let lan = SimLan::start(sim_bmc(), "admin", "secret");
+        let mut session = Session::new(&lan.addr().to_string(), "admin", "wrong", CipherSuite::Suite3).unwrap();
+        let err = session.sensors().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ipmi::{check_completion, IpmiTransport};
+use crate::sensor_result::SensorResult;
+
+#[derive(Default)]
+struct MockState {
+    sensors: VecDeque<io::Result<Vec<SensorResult>>>,
+    responses: HashMap<(u8, u8), VecDeque<io::Result<Vec<u8>>>>,
+    requests: Vec<(u8, u8, Vec<u8>)>,
+    thresholds: Vec<(String, [f64; 3])>,
+    sensors_delay: Duration,
+    sensor_reads: usize,
+}
+
+/// In-memory transport with scripted BMC responses.
+///
+/// Clones share the same script, so a test can keep one handle and move another into the loop.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Queues a sensor reading. The last successful reading is repeated once the queue runs dry.
+    pub fn push_sensors(&self, sensors: Vec<SensorResult>) {
+        self.state.lock().unwrap().sensors.push_back(Ok(sensors));
+    }
+
+    pub fn push_sensors_error(&self, msg: &str) {
+        self.state.lock().unwrap().sensors.push_back(Err(io::Error::other(msg.to_string())));
+    }
+
+    /// Makes every sensor read block for `delay`, like a slow BMC.
+    pub fn set_sensors_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().sensors_delay = delay;
+    }
+
+    /// How many sensor reads have started.
+    pub fn sensor_reads(&self) -> usize {
+        self.state.lock().unwrap().sensor_reads
+    }
+
+    /// Queues the response to the next `netfn`/`cmd` request. Unscripted requests succeed with no data.
+    pub fn push_response(&self, netfn: u8, cmd: u8, data: Vec<u8>) {
+        self.push(netfn, cmd, Ok(data));
+    }
+
+    /// Queues a completion code other than 0x00 for the next `netfn`/`cmd` request.
+    pub fn push_completion_code(&self, netfn: u8, cmd: u8, cc: u8) {
+        self.push(netfn, cmd, check_completion(netfn, cmd, cc).map(|_| vec![]));
+    }
+
+    pub fn push_error(&self, netfn: u8, cmd: u8, msg: &str) {
+        self.push(netfn, cmd, Err(io::Error::other(msg.to_string())));
+    }
+
+    fn push(&self, netfn: u8, cmd: u8, response: io::Result<Vec<u8>>) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry((netfn, cmd))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Every raw request sent so far, oldest first.
+    pub fn requests(&self) -> Vec<(u8, u8, Vec<u8>)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Every `set_lower_thresholds` call so far.
+    pub fn thresholds(&self) -> Vec<(String, [f64; 3])> {
+        self.state.lock().unwrap().thresholds.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+impl IpmiTransport for MockTransport {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            state.sensor_reads += 1;
+            state.sensors_delay
+        };
+        std::thread::sleep(delay);
+        let mut state = self.state.lock().unwrap();
+        match state.sensors.len() {
+            0 => Ok(vec![]),
+            1 => match state.sensors.front().unwrap() {
+                Ok(sensors) => Ok(sensors.clone()),
+                Err(_) => state.sensors.pop_front().unwrap(),
+            },
+            _ => state.sensors.pop_front().unwrap(),
+        }
+    }
+
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push((netfn, cmd, data.to_vec()));
+        state
+            .responses
+            .get_mut(&(netfn, cmd))
+            .and_then(|queue| queue.pop_front())
+            .unwrap_or(Ok(vec![]))
+    }
+
+    fn set_lower_thresholds(&mut self, sensor: &str, lower: [f64; 3]) -> io::Result<()> {
+        self.state.lock().unwrap().thresholds.push((sensor.to_string(), lower));
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io;
+
+use crate::config::Config;
+use crate::sensor_result::SensorResult;
+use identify::ServerIdentity;
+use crate::{IN_BAND, REDFISH};
+
+pub mod identify;
+#[cfg(target_os = "linux")]
+pub mod inband;
+pub mod ipmitool;
+pub mod lanplus;
+pub mod mock;
+pub mod sdr;
+#[cfg(test)]
+pub(crate) mod sim;
+
+pub const NETFN_SENSOR: u8 = 0x04;
+pub const NETFN_APP: u8 = 0x06;
+pub const NETFN_STORAGE: u8 = 0x0a;
+
+/// BMC 的从地址
+pub const BMC_SLAVE_ADDR: u8 = 0x20;
+/// 远程控制台的软件 ID
+pub const REMOTE_SWID: u8 = 0x81;
+
+/// Everything the control loop needs from a BMC connection.
+pub trait IpmiTransport: Send {
+    /// Reads every threshold sensor, the equivalent of `ipmitool sensor`.
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>>;
+
+    /// Sends a raw request and returns the response data without the completion code.
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Sets a fan zone to `duty` percent without raw commands.
+    /// Returns false when the transport has no such request and the caller should fall back to `raw`.
+    fn set_fan_duty(&mut self, _zone: u8, _duty: u8) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Undoes what `set_fan_duty` changed, for transports whose fan setting outlives the process.
+    fn restore_fan_duty(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Asks the BMC which server it is in, the equivalent of `ipmitool mc info` plus `ipmitool fru`.
+    fn identify(&mut self) -> io::Result<ServerIdentity> {
+        identify::identify(&mut |netfn, cmd, data: &[u8]| self.raw(netfn, cmd, data))
+    }
+
+    /// Sets the lower non-recoverable, critical and non-critical thresholds of a sensor.
+    fn set_lower_thresholds(&mut self, sensor: &str, _lower: [f64; 3]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot change thresholds of {} over this transport", sensor),
+        ))
+    }
+}
+
+/// Builds the transport selected by `mode` and `use_ipmitool` in the config.
+/// On Linux in-band mode talks to the OpenIPMI device directly, elsewhere it needs ipmitool.
+pub fn connect(config: &Config) -> io::Result<Box<dyn IpmiTransport>> {
+    if config.mode.to_lowercase() == REDFISH {
+        return connect_redfish(config);
+    }
+    if config.mode.to_lowercase() == IN_BAND {
+        #[cfg(target_os = "linux")]
+        if !config.use_ipmitool {
+            let device = config.ipmi_device.as_deref().unwrap_or(inband::DEFAULT_DEVICE);
+            return Ok(Box::new(inband::InBand::open(std::path::Path::new(device))?));
+        }
+        return Ok(Box::new(ipmitool::IpmiTool::in_band()));
+    }
+    if config.ipmi.username.is_empty() || config.ipmi.password.is_empty() || config.ipmi.host.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "必须配置用户名、密码和BMC主机地址，才能使用out-band模式/username, password and host must be set to use out-band mode.",
+        ));
+    }
+    if config.use_ipmitool {
+        return Ok(Box::new(ipmitool::IpmiTool::lanplus(
+            &config.ipmi.host,
+            &config.ipmi.username,
+            &config.ipmi.password,
+        )));
+    }
+    let suite = lanplus::CipherSuite::from_id(config.ipmi.cipher_suite)?;
+    Ok(Box::new(lanplus::Session::new(
+        &config.ipmi.host,
+        &config.ipmi.username,
+        &config.ipmi.password,
+        suite,
+    )?))
+}
+
+fn connect_redfish(config: &Config) -> io::Result<Box<dyn IpmiTransport>> {
+    let redfish = config.redfish.as_ref();
+    let url = match redfish.and_then(|r| r.url.clone()) {
+        Some(url) => url,
+        None if !config.ipmi.host.is_empty() => format!("https://{}", config.ipmi.host),
+        None => String::new(),
+    };
+    if url.is_empty() || config.ipmi.username.is_empty() || config.ipmi.password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "必须配置用户名、密码和BMC地址，才能使用redfish模式/username, password and host (or redfish.url) must be set to use redfish mode.",
+        ));
+    }
+    Ok(Box::new(crate::redfish::Redfish::new(
+        &url,
+        &config.ipmi.username,
+        &config.ipmi.password,
+        redfish.is_some_and(|r| r.verify_tls),
+        redfish.and_then(|r| r.fan_control.clone()),
+    )))
+}
+
+/// IPMI 校验和：所有字节相加后取二进制补码
+pub fn checksum(data: &[u8]) -> u8 {
+    0u8.wrapping_sub(data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)))
+}
+
+/// A response whose completion code was not 0x00.
+#[derive(Debug)]
+pub struct CompletionError {
+    pub netfn: u8,
+    pub cmd: u8,
+    pub cc: u8,
+}
+
+impl fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IPMI request netfn 0x{:02x} cmd 0x{:02x} failed: {} (0x{:02x})",
+            self.netfn,
+            self.cmd,
+            completion_code_str(self.cc),
+            self.cc
+        )
+    }
+}
+
+impl std::error::Error for CompletionError {}
+
+/// Turns a non-zero completion code into an error.
+pub fn check_completion(netfn: u8, cmd: u8, cc: u8) -> io::Result<()> {
+    if cc == 0 {
+        return Ok(());
+    }
+    Err(io::Error::other(CompletionError { netfn, cmd, cc }))
+}
+
+/// Completion code carried by an error from [`check_completion`], if any.
+pub fn completion_code(e: &io::Error) -> Option<u8> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<CompletionError>())
+        .map(|c| c.cc)
+}
+
+pub fn completion_code_str(cc: u8) -> &'static str {
+    match cc {
+        0x00 => "Command completed normally",
+        0xc0 => "Node busy",
+        0xc1 => "Invalid command",
+        0xc2 => "Invalid command on LUN",
+        0xc3 => "Timeout",
+        0xc4 => "Out of space",
+        0xc5 => "Reservation cancelled or invalid",
+        0xc6 => "Request data truncated",
+        0xc7 => "Request data length invalid",
+        0xc8 => "Request data field length limit exceeded",
+        0xc9 => "Parameter out of range",
+        0xca => "Cannot return number of requested data bytes",
+        0xcb => "Requested sensor, data, or record not found",
+        0xcc => "Invalid data field in request",
+        0xcd => "Command illegal for specified sensor or record type",
+        0xce => "Command response could not be provided",
+        0xcf => "Cannot execute duplicated request",
+        0xd0 => "SDR repository in update mode",
+        0xd1 => "Device firmware in update mode",
+        0xd2 => "BMC initialization in progress",
+        0xd3 => "Destination unavailable",
+        0xd4 => "Insufficient privilege level",
+        0xd5 => "Command not supported in present state",
+        0xd6 => "Cannot execute command, command disabled",
+        _ => "Unspecified error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // rsAddr + netFn/LUN of a Get Device ID request
+        assert_eq!(checksum(&[0x20, 0x18]), 0xc8);
+        assert_eq!(checksum(&[]), 0);
+    }
+}
@@ -0,0 +1,408 @@
+use std::io;
+
+use crate::ipmi::{completion_code, NETFN_SENSOR, NETFN_STORAGE};
+use crate::sensor_result::{SensorResult, Thresholds};
+
+const CMD_RESERVE_SDR_REPOSITORY: u8 = 0x22;
+const CMD_GET_SDR: u8 = 0x23;
+const CMD_SET_SENSOR_THRESHOLDS: u8 = 0x26;
+const CMD_GET_SENSOR_READING: u8 = 0x2d;
+
+const RECORD_TYPE_FULL: u8 = 0x01;
+const EVENT_TYPE_THRESHOLD: u8 = 0x01;
+const SDR_HEADER_LEN: usize = 5;
+const SDR_CHUNK_LEN: u8 = 16;
+const LAST_RECORD_ID: u16 = 0xffff;
+
+/// Sends one request to the BMC, returning the response data after the completion code.
+pub type RawFn<'a> = dyn FnMut(u8, u8, &[u8]) -> io::Result<Vec<u8>> + 'a;
+
+/// 全传感器记录（Full Sensor Record）中与读数换算相关的部分
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorRecord {
+    pub number: u8,
+    pub name: String,
+    pub unit: Option<String>,
+    analog_format: u8,
+    linearization: u8,
+    m: i16,
+    b: i16,
+    r_exp: i8,
+    b_exp: i8,
+    /// lnr, lc, lnc, unc, uc, unr 的原始值，不可读时为 None
+    thresholds: [Option<u8>; 6],
+    /// 可设置的阈值，位定义与 Set Sensor Thresholds 的掩码相同
+    settable: u8,
+}
+
+impl SensorRecord {
+    /// Parses a full sensor record of a threshold sensor owned by the BMC.
+    /// Discrete sensors and records of other types are skipped, like `ipmitool sensor` prints them without a value.
+    pub fn parse(record: &[u8]) -> Option<SensorRecord> {
+        if record.len() < 48 || record[3] != RECORD_TYPE_FULL || record[13] != EVENT_TYPE_THRESHOLD {
+            return None;
+        }
+        // 只读取 BMC 自己的 LUN 0 传感器
+        if record[5] != crate::ipmi::BMC_SLAVE_ADDR || record[6] & 0x03 != 0 {
+            return None;
+        }
+        let analog_format = record[20] >> 6;
+        if analog_format == 0x03 {
+            return None;
+        }
+
+        let readable = record[18];
+        let threshold = |bit: u8, idx: usize| (readable & (1 << bit) != 0).then_some(record[idx]);
+        let name_len = (record[47] & 0x1f) as usize;
+        let name_end = (48 + name_len).min(record.len());
+
+        Some(SensorRecord {
+            number: record[7],
+            name: String::from_utf8_lossy(&record[48..name_end]).trim().to_string(),
+            unit: unit_name(record[20], record[21]),
+            analog_format,
+            linearization: record[23] & 0x7f,
+            m: ten_bit(record[24], record[25]),
+            b: ten_bit(record[26], record[27]),
+            r_exp: four_bit(record[29] >> 4),
+            b_exp: four_bit(record[29] & 0x0f),
+            thresholds: [
+                threshold(2, 39),
+                threshold(1, 40),
+                threshold(0, 41),
+                threshold(3, 38),
+                threshold(4, 37),
+                threshold(5, 36),
+            ],
+            settable: record[19] & 0x3f,
+        })
+    }
+
+    /// y = L[(M * x + B * 10^Bexp) * 10^Rexp]
+    pub fn convert(&self, raw: u8) -> f64 {
+        let x = match self.analog_format {
+            0x01 => {
+                if raw & 0x80 != 0 {
+                    -((!raw & 0x7f) as f64)
+                } else {
+                    raw as f64
+                }
+            }
+            0x02 => raw as i8 as f64,
+            _ => raw as f64,
+        };
+        let y = (self.m as f64 * x + self.b as f64 * 10f64.powi(self.b_exp as i32))
+            * 10f64.powi(self.r_exp as i32);
+        let y = match self.linearization {
+            0x01 => y.ln(),
+            0x02 => y.log10(),
+            0x03 => y.log2(),
+            0x04 => y.exp(),
+            0x05 => 10f64.powf(y),
+            0x06 => 2f64.powf(y),
+            0x07 => 1.0 / y,
+            0x08 => y * y,
+            0x09 => y * y * y,
+            0x0a => y.sqrt(),
+            0x0b => y.cbrt(),
+            _ => y,
+        };
+        // 去掉浮点误差，ipmitool 也只打印三位小数
+        (y * 1000.0).round() / 1000.0
+    }
+
+    /// The raw value closest to `value`, the inverse of [`SensorRecord::convert`] for linear sensors.
+    pub fn to_raw(&self, value: f64) -> Option<u8> {
+        if self.linearization != 0 || self.m == 0 {
+            return None;
+        }
+        let x = (value / 10f64.powi(self.r_exp as i32) - self.b as f64 * 10f64.powi(self.b_exp as i32)) / self.m as f64;
+        let x = x.round();
+        Some(match self.analog_format {
+            0x01 => {
+                let x = x.clamp(-127.0, 127.0) as i8;
+                if x < 0 {
+                    !(x.unsigned_abs()) | 0x80
+                } else {
+                    x as u8
+                }
+            }
+            0x02 => x.clamp(-128.0, 127.0) as i8 as u8,
+            _ => x.clamp(0.0, 255.0) as u8,
+        })
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        let t = |i: usize| self.thresholds[i].map(|raw| self.convert(raw));
+        Thresholds {
+            lnr: t(0),
+            lc: t(1),
+            lnc: t(2),
+            unc: t(3),
+            uc: t(4),
+            unr: t(5),
+        }
+    }
+}
+
+fn ten_bit(ls: u8, ms: u8) -> i16 {
+    let v = (((ms & 0xc0) as i16) << 2) | ls as i16;
+    if v & 0x200 != 0 {
+        v - 0x400
+    } else {
+        v
+    }
+}
+
+fn four_bit(v: u8) -> i8 {
+    if v & 0x08 != 0 {
+        v as i8 - 0x10
+    } else {
+        v as i8
+    }
+}
+
+/// 单位名称与 ipmitool 保持一致，这样 `SensorResult::from_line` 与原生读取的结果可以互换
+fn unit_name(units1: u8, base: u8) -> Option<String> {
+    if units1 & 0x01 != 0 {
+        return Some("percent".to_string());
+    }
+    let name = match base {
+        1 => "degrees C",
+        2 => "degrees F",
+        3 => "degrees K",
+        4 => "Volts",
+        5 => "Amps",
+        6 => "Watts",
+        7 => "Joules",
+        8 => "Coulombs",
+        9 => "VA",
+        17 => "CFM",
+        18 => "RPM",
+        19 => "Hz",
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Walks the SDR repository and keeps the sensors that [`SensorRecord::parse`] understands.
+pub fn read_repository(raw: &mut RawFn) -> io::Result<Vec<SensorRecord>> {
+    let mut reservation = reserve(raw)?;
+    let mut records = vec![];
+    let mut record_id: u16 = 0;
+
+    while record_id != LAST_RECORD_ID {
+        let (next_id, record) = match read_record(raw, reservation, record_id) {
+            Ok(r) => r,
+            Err(e) if is_reservation_lost(&e) => {
+                reservation = reserve(raw)?;
+                read_record(raw, reservation, record_id)?
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(r) = SensorRecord::parse(&record) {
+            records.push(r);
+        }
+        if next_id == record_id {
+            break;
+        }
+        record_id = next_id;
+    }
+    Ok(records)
+}
+
+fn reserve(raw: &mut RawFn) -> io::Result<u16> {
+    let rsp = raw(NETFN_STORAGE, CMD_RESERVE_SDR_REPOSITORY, &[])?;
+    if rsp.len() < 2 {
+        return Err(short_response("Reserve SDR Repository"));
+    }
+    Ok(u16::from_le_bytes([rsp[0], rsp[1]]))
+}
+
+fn is_reservation_lost(e: &io::Error) -> bool {
+    completion_code(e) == Some(0xc5)
+}
+
+/// 先读 5 字节记录头，再按 16 字节分块读取剩余部分，兼容不支持一次读取整条记录的 BMC
+fn read_record(raw: &mut RawFn, reservation: u16, record_id: u16) -> io::Result<(u16, Vec<u8>)> {
+    let (next_id, mut record) = get_sdr(raw, reservation, record_id, 0, SDR_HEADER_LEN as u8)?;
+    if record.len() < SDR_HEADER_LEN {
+        return Err(short_response("Get SDR"));
+    }
+    let total = SDR_HEADER_LEN + record[4] as usize;
+    while record.len() < total {
+        let len = SDR_CHUNK_LEN.min((total - record.len()) as u8);
+        let (_, chunk) = get_sdr(raw, reservation, record_id, record.len() as u8, len)?;
+        if chunk.is_empty() {
+            return Err(short_response("Get SDR"));
+        }
+        record.extend(chunk);
+    }
+    Ok((next_id, record))
+}
+
+fn get_sdr(raw: &mut RawFn, reservation: u16, record_id: u16, offset: u8, len: u8) -> io::Result<(u16, Vec<u8>)> {
+    let [res_lo, res_hi] = reservation.to_le_bytes();
+    let [id_lo, id_hi] = record_id.to_le_bytes();
+    let rsp = raw(NETFN_STORAGE, CMD_GET_SDR, &[res_lo, res_hi, id_lo, id_hi, offset, len])?;
+    if rsp.len() < 2 {
+        return Err(short_response("Get SDR"));
+    }
+    Ok((u16::from_le_bytes([rsp[0], rsp[1]]), rsp[2..].to_vec()))
+}
+
+/// Reads every sensor in the repository, walking it only when `cache` is still empty.
+pub fn read_sensors(raw: &mut RawFn, cache: &mut Option<Vec<SensorRecord>>) -> io::Result<Vec<SensorResult>> {
+    if cache.is_none() {
+        *cache = Some(read_repository(raw)?);
+    }
+    let records = cache.as_ref().unwrap();
+    let mut results = Vec::with_capacity(records.len());
+    for record in records {
+        match read_sensor(raw, record) {
+            Ok(r) => results.push(r),
+            // 个别传感器不存在时（比如未插第二颗 CPU）跳过即可
+            Err(e) if completion_code(&e).is_some() => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(results)
+}
+
+/// Sets the lower thresholds (lnr, lc, lnc) of the sensor called `name`, skipping the ones the BMC
+/// does not allow to change.
+pub fn set_lower_thresholds(
+    raw: &mut RawFn,
+    cache: &mut Option<Vec<SensorRecord>>,
+    name: &str,
+    lower: [f64; 3],
+) -> io::Result<()> {
+    if cache.is_none() {
+        *cache = Some(read_repository(raw)?);
+    }
+    let Some(record) = cache.as_ref().unwrap().iter().find(|r| r.name == name) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No sensor named {}", name)));
+    };
+    let [lnr, lc, lnc] = lower;
+    // 掩码: bit0 lnc, bit1 lc, bit2 lnr
+    let mut mask = 0;
+    let mut data = [record.number, 0, 0, 0, 0, 0, 0, 0];
+    for (bit, value) in [(0, lnc), (1, lc), (2, lnr)] {
+        if record.settable & (1 << bit) == 0 {
+            continue;
+        }
+        let Some(raw_value) = record.to_raw(value) else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Cannot convert thresholds of non-linear sensor {}", name),
+            ));
+        };
+        mask |= 1 << bit;
+        data[2 + bit] = raw_value;
+    }
+    if mask == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Lower thresholds of {} are not settable", name),
+        ));
+    }
+    data[1] = mask;
+    raw(NETFN_SENSOR, CMD_SET_SENSOR_THRESHOLDS, &data)?;
+    Ok(())
+}
+
+/// Reads one sensor and converts it into the same shape `ipmitool sensor` produces.
+pub fn read_sensor(raw: &mut RawFn, record: &SensorRecord) -> io::Result<SensorResult> {
+    let rsp = raw(NETFN_SENSOR, CMD_GET_SENSOR_READING, &[record.number])?;
+    if rsp.len() < 2 {
+        return Err(short_response("Get Sensor Reading"));
+    }
+    // bit 5: 读数不可用，bit 6: 扫描已启用
+    let available = rsp[1] & 0x20 == 0 && rsp[1] & 0x40 != 0;
+    let (value, status) = if available {
+        let state = rsp.get(2).copied().unwrap_or(0);
+        (Some(record.convert(rsp[0])), Some(threshold_status(state).to_string()))
+    } else {
+        (None, None)
+    };
+    Ok(SensorResult {
+        sensor_name: record.name.clone(),
+        value,
+        unit: record.unit.clone(),
+        status,
+        thresholds: record.thresholds(),
+    })
+}
+
+fn threshold_status(state: u8) -> &'static str {
+    if state & 0x24 != 0 {
+        "nr"
+    } else if state & 0x12 != 0 {
+        "cr"
+    } else if state & 0x09 != 0 {
+        "nc"
+    } else {
+        "ok"
+    }
+}
+
+fn short_response(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} response too short", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::sim::{full_sensor_record, SimBmc};
+
+    #[test]
+    fn test_convert() {
+        let record = SensorRecord::parse(&full_sensor_record(1, 0x10, "FAN1_Speed", 18, 100, 0, 0)).unwrap();
+        assert_eq!(record.unit.as_deref(), Some("RPM"));
+        assert_eq!(record.convert(54), 5400.0);
+
+        // M = 1, B = -5 (10 bit two's complement), Rexp = -1
+        let mut bytes = full_sensor_record(2, 0x11, "P12V", 4, 1, 0, 0);
+        bytes[26] = 0xfb;
+        bytes[27] = 0xc0;
+        bytes[29] = 0xf0;
+        let record = SensorRecord::parse(&bytes).unwrap();
+        assert_eq!(record.convert(125), 12.0);
+        assert_eq!(record.to_raw(12.0), Some(125));
+    }
+
+    #[test]
+    fn test_read_repository_and_sensor() {
+        let mut bmc = SimBmc::default();
+        bmc.add_sensor(full_sensor_record(1, 0x01, "CPU1_Temp", 1, 1, 0, 0), 34);
+        bmc.add_sensor(full_sensor_record(2, 0x02, "FAN1_Speed", 18, 100, 0, 0), 54);
+        let mut raw = |netfn: u8, cmd: u8, data: &[u8]| bmc.handle(netfn, cmd, data);
+
+        let records = read_repository(&mut raw).unwrap();
+        assert_eq!(records.len(), 2);
+        let cpu = read_sensor(&mut raw, &records[0]).unwrap();
+        assert_eq!(cpu.sensor_name, "CPU1_Temp");
+        assert_eq!(cpu.value, Some(34.0));
+        assert_eq!(cpu.unit.as_deref(), Some("degrees C"));
+        assert_eq!(cpu.status.as_deref(), Some("ok"));
+        assert_eq!(cpu.thresholds.uc, Some(100.0));
+        assert_eq!(cpu.thresholds.lnr, None);
+    }
+
+    #[test]
+    fn test_set_lower_thresholds() {
+        let mut bmc = SimBmc::default();
+        let mut fan = full_sensor_record(1, 0x30, "FAN1", 18, 100, 0, 0);
+        fan[19] = 0x3f;
+        bmc.add_sensor(fan, 12);
+        bmc.add_sensor(full_sensor_record(2, 0x31, "FAN2", 18, 100, 0, 0), 12);
+        let mut raw = |netfn: u8, cmd: u8, data: &[u8]| bmc.handle(netfn, cmd, data);
+        let mut cache = None;
+
+        set_lower_thresholds(&mut raw, &mut cache, "FAN1", [100.0, 200.0, 300.0]).unwrap();
+        let err = set_lower_thresholds(&mut raw, &mut cache, "FAN2", [100.0, 200.0, 300.0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(set_lower_thresholds(&mut raw, &mut cache, "FAN3", [0.0; 3]).is_err());
+        assert_eq!(bmc.raw_log(), vec![(NETFN_SENSOR, CMD_SET_SENSOR_THRESHOLDS, vec![0x30, 0x07, 3, 2, 1, 0, 0, 0])]);
+    }
+}
@@ -0,0 +1,331 @@
+//! A stand-in BMC for tests: a command handler with an SDR repository, and a UDP front end
+//! speaking just enough RMCP+ for [`crate::ipmi::lanplus::Session`].
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rand::RngCore;
+
+use crate::ipmi::lanplus::*;
+use crate::ipmi::{check_completion, checksum, NETFN_APP, NETFN_SENSOR, NETFN_STORAGE};
+
+/// Builds a full sensor record for an unsigned, linear threshold sensor.
+/// The raw upper thresholds are UNC 93, UC 100, UNR 105.
+pub(crate) fn full_sensor_record(id: u16, number: u8, name: &str, base_unit: u8, m: i16, b: i16, r_exp: i8) -> Vec<u8> {
+    let mut r = vec![0u8; 48];
+    r[..2].copy_from_slice(&id.to_le_bytes());
+    r[2] = 0x51;
+    r[3] = 0x01;
+    r[4] = (43 + name.len()) as u8;
+    r[5] = 0x20;
+    r[7] = number;
+    r[8] = 0x03;
+    r[9] = 0x01;
+    r[12] = if base_unit == 18 { 0x04 } else { 0x01 };
+    r[13] = 0x01;
+    r[18] = 0x38;
+    r[19] = 0x38;
+    r[21] = base_unit;
+    r[24] = m as u8;
+    r[25] = (((m >> 8) & 0x03) as u8) << 6;
+    r[26] = b as u8;
+    r[27] = (((b >> 8) & 0x03) as u8) << 6;
+    r[29] = ((r_exp as u8) & 0x0f) << 4;
+    r[36] = 105;
+    r[37] = 100;
+    r[38] = 93;
+    r[47] = 0xc0 | name.len() as u8;
+    r.extend(name.as_bytes());
+    r
+}
+
+#[derive(Default)]
+struct State {
+    records: Vec<Vec<u8>>,
+    readings: HashMap<u8, u8>,
+    reservation: u16,
+    raw_log: Vec<(u8, u8, Vec<u8>)>,
+    session_closed: bool,
+    device_id: Option<(u32, u16)>,
+    fru: Option<Vec<u8>>,
+}
+
+/// IPMI command handler shared by the simulated transports. Commands it does not know
+/// are recorded in [`SimBmc::raw_log`] and answered with an empty success response.
+#[derive(Clone, Default)]
+pub(crate) struct SimBmc {
+    state: Arc<Mutex<State>>,
+}
+
+impl SimBmc {
+    pub fn add_sensor(&mut self, record: Vec<u8>, reading: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.readings.insert(record[7], reading);
+        state.records.push(record);
+    }
+
+    pub fn set_device_id(&self, manufacturer_id: u32, product_id: u16) {
+        self.state.lock().unwrap().device_id = Some((manufacturer_id, product_id));
+    }
+
+    pub fn set_fru(&self, fru: Vec<u8>) {
+        self.state.lock().unwrap().fru = Some(fru);
+    }
+
+    pub fn raw_log(&self) -> Vec<(u8, u8, Vec<u8>)> {
+        self.state.lock().unwrap().raw_log.clone()
+    }
+
+    pub fn session_closed(&self) -> bool {
+        self.state.lock().unwrap().session_closed
+    }
+
+    pub fn handle(&self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (cc, data) = self.respond(netfn, cmd, data);
+        check_completion(netfn, cmd, cc)?;
+        Ok(data)
+    }
+
+    /// Returns (completion code, response data).
+    pub fn respond(&self, netfn: u8, cmd: u8, data: &[u8]) -> (u8, Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        match (netfn, cmd) {
+            (NETFN_APP, CMD_GET_CHANNEL_AUTH_CAPABILITIES) => (0, vec![0x01, 0x80, 0x04, 0x02, 0, 0, 0, 0]),
+            (NETFN_APP, CMD_SET_SESSION_PRIVILEGE) => (0, vec![PRIV_ADMIN]),
+            (NETFN_APP, CMD_CLOSE_SESSION) => {
+                state.session_closed = true;
+                (0, vec![])
+            }
+            (NETFN_STORAGE, 0x22) => {
+                state.reservation = state.reservation.wrapping_add(1);
+                (0, state.reservation.to_le_bytes().to_vec())
+            }
+            (NETFN_STORAGE, 0x23) => {
+                if data.len() < 6 || u16::from_le_bytes([data[0], data[1]]) != state.reservation {
+                    return (0xc5, vec![]);
+                }
+                let id = u16::from_le_bytes([data[2], data[3]]);
+                let index = if id == 0 {
+                    Some(0)
+                } else {
+                    state.records.iter().position(|r| u16::from_le_bytes([r[0], r[1]]) == id)
+                };
+                let Some(record) = index.and_then(|i| state.records.get(i)) else {
+                    return (0xcb, vec![]);
+                };
+                let next = match state.records.get(index.unwrap() + 1) {
+                    Some(r) => u16::from_le_bytes([r[0], r[1]]),
+                    None => 0xffff,
+                };
+                let offset = (data[4] as usize).min(record.len());
+                let end = (offset + data[5] as usize).min(record.len());
+                let mut rsp = next.to_le_bytes().to_vec();
+                rsp.extend(&record[offset..end]);
+                (0, rsp)
+            }
+            (NETFN_APP, 0x01) if state.device_id.is_some() => {
+                let (manufacturer_id, product_id) = state.device_id.unwrap();
+                let mut rsp = vec![0x20, 0x01, 0x01, 0x00, 0x02, 0xbf];
+                rsp.extend(&manufacturer_id.to_le_bytes()[..3]);
+                rsp.extend(product_id.to_le_bytes());
+                (0, rsp)
+            }
+            (NETFN_STORAGE, 0x10) => match &state.fru {
+                Some(fru) => {
+                    let mut rsp = (fru.len() as u16).to_le_bytes().to_vec();
+                    rsp.push(0);
+                    (0, rsp)
+                }
+                None => (0xcb, vec![]),
+            },
+            (NETFN_STORAGE, 0x11) if data.len() >= 4 => match &state.fru {
+                Some(fru) => {
+                    let offset = (u16::from_le_bytes([data[1], data[2]]) as usize).min(fru.len());
+                    let end = (offset + data[3] as usize).min(fru.len());
+                    let mut rsp = vec![(end - offset) as u8];
+                    rsp.extend(&fru[offset..end]);
+                    (0, rsp)
+                }
+                None => (0xcb, vec![]),
+            },
+            (NETFN_SENSOR, 0x2d) => match data.first().and_then(|n| state.readings.get(n)) {
+                Some(reading) => (0, vec![*reading, 0x40, 0x00]),
+                None => (0xcb, vec![]),
+            },
+            _ => {
+                state.raw_log.push((netfn, cmd, data.to_vec()));
+                (0, vec![])
+            }
+        }
+    }
+}
+
+fn decode_request(msg: &[u8]) -> Option<(u8, u8, u8, Vec<u8>)> {
+    if msg.len() < 7 || checksum(&msg[..2]) != msg[2] || checksum(&msg[3..msg.len() - 1]) != msg[msg.len() - 1] {
+        return None;
+    }
+    Some((msg[1] >> 2, msg[4] >> 2, msg[5], msg[6..msg.len() - 1].to_vec()))
+}
+
+fn encode_response(netfn: u8, rq_seq: u8, cmd: u8, cc: u8, data: &[u8]) -> Vec<u8> {
+    let mut msg = vec![0x81, (netfn | 1) << 2];
+    msg.push(checksum(&msg));
+    msg.extend([0x20, rq_seq << 2, cmd, cc]);
+    msg.extend(data);
+    msg.push(checksum(&msg[3..]));
+    msg
+}
+
+#[derive(Default)]
+struct LanSession {
+    suite: Option<CipherSuite>,
+    console_id: u32,
+    bmc_id: u32,
+    rm: Vec<u8>,
+    rc: Vec<u8>,
+    role: u8,
+    user: Vec<u8>,
+    keys: Option<SessionKeys>,
+    seq: u32,
+}
+
+/// Serves a [`SimBmc`] over RMCP+ on a random localhost UDP port until dropped.
+pub(crate) struct SimLan {
+    addr: SocketAddr,
+    bmc: SimBmc,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SimLan {
+    pub fn start(bmc: SimBmc, username: &str, password: &str) -> SimLan {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let bmc = bmc.clone();
+            let stop = stop.clone();
+            let username = username.as_bytes().to_vec();
+            let kuid = password_key(password);
+            thread::spawn(move || {
+                let mut session = LanSession::default();
+                let mut buf = [0u8; 1024];
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok((n, peer)) = socket.recv_from(&mut buf) else { continue };
+                    if let Some(rsp) = serve(&bmc, &mut session, &username, &kuid, &buf[..n]) {
+                        let _ = socket.send_to(&rsp, peer);
+                    }
+                }
+            })
+        };
+        SimLan {
+            addr,
+            bmc,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn bmc(&self) -> &SimBmc {
+        &self.bmc
+    }
+}
+
+impl Drop for SimLan {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(bmc: &SimBmc, s: &mut LanSession, username: &[u8], kuid: &[u8], packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() > 14 && packet[4] == AUTH_TYPE_NONE {
+        let (netfn, rq_seq, cmd, data) = decode_request(&packet[14..])?;
+        let (cc, data) = bmc.respond(netfn, cmd, &data);
+        let msg = encode_response(netfn, rq_seq, cmd, cc, &data);
+        let mut rsp = RMCP_HEADER.to_vec();
+        rsp.push(AUTH_TYPE_NONE);
+        rsp.extend([0u8; 8]);
+        rsp.push(msg.len() as u8);
+        rsp.extend(msg);
+        return Some(rsp);
+    }
+
+    let opened = open(s.keys.as_ref(), packet).ok()?;
+    let p = &opened.payload;
+    let mut rng = rand::thread_rng();
+    match opened.payload_type {
+        PAYLOAD_OPEN_SESSION_REQUEST => {
+            s.suite = Some(if p[12] == 0x03 { CipherSuite::Suite17 } else { CipherSuite::Suite3 });
+            s.console_id = u32::from_le_bytes(p[4..8].try_into().unwrap());
+            s.bmc_id = rng.next_u32() | 1;
+            s.keys = None;
+            let mut rsp = vec![p[0], 0, PRIV_ADMIN, 0];
+            rsp.extend(s.console_id.to_le_bytes());
+            rsp.extend(s.bmc_id.to_le_bytes());
+            rsp.extend(&p[8..32]);
+            Some(seal(None, PAYLOAD_OPEN_SESSION_RESPONSE, 0, 0, &rsp))
+        }
+        PAYLOAD_RAKP1 => {
+            let suite = s.suite?;
+            s.rm = p[8..24].to_vec();
+            s.role = p[24];
+            s.user = p[28..28 + p[27] as usize].to_vec();
+            if s.user != username {
+                return Some(seal(None, PAYLOAD_RAKP2, 0, 0, &[p[0], 0x0d, 0, 0]));
+            }
+            s.rc = vec![0u8; 16];
+            rng.fill_bytes(&mut s.rc);
+            let guid = [0x5a; 16];
+            let ulen = [s.user.len() as u8];
+            let auth = suite.hmac(
+                kuid,
+                &[&s.console_id.to_le_bytes(), &s.bmc_id.to_le_bytes(), &s.rm, &s.rc, &guid, &[s.role], &ulen, &s.user],
+            );
+            let mut rsp = vec![p[0], 0, 0, 0];
+            rsp.extend(s.console_id.to_le_bytes());
+            rsp.extend(&s.rc);
+            rsp.extend(guid);
+            rsp.extend(auth);
+            Some(seal(None, PAYLOAD_RAKP2, 0, 0, &rsp))
+        }
+        PAYLOAD_RAKP3 => {
+            let suite = s.suite?;
+            let ulen = [s.user.len() as u8];
+            let expected = suite.hmac(kuid, &[&s.rc, &s.console_id.to_le_bytes(), &[s.role], &ulen, &s.user]);
+            if p[8..] != expected[..] {
+                return Some(seal(None, PAYLOAD_RAKP4, 0, 0, &[p[0], 0x0f, 0, 0]));
+            }
+            let sik = suite.hmac(kuid, &[&s.rm, &s.rc, &[s.role], &ulen, &s.user]);
+            let mut icv = suite.hmac(&sik, &[&s.rm, &s.bmc_id.to_le_bytes(), &[0x5a; 16]]);
+            icv.truncate(suite.integrity_len());
+            s.keys = Some(SessionKeys::derive(suite, &sik));
+            let mut rsp = vec![p[0], 0, 0, 0];
+            rsp.extend(s.console_id.to_le_bytes());
+            rsp.extend(icv);
+            Some(seal(None, PAYLOAD_RAKP4, 0, 0, &rsp))
+        }
+        PAYLOAD_IPMI if opened.session_id == s.bmc_id => {
+            let keys = s.keys.clone()?;
+            let (netfn, rq_seq, cmd, data) = decode_request(p)?;
+            let (cc, data) = bmc.respond(netfn, cmd, &data);
+            s.seq += 1;
+            let msg = encode_response(netfn, rq_seq, cmd, cc, &data);
+            Some(seal(Some(&keys), PAYLOAD_IPMI, s.console_id, s.seq, &msg))
+        }
+        _ => None,
+    }
+}
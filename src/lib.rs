@@ -1,19 +1,38 @@
 use std::fs::File;
-use std::io::Read;
-use std::thread;
+use std::io::{self, Read};
 use std::time::Duration;
 use chrono::Local;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{self, Receiver, Sender};
 use derive_more::Display;
 use log::Level;
 
+pub mod alarm;
+pub mod cli;
 pub mod config;
 pub mod constants;
+pub mod control;
+pub mod controller;
+pub mod curve;
+pub mod daemon;
+pub mod datalog;
+pub mod fan_check;
+pub mod history;
+pub mod ipmi;
+pub mod metrics;
+pub mod mqtt;
+pub mod notify;
+pub mod profile;
+pub mod redfish;
+pub mod safety;
 pub mod sensor;
+pub mod sink;
 pub mod tui;
 pub mod sensor_result;
+#[cfg(test)]
+mod testutil;
 
 pub use constants::*;
+use ipmi::IpmiTransport;
 
 #[derive(Debug, Display)]
 pub enum Message {
@@ -27,6 +46,20 @@ pub enum Message {
     GotCpuAndFansSpeed(String, (usize, usize), Vec<(String, f64)>),   // cpu, all fans
     #[display("Ipmi: power: {}", _1.len())]
     Power(String, Vec<(String, f64)>),   // 电耗
+    #[display("Ipmi: model: {}", _1)]
+    ServerModel(String, String),   // BMC 报告的服务器型号
+    #[display("Ipmi: zones: {}", _1.len())]
+    ZoneFans(String, Vec<sensor::ZoneFans>),   // 按 zone 分组的风扇：zone、转速%、各风扇转速
+    #[display("Safety: {}", _1)]
+    Safety(String, safety::SafetyEvent),   // 安全策略状态变化
+    #[display("Alarms: {}", _1.len())]
+    Alarms(String, Vec<alarm::Alarm>),   // 每个传感器的告警级别
+    #[display("Fans: failed: {}", _1.len())]
+    FanFaults(String, Vec<fan_check::FanFault>),   // 当前故障的风扇
+    #[display("Pid: setpoint: {}", _1)]
+    Setpoint(String, f64),   // PID 目标温度
+    #[display("History: {}", _0.len())]
+    History(Vec<(String, f64, f64)>),   // 启动时从数据库加载的时间、温度、转速
 }
 
 impl Message {
@@ -39,85 +72,585 @@ impl Message {
 
 #[derive(Debug, Display)]
 pub enum UIMessage {
-    RestartLoop,
+    #[display("{}", _0)]
+    Control(control::Request),   // 与控制 socket 相同的命令
+}
+
+/// What the loop keeps between polls, besides the fans themselves.
+pub struct LoopState {
+    pub controller: controller::Controller,
+    pub safety: safety::Safety,
+    pub fan_check: fan_check::FanCheck,
+    /// 暂停时只读取传感器，风扇由 BMC 控制
+    pub paused: bool,
+    /// 通过控制 socket 设置的转速
+    pub manual: Option<control::Manual>,
+}
+
+impl LoopState {
+    pub fn new(config: &config::Config, profile: &profile::Profile) -> LoopState {
+        LoopState {
+            controller: controller::Controller::from_config(config, profile),
+            safety: safety::Safety::from_config(config),
+            fan_check: fan_check::FanCheck::from_config(config),
+            paused: false,
+            manual: None,
+        }
+    }
+
+    /// What decides the fan speed right now.
+    pub fn mode(&self, config: &config::Config) -> &'static str {
+        match (self.paused, self.manual, self.safety.engaged(), config.controller) {
+            (true, ..) => "paused",
+            (_, Some(_), false, _) => "manual",
+            (_, _, true, _) => "fail-safe",
+            (.., config::ControllerMode::Curve) => "curve",
+            (.., config::ControllerMode::Pid) => "pid",
+        }
+    }
 }
 
-pub fn load_config(config_path: &str) -> config::Config {
-    let mut file = File::open(config_path).expect("Failed to open config file");
+/// config.yaml in the working directory.
+pub fn config_path() -> String {
+    format!("{}/config.yaml", std::env::current_dir().unwrap().display())
+}
+
+/// Reads and validates config.yaml.
+pub fn load_config(config_path: &str) -> io::Result<config::Config> {
+    let mut file = File::open(config_path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Failed to read config file");
+    file.read_to_string(&mut contents)?;
 
-    let config: config::Config =
-        serde_yaml::from_str(&contents).expect("Failed to parse config file");
-    config
+    let config: config::Config = serde_yaml::from_str(&contents).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Failed to parse {}: {}", config_path, e))
+    })?;
+    config.validate().map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", config_path, e))
+    })?;
+    Ok(config)
+}
+
+/// Sends a message to the UI. Once the UI has quit the channel is closed and the message is
+/// dropped; the loop sees that after the poll and stops, handing the fans back.
+async fn to_ui(send_to_ui: &Sender<Message>, msg: Message) {
+    if send_to_ui.send(msg).await.is_err() {
+        log::debug!("UI is gone, message dropped");
+    }
 }
 
-pub async fn init_loop(send_to_ui: Sender<Message>, receive_from_ui: Receiver<UIMessage>) {
-    let mut in_band = false;
+/// Loads config.yaml from the working directory, connects, picks the profile and runs the loop.
+/// Returns an error when any of that fails; the error has been sent to the UI as well.
+pub async fn init_loop(send_to_ui: Sender<Message>, receive_from_ui: Receiver<UIMessage>) -> io::Result<()> {
+    let config_path = config_path();
+    if std::fs::metadata(config_path.clone()).is_err() {
+        let msg = format!("{} not exists.", config_path);
+        to_ui(&send_to_ui, Message::build_log(Level::Error, msg.clone())).await;
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+            return Err(e);
+        }
+    };
+    let mut transport = match ipmi::connect(&config) {
+        Ok(transport) => transport,
+        Err(e) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+            return Err(e);
+        }
+    };
+    let identity = match transport.identify() {
+        Ok(identity) => {
+            let time_str = Local::now().format("%H:%M:%S").to_string();
+            to_ui(&send_to_ui, Message::build_log(Level::Info, format!("Detected server: {}", identity))).await;
+            to_ui(&send_to_ui, Message::ServerModel(time_str, identity.to_string())).await;
+            Some(identity)
+        }
+        Err(e) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Warn, format!("Failed to detect server model: {}", e))).await;
+            None
+        }
+    };
+    let profile = match profile::select(&config, identity.as_ref()) {
+        Ok(profile) => profile,
+        Err(e) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+            return Err(e);
+        }
+    };
+    to_ui(&send_to_ui, Message::build_log(Level::Info, format!("Using fan profile {}", profile.name))).await;
+
+    run_loop(config, &config_path, identity, transport, profile::FanControl::new(profile), send_to_ui, receive_from_ui).await;
+    Ok(())
+}
+
+/// Polls the BMC every 15 seconds through any transport, until the UI goes away, Ctrl-C or SIGTERM.
+/// Between polls it answers the UI and the control socket.
+/// The fans are handed back to the BMC before returning, also when the loop panics.
+pub async fn run_loop(
+    mut config: config::Config,
+    config_path: &str,
+    identity: Option<ipmi::identify::ServerIdentity>,
+    transport: Box<dyn IpmiTransport>,
+    fan_control: profile::FanControl,
+    send_to_ui: Sender<Message>,
+    mut receive_from_ui: Receiver<UIMessage>,
+) {
+    let mut shutdown = Shutdown::new();
+    let mut state = LoopState::new(&config, fan_control.profile());
+    let metrics = match &config.metrics {
+        Some(metrics_config) => {
+            let metrics = metrics::SharedMetrics::default();
+            match metrics::serve(&metrics_config.listen, metrics.clone()) {
+                Ok(()) => {
+                    to_ui(&send_to_ui, Message::build_log(Level::Info, format!("Metrics at http://{}/metrics", metrics_config.listen))).await;
+                    Some(metrics)
+                }
+                Err(e) => {
+                    to_ui(&send_to_ui, Message::build_log(Level::Warn, format!("Metrics unavailable on {}: {}", metrics_config.listen, e))).await;
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    // 统计每次 BMC 调用的耗时和错误
+    let transport: Box<dyn IpmiTransport> = match &metrics {
+        Some(metrics) => Box::new(metrics::Instrumented::new(transport, metrics.clone())),
+        None => transport,
+    };
+    let mut fans = safety::AutoRestore { transport, fan_control };
+    let mut history = match config.history.enabled {
+        true => open_history(&config.history, &send_to_ui).await,
+        false => None,
+    };
+    let mut data_log = match &config.data_log {
+        Some(data_log_config) => match datalog::DataLog::open(data_log_config) {
+            Ok(data_log) => Some(data_log),
+            Err(e) => {
+                to_ui(&send_to_ui, Message::build_log(Level::Warn, format!("Data log unavailable: {}", e))).await;
+                None
+            }
+        },
+        None => None,
+    };
+    let sinks = match config.sinks.is_empty() {
+        true => None,
+        false => Some(sink::spawn(&config.sinks, &config.ipmi.host, send_to_ui.clone())),
+    };
+    if let Some(setpoint) = state.controller.setpoint() {
+        let time_str = Local::now().format("%H:%M:%S").to_string();
+        to_ui(&send_to_ui, Message::Setpoint(time_str, setpoint)).await;
+    }
+    // 保留发送端，socket 不可用时 recv 不会立即返回
+    let (calls_tx, mut calls) = mpsc::channel::<control::Call>(8);
+    let socket_path = config.control_socket.clone().unwrap_or_else(|| control::DEFAULT_SOCKET.to_string());
+    let _server = match control::serve(&socket_path, calls_tx.clone()) {
+        Ok(server) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Info, format!("Control socket at {}", socket_path))).await;
+            Some(server)
+        }
+        Err(e) => {
+            to_ui(&send_to_ui, Message::build_log(Level::Warn, format!("Control socket unavailable: {}", e))).await;
+            None
+        }
+    };
+    let mut mqtt = config.mqtt.as_ref().map(|mqtt_config| mqtt::Mqtt::start(mqtt_config, &config.ipmi.host, calls_tx.clone(), send_to_ui.clone()));
+    let mut notifier = notify::Notifier::from_env();
+    let mut last = None;
+    'poll: loop {
+        let reading = poll(&config, fans.transport.as_mut(), &mut fans.fan_control, &mut state, &send_to_ui).await;
+        if send_to_ui.is_closed() {
+            break 'poll;
+        }
+        match &reading {
+            Some(control::Reading { temperature, speed: Some(speed), .. }) => notifier.alive(*temperature, *speed),
+            Some(reading) => notifier.paused(reading.temperature),
+            None => notifier.failed("sensor read or fan control failed"),
+        }
+        if let Some(metrics) = &metrics {
+            let mode = state.mode(&config);
+            metrics.lock().unwrap().record_iteration(reading.as_ref(), &fans.fan_control, mode, state.controller.setpoint());
+        }
+        if let (Some(history), Some(reading)) = (&mut history, &reading) {
+            let now = Local::now().timestamp();
+            let result = history
+                .record(now, reading, &fans.fan_control.duties(), state.mode(&config))
+                .and_then(|()| history.maintain_if_due(now));
+            if let Err(e) = result {
+                to_ui(&send_to_ui, Message::build_log(Level::Warn, e.to_string())).await;
+            }
+        }
+        if let (Some(data_log), Some(reading)) = (&mut data_log, &reading) {
+            let record = datalog::Record::new(Local::now(), reading, &fans.fan_control.duties());
+            if let Err(e) = data_log.append(&record) {
+                to_ui(&send_to_ui, Message::build_log(Level::Warn, format!("Data log: {}", e))).await;
+            }
+        }
+        if let (Some(sinks), Some(reading)) = (&sinks, &reading) {
+            let points = sink::points(Local::now().timestamp(), reading, &fans.fan_control.duties(), state.controller.setpoint());
+            let _ = sinks.send(points);
+        }
+        if let (Some(mqtt), Some(reading)) = (&mut mqtt, &reading) {
+            mqtt.publish(reading, &fans.fan_control.duties(), state.mode(&config));
+        }
+        if reading.is_some() {
+            last = reading;
+        }
 
-    let config_path = format!("{}/config.yaml", std::env::current_dir().unwrap().display());
-    if !std::fs::metadata(config_path.clone()).is_ok() {
-        send_to_ui.send(Message::build_log(Level::Error, format!("{} not exists.", config_path))).await.expect("send message to ui successfully");
-        return;
+        // tokio async
+        let next_poll = tokio::time::Instant::now() + Duration::from_millis(15000);
+        loop {
+            let (request, reply) = tokio::select! {
+                _ = tokio::time::sleep_until(next_poll) => break,
+                msg = receive_from_ui.recv() => match msg {
+                    Some(UIMessage::Control(request)) => (request, None),
+                    None => break 'poll,
+                },
+                Some((request, reply)) = calls.recv() => (request, Some(reply)),
+                _ = shutdown.recv() => break 'poll,
+            };
+            let (response, poll_now) = control(&request, &mut config, config_path, identity.as_ref(), &mut fans, &mut state, last.as_ref());
+            if request != control::Request::Status {
+                let level = if response.ok { Level::Info } else { Level::Warn };
+                let msg = format!("Control: {}: {}", request, response.message.as_deref().unwrap_or_default());
+                to_ui(&send_to_ui, Message::build_log(level, msg)).await;
+                if let (true, Some(setpoint)) = (response.ok, state.controller.setpoint()) {
+                    let time_str = Local::now().format("%H:%M:%S").to_string();
+                    to_ui(&send_to_ui, Message::Setpoint(time_str, setpoint)).await;
+                }
+            }
+            if let Some(reply) = reply {
+                let _ = reply.send(response);
+            }
+            if poll_now {
+                break;
+            }
+        }
+    }
+    notifier.stopping();
+    if let Some(mqtt) = mqtt {
+        mqtt.stop().await;
     }
-    let config: config::Config = load_config(&config_path);
-    if config.mode.to_lowercase() == "in-band" {
-        in_band = true;
-    } else {
-        if config.ipmi.username.is_empty() || config.ipmi.password.is_empty() || config.ipmi.host.is_empty() {
-            send_to_ui.send(Message::build_log(Level::Error, "必须配置用户名、密码和BMC主机地址，才能使用out-band模式/username, password and host must be set to use out-band mode.".to_string())).await.expect("send message to ui successfully");
-            return;
+    // fans 在这里释放，交还给 BMC
+}
+
+/// Opens the history database, tidies it up and sends the last `chart_hours` to the chart.
+async fn open_history(history_config: &config::HistoryConfig, send_to_ui: &Sender<Message>) -> Option<history::History> {
+    let now = Local::now().timestamp();
+    let result = history::History::open(history_config).and_then(|mut history| {
+        history.maintain_if_due(now)?;
+        let since = now - history_config.chart_hours as i64 * 3600;
+        let points = match history_config.chart_hours {
+            0 => vec![],
+            _ => history.chart(since, now, history::CHART_POINTS)?,
+        };
+        Ok((history, points))
+    });
+    match result {
+        Ok((history, points)) => {
+            if !points.is_empty() {
+                to_ui(send_to_ui, Message::History(points)).await;
+            }
+            Some(history)
+        }
+        Err(e) => {
+            to_ui(send_to_ui, Message::build_log(Level::Warn, format!("History unavailable: {}", e))).await;
+            None
         }
     }
+}
 
-    let ipmi_tool_cmd = if in_band {
-        "ipmitool".to_string()
-    } else {
-        format!(
-            "ipmitool -I lanplus -H {} -U {} -P '{}'",
-            config.ipmi.host, config.ipmi.username, config.ipmi.password
-        )
+/// Carries out one control request. Returns the answer, and whether to poll right away
+/// because the fans should change. Connection settings are not reloaded.
+fn control(
+    request: &control::Request,
+    config: &mut config::Config,
+    config_path: &str,
+    identity: Option<&ipmi::identify::ServerIdentity>,
+    fans: &mut safety::AutoRestore,
+    state: &mut LoopState,
+    last: Option<&control::Reading>,
+) -> (control::Response, bool) {
+    use control::{Request, Response};
+
+    let result = match request {
+        Request::Status => {
+            let now = std::time::Instant::now();
+            return (
+                Response::status(control::Status {
+                    profile: fans.fan_control.profile().name.clone(),
+                    server_model: identity.map(|id| id.to_string()),
+                    paused: state.paused,
+                    manual: state.manual.map(|m| (m.speed, m.remaining(now).as_secs())),
+                    setpoint: state.controller.setpoint(),
+                    fail_safe: state.safety.fault().map(|f| f.to_string()),
+                    reading: last.cloned(),
+                }),
+                false,
+            );
+        }
+        Request::Override { speed, minutes } => {
+            match control::Manual::new(*speed, *minutes, std::time::Instant::now()) {
+                Some(manual) if *speed <= 100 && (1..=control::MAX_OVERRIDE_MINUTES).contains(minutes) => {
+                    state.manual = Some(manual);
+                    state.paused = false;
+                    Ok(format!("fans at {}% for {} min", speed, minutes))
+                }
+                _ => Err(format!(
+                    "override needs a speed up to 100 and 1 to {} minutes, got {}% for {} min",
+                    control::MAX_OVERRIDE_MINUTES,
+                    speed,
+                    minutes
+                )),
+            }
+        }
+        // 没有交还命令时暂停会让风扇停在最后的转速
+        Request::Pause if !fans.fan_control.can_restore_auto() => Err(format!(
+            "{} 没有交还 BMC 的命令，不能暂停/profile {} has no auto mode command, cannot pause; use override instead",
+            fans.fan_control.profile().name,
+            fans.fan_control.profile().name
+        )),
+        Request::Pause => match fans.fan_control.restore_auto(fans.transport.as_mut()) {
+            Ok(()) => {
+                state.paused = true;
+                state.manual = None;
+                return (Response::ok("paused, the BMC controls the fans".to_string()), false);
+            }
+            Err(e) => Err(e.to_string()),
+        },
+        Request::Resume => {
+            state.paused = false;
+            state.manual = None;
+            Ok("automatic control".to_string())
+        }
+        Request::Profile { name } => profile::select_named(config, Some(name), identity)
+            .and_then(|profile| switch_profile(config, fans, state, profile))
+            .map(|()| format!("using profile {}", fans.fan_control.profile().name))
+            .map_err(|e| e.to_string()),
+        Request::Reload => load_config(config_path)
+            .and_then(|new| {
+                let profile = profile::select(&new, identity)?;
+                switch_profile(&new, fans, state, profile)?;
+                state.safety = safety::Safety::from_config(&new);
+                *config = new;
+                Ok(format!("reloaded {}, using profile {}", config_path, fans.fan_control.profile().name))
+            })
+            .map_err(|e| e.to_string()),
     };
+    match result {
+        Ok(msg) => (Response::ok(msg), true),
+        Err(msg) => (Response::error(msg), false),
+    }
+}
 
-    let mut cpu2_fan_speed_set = false;
-
-    loop {
-        match sensor::get_all_sensor_data(&ipmi_tool_cmd) {
-            Ok(sensor_data) => {
-                let now = Local::now();
-                let time_str = now.format("%H:%M:%S").to_string();
-                let (active_cpu_nums, max) = sensor::get_active_cpu_num(&sensor_data);
-                let max_temperature = sensor::get_max_temperature(&sensor_data);
-                let speed = sensor::get_fan_speed(max_temperature, &config.fan_speeds);
-                let all_fans_speed = sensor::get_fans_speed(&sensor_data);
-                // 风扇
-                let fan_speed_str = all_fans_speed.iter()
-                    .map(|(name, speed)| format!("{}: {}", name, speed))
-                    .collect::<Vec<_>>()
-                    .join(", ");;
-
-                send_to_ui.send(Message::build_log(Level::Info, format!("GotCpuAndFansSpeed, active cpu num: {}, max sockets num: {}, fans: {}", active_cpu_nums, max, fan_speed_str))).await.expect("send message to ui successfully");
-                send_to_ui.send(Message::GotCpuAndFansSpeed(time_str.clone(), (active_cpu_nums, max), all_fans_speed)).await.expect("send message to ui successfully");
-                match sensor::set_fan_speed(speed, &ipmi_tool_cmd, active_cpu_nums, &mut cpu2_fan_speed_set) {
+/// Hands the fans of the old profile back to the BMC and starts over with `profile`.
+/// Without an auto mode command the fans stay as they are until the new profile sets them,
+/// right after the switch.
+fn switch_profile(
+    config: &config::Config,
+    fans: &mut safety::AutoRestore,
+    state: &mut LoopState,
+    profile: profile::Profile,
+) -> io::Result<()> {
+    if fans.fan_control.can_restore_auto() {
+        fans.fan_control.restore_auto(fans.transport.as_mut())?;
+    }
+    fans.fan_control = profile::FanControl::new(profile);
+    state.controller = controller::Controller::from_config(config, fans.fan_control.profile());
+    state.fan_check = fan_check::FanCheck::from_config(config);
+    Ok(())
+}
+
+/// SIGINT and SIGTERM, registered once for the whole loop: a signal that arrives while a poll
+/// or a control request is running is kept until the loop waits again.
+struct Shutdown {
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+    #[cfg(unix)]
+    interrupt: Option<tokio::signal::unix::Signal>,
+    #[cfg(not(unix))]
+    ctrl_c: std::pin::Pin<Box<dyn std::future::Future<Output = io::Result<()>> + Send>>,
+}
+
+impl Shutdown {
+    fn new() -> Shutdown {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let listen = |kind: SignalKind, name: &str| {
+                signal(kind).inspect_err(|e| log::warn!("Cannot listen for {}: {}", name, e)).ok()
+            };
+            Shutdown {
+                terminate: listen(SignalKind::terminate(), "SIGTERM"),
+                interrupt: listen(SignalKind::interrupt(), "SIGINT"),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Shutdown {
+                ctrl_c: Box::pin(tokio::signal::ctrl_c()),
+            }
+        }
+    }
+
+    /// Resolves once either signal has arrived since `new`.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        {
+            async fn recv(signal: &mut Option<tokio::signal::unix::Signal>) {
+                match signal {
+                    Some(signal) => {
+                        signal.recv().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            }
+            tokio::select! {
+                _ = recv(&mut self.terminate) => {}
+                _ = recv(&mut self.interrupt) => {}
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (&mut self.ctrl_c).await;
+        }
+    }
+}
+
+/// One loop iteration: read the sensors, set the fan speed and report both to the UI.
+/// Returns what was read and set when both steps worked; while paused nothing is set.
+/// While the safety policy is engaged every fan runs at 100%, zones with a failed fan are raised.
+/// Alarms, failed fans and the fail-safe also raise a manual override.
+pub async fn poll(
+    config: &config::Config,
+    transport: &mut dyn IpmiTransport,
+    fan_control: &mut profile::FanControl,
+    state: &mut LoopState,
+    send_to_ui: &Sender<Message>,
+) -> Option<control::Reading> {
+    // 界面已经退出，不再读写 BMC
+    if send_to_ui.is_closed() {
+        return None;
+    }
+    match sensor::get_all_sensor_data(transport) {
+        Ok(sensor_data) => {
+            let now = Local::now();
+            let time_str = now.format("%H:%M:%S").to_string();
+            if let Some(event) = state.safety.check(&sensor_data) {
+                to_ui(send_to_ui, Message::Safety(time_str.clone(), event)).await;
+            }
+            let (active_cpu_nums, max) = sensor::get_active_cpu_num(&sensor_data);
+            let max_temperature = sensor::get_max_temperature(&sensor_data);
+            let now = std::time::Instant::now();
+            let mut speed = sensor::get_main_speed(config, &sensor_data, max_temperature, &mut state.controller, now);
+            let mut zone_speeds = sensor::get_zone_speeds(&sensor_data, max_temperature, config, fan_control, &mut state.controller, now);
+            if let Some(manual) = state.manual {
+                if manual.remaining(now).is_zero() {
+                    state.manual = None;
+                    to_ui(send_to_ui, Message::build_log(Level::Info, "Manual override expired, back to automatic control".to_string())).await;
+                } else {
+                    speed = manual.speed;
+                    zone_speeds.clear();
+                }
+            }
+            let alarms = alarm::evaluate_all(&sensor_data);
+            if let Some(min) = alarm::override_speed(&alarms, &config.alarm_speeds) {
+                speed = speed.max(min);
+                zone_speeds.iter_mut().for_each(|(_, s)| *s = (*s).max(min));
+            }
+            to_ui(send_to_ui, Message::Alarms(time_str.clone(), alarms.clone())).await;
+            // 必须在设置新转速之前检查，读数对应的是上一次设置的转速
+            for event in state.fan_check.check(&sensor_data, fan_control) {
+                let level = match event {
+                    fan_check::FanEvent::Failed(_) => Level::Error,
+                    fan_check::FanEvent::Recovered(_) => Level::Info,
+                };
+                to_ui(send_to_ui, Message::build_log(level, event.to_string())).await;
+            }
+            state.fan_check.boost(&mut speed, &mut zone_speeds);
+            let fan_faults = state.fan_check.faults().to_vec();
+            to_ui(send_to_ui, Message::FanFaults(time_str.clone(), fan_faults.clone())).await;
+            // 全速时也包括缺失 CPU 的 zone，不再用 idle_duty
+            let mut cpu_num = active_cpu_nums;
+            if state.safety.engaged() {
+                speed = 100;
+                zone_speeds.clear();
+                cpu_num = 0;
+            }
+            let all_fans_speed = sensor::get_fans_speed(&sensor_data);
+            // 风扇
+            let fan_speed_str = all_fans_speed.iter()
+                .map(|(name, speed)| format!("{}: {}", name, speed))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            to_ui(send_to_ui, Message::build_log(Level::Info, format!("GotCpuAndFansSpeed, active cpu num: {}, max sockets num: {}, fans: {}", active_cpu_nums, max, fan_speed_str))).await;
+            to_ui(send_to_ui, Message::GotCpuAndFansSpeed(time_str.clone(), (active_cpu_nums, max), all_fans_speed.clone())).await;
+            let zones = sensor::get_zone_fans(&sensor_data, fan_control);
+            let result = if state.paused {
+                to_ui(send_to_ui, Message::build_log(Level::Info, format!("Paused, temp: {}℃, the BMC controls the fans", max_temperature))).await;
+                Some(None)
+            } else {
+                match fan_control.apply_fan_thresholds(transport, &sensor_data) {
+                    Ok(fans) if !fans.is_empty() => {
+                        to_ui(send_to_ui, Message::build_log(Level::Info, format!("Lowered fan thresholds of {}", fans.join(", ")))).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        to_ui(send_to_ui, Message::build_log(Level::Warn, format!("Failed to lower fan thresholds: {}", e))).await;
+                    }
+                }
+                match sensor::set_fan_speed(speed, &zone_speeds, transport, cpu_num, fan_control) {
                     Ok(()) => {
-                        send_to_ui.send(Message::build_log(Level::Info, format!("SetFanSpeed, temp: {}℃, speed: {}%", max_temperature, speed))).await.expect("send message to ui successfully");
-                        send_to_ui.send(Message::SetFanSpeed(time_str.clone(), max_temperature, speed)).await.expect("send message to ui successfully");
+                        let zone_str = zone_speeds.iter()
+                            .map(|(zone, speed)| format!(", zone {}: {}%", zone, speed))
+                            .collect::<String>();
+                        to_ui(send_to_ui, Message::build_log(Level::Info, format!("SetFanSpeed, temp: {}℃, speed: {}%{}", max_temperature, speed, zone_str))).await;
+                        to_ui(send_to_ui, Message::SetFanSpeed(time_str.clone(), max_temperature, speed)).await;
+                        to_ui(send_to_ui, Message::ZoneFans(time_str.clone(), zones.clone())).await;
+                        Some(Some(speed))
                     }
                     Err(e) => {
-                        send_to_ui.send(Message::build_log(Level::Error, e.to_string())).await.expect("send message to ui successfully");
+                        to_ui(send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+                        None
                     }
                 }
-                // 电耗
-                let powers = sensor::get_power(&sensor_data);
-                send_to_ui.send(Message::build_log(Level::Info, format!("Power data got, length is {}", powers.len()))).await.expect("send message to ui successfully");
-                send_to_ui.send(Message::Power(time_str.clone(), powers)).await.expect("send message to ui successfully");
+            };
+            // 电耗
+            let powers = sensor::get_power(&sensor_data);
+            to_ui(send_to_ui, Message::build_log(Level::Info, format!("Power data got, length is {}", powers.len()))).await;
+            to_ui(send_to_ui, Message::Power(time_str.clone(), powers.clone())).await;
+            result.map(|speed| control::Reading {
+                time: time_str,
+                temperature: max_temperature,
+                speed,
+                zone_speeds,
+                cpus: (active_cpu_nums, max),
+                fans: all_fans_speed,
+                zones,
+                power: powers,
+                alarms,
+                fan_faults,
+                sensors: sensor_data,
+            })
+        }
+        Err(e) => {
+            to_ui(send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+            if let Some(event) = state.safety.read_failed() {
+                let time_str = Local::now().format("%H:%M:%S").to_string();
+                to_ui(send_to_ui, Message::Safety(time_str, event)).await;
             }
-            Err(e) => send_to_ui.send(Message::build_log(Level::Error, e.to_string())).await.expect("send message to ui successfully"),
+            // 暂停时由 BMC 自己负责
+            if state.safety.engaged() && !state.paused {
+                if let Err(e) = fan_control.set_speed(transport, 100, 0) {
+                    to_ui(send_to_ui, Message::build_log(Level::Error, e.to_string())).await;
+                }
+            }
+            None
         }
-
-        // tokio async
-        tokio::time::sleep(Duration::from_millis(15000)).await;
     }
 }
 
@@ -132,7 +665,254 @@ mod tests {
             std::env::current_dir().unwrap().display(),
             "scripts"
         );
-        let config = load_config(&config_path);
+        let config = load_config(&config_path).unwrap();
         assert_eq!(config.ipmi.username, "changeme");
+        // 示例配置没有开启历史，不应在工作目录里建数据库
+        assert!(!config.history.enabled);
+
+        let err = load_config("does-not-exist.yaml").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_poll_with_mock_transport() {
+        let config: config::Config = serde_yaml::from_str(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {host: bmc, username: admin, password: admin}
+fan_speeds:
+  - temp_range: [0, 50]
+    speed: 20
+  - temp_range: [50, 60]
+    speed: 25
+",
+        )
+        .unwrap();
+        let mock = ipmi::mock::MockTransport::new();
+        mock.push_sensors(testutil::sensors(&[
+            "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "CPU2_Temp | 0.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "FAN1_Speed | 5400.000 | RPM | ok | na | na | na | na | na | na",
+        ]));
+        let mut transport = mock.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fan_control = profile::FanControl::new(hr650x);
+        let mut state = LoopState::new(&config, fan_control.profile());
+
+        poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await;
+        drop(tx);
+
+        let mut set = None;
+        while let Some(msg) = rx.recv().await {
+            if let Message::SetFanSpeed(_, temp, speed) = msg {
+                set = Some((temp, speed));
+            }
+        }
+        assert_eq!(set, Some((52.0, 25)));
+        assert_eq!(mock.requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_poll_fail_safe() {
+        let config: config::Config = serde_yaml::from_str(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {host: bmc, username: admin, password: admin}
+safety: {max_read_failures: 2}
+curve: [[40, 20], [80, 60]]
+",
+        )
+        .unwrap();
+        let mock = ipmi::mock::MockTransport::new();
+        mock.push_sensors_error("timeout");
+        mock.push_sensors_error("timeout");
+        mock.push_sensors(testutil::sensors(&["CPU1_Temp | 60.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000"]));
+        let mut transport = mock.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fan_control = profile::FanControl::new(hr650x);
+        let mut state = LoopState::new(&config, fan_control.profile());
+
+        for _ in 0..3 {
+            poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await;
+        }
+        drop(tx);
+
+        let mut events = vec![];
+        while let Some(msg) = rx.recv().await {
+            if let Message::Safety(_, event) = msg {
+                events.push(event);
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                safety::SafetyEvent::Engaged(safety::Fault::ReadFailures(2)),
+                safety::SafetyEvent::Released(safety::Fault::ReadFailures(2)),
+            ]
+        );
+        // 第二次失败后全速，恢复读数后按曲线
+        assert_eq!(mock.requests()[0], (0x2e, 0x30, vec![0x00, 0, 100]));
+        assert!(mock.requests().contains(&(0x2e, 0x30, vec![0x00, 1, 40])));
+    }
+
+    /// 界面退出后 poll 不再读写 BMC，也不会因为发送失败而 panic
+    #[tokio::test]
+    async fn test_poll_after_ui_quit() {
+        let config: config::Config = serde_yaml::from_str(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {host: bmc, username: admin, password: admin}
+curve: [[40, 20], [80, 60]]
+",
+        )
+        .unwrap();
+        let mock = ipmi::mock::MockTransport::new();
+        let mut transport = mock.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        drop(rx);
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fan_control = profile::FanControl::new(hr650x);
+        let mut state = LoopState::new(&config, fan_control.profile());
+
+        assert!(poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await.is_none());
+        assert_eq!(mock.sensor_reads(), 0);
+        assert!(mock.requests().is_empty());
+        to_ui(&tx, Message::build_log(Level::Info, "dropped".to_string())).await;
+    }
+
+    /// 只有一个 CPU 时全速保护也要把 CPU2 的 idle zone 调到 100%，解除后回到 idle_duty
+    #[tokio::test]
+    async fn test_fail_safe_with_idle_zones() {
+        let config: config::Config = serde_yaml::from_str(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {host: bmc, username: admin, password: admin}
+curve: [[40, 20], [80, 60]]
+",
+        )
+        .unwrap();
+        let reading = |temp: &str| {
+            testutil::sensors(&[
+                format!("CPU1_Temp | {} | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000", temp),
+                "CPU2_Temp | 0.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000".to_string(),
+            ])
+        };
+        let mock = ipmi::mock::MockTransport::new();
+        mock.push_sensors(reading("60.000"));
+        mock.push_sensors(reading("101.000"));
+        mock.push_sensors(reading("60.000"));
+        let mut transport = mock.clone();
+        let (tx, _rx) = tokio::sync::mpsc::channel(100);
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fan_control = profile::FanControl::new(hr650x);
+        let mut state = LoopState::new(&config, fan_control.profile());
+
+        poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await;
+        assert_eq!(fan_control.duty(4), Some(2));
+        let sent = mock.requests().len();
+        poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await;
+        assert!(state.safety.engaged());
+        assert_eq!(mock.requests()[sent..], [(0x2e, 0x30, vec![0x00, 0, 100])]);
+        assert!(fan_control.duties().iter().all(|(_, duty)| *duty == 100));
+        poll(&config, &mut transport, &mut fan_control, &mut state, &tx).await;
+        assert!(!state.safety.engaged());
+        assert_eq!(fan_control.duty(4), Some(2));
+        assert_eq!(fan_control.duty(1), Some(40));
+    }
+
+    /// SIGTERM 在读取传感器时到达，也要退出并交还给 BMC
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sigterm_during_poll() {
+        let socket = std::env::temp_dir().join(format!("smartfan-sigterm-{}.sock", std::process::id()));
+        let config: config::Config = serde_yaml::from_str(&format!(
+            "mode: out-band
+server_model: Dell PowerEdge R730
+ipmi: {{host: bmc, username: admin, password: admin}}
+curve: [[40, 20], [80, 60]]
+control_socket: {}
+history: {{enabled: false}}
+",
+            socket.display()
+        ))
+        .unwrap();
+        let mock = ipmi::mock::MockTransport::new();
+        mock.push_sensors(testutil::sensors(&["CPU1_Temp | 60.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000"]));
+        mock.set_sensors_delay(Duration::from_millis(500));
+        let dell = testutil::builtin_profile("dell-poweredge");
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        // 界面保持打开，循环只能因为信号退出
+        let (_ui, receive_from_ui) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        let looped = tokio::spawn(run_loop(config, "config.yaml", None, Box::new(mock.clone()), profile::FanControl::new(dell), tx, receive_from_ui));
+
+        while mock.sensor_reads() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+        tokio::time::timeout(Duration::from_secs(5), looped).await.expect("loop exits on SIGTERM").unwrap();
+        assert!(mock.requests().contains(&(0x30, 0x30, vec![0x01, 0x01])));
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn test_control_requests() {
+        let mut config: config::Config = serde_yaml::from_str(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {host: bmc, username: admin, password: admin}
+curve: [[40, 20], [80, 60]]
+",
+        )
+        .unwrap();
+        let mock = ipmi::mock::MockTransport::new();
+        let sensors = testutil::sensors(&["CPU1_Temp | 60.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000"]);
+        mock.push_sensors(sensors.clone());
+        mock.push_sensors(sensors);
+        let (tx, _rx) = tokio::sync::mpsc::channel(100);
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut state = LoopState::new(&config, &hr650x);
+        let mut fans = safety::AutoRestore {
+            transport: Box::new(mock.clone()),
+            fan_control: profile::FanControl::new(hr650x),
+        };
+
+        let (response, _) = control(&control::Request::Override { speed: 120, minutes: 10 }, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(!response.ok);
+        for minutes in [control::MAX_OVERRIDE_MINUTES + 1, u64::MAX] {
+            let (response, _) = control(&control::Request::Override { speed: 70, minutes }, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+            assert!(!response.ok && state.manual.is_none());
+        }
+        let (response, poll_now) = control(&control::Request::Override { speed: 70, minutes: 10 }, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(response.ok && poll_now);
+        let reading = poll(&config, fans.transport.as_mut(), &mut fans.fan_control, &mut state, &tx).await.unwrap();
+        // 手动转速代替曲线的 40%
+        assert_eq!(reading.speed, Some(70));
+
+        // 暂停需要交还 BMC 的命令，HR650X 没有，拒绝且不写入
+        let sent = mock.requests().len();
+        let (response, _) = control(&control::Request::Pause, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(!response.ok && !state.paused);
+        assert_eq!(mock.requests().len(), sent);
+        config.force_profile = true;
+        let (response, _) = control(&control::Request::Profile { name: "dell-poweredge".to_string() }, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(response.ok);
+        poll(&config, fans.transport.as_mut(), &mut fans.fan_control, &mut state, &tx).await.unwrap();
+        let (response, _) = control(&control::Request::Pause, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(response.ok);
+        let sent = mock.requests().len();
+        let paused = poll(&config, fans.transport.as_mut(), &mut fans.fan_control, &mut state, &tx).await.unwrap();
+        assert_eq!(paused.speed, None);
+        assert_eq!(mock.requests().len(), sent);
+
+        let (response, _) = control(&control::Request::Status, &mut config, "config.yaml", None, &mut fans, &mut state, Some(&reading));
+        let status = response.status.unwrap();
+        assert_eq!(status.profile, "dell-poweredge");
+        assert!(status.paused && status.manual.is_none());
+        assert_eq!(status.reading.unwrap().temperature, 60.0);
+        let (response, _) = control(&control::Request::Profile { name: "no-such-profile".to_string() }, &mut config, "config.yaml", None, &mut fans, &mut state, None);
+        assert!(!response.ok);
     }
 }
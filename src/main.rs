@@ -1,28 +1,256 @@
-use log::Level;
+use argh::FromArgs;
+use log::{Level, LevelFilter};
 use std::error::Error;
 use tokio::sync::mpsc;
-use std::env;
+
+/// 灵蛛smartfan: fan control for rack servers through their BMC
+#[derive(Debug, FromArgs)]
+struct Cli {
+    /// whether unicode symbols are used to improve the overall look of the app
+    #[argh(option, default = "true")]
+    enhanced_graphics: bool,
+    /// run the control loop without the TUI, logging to stderr (same as `run`)
+    #[argh(switch)]
+    daemon: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Run(RunArgs),
+    Sensors(SensorsArgs),
+    Set(SetArgs),
+    Auto(AutoArgs),
+    CheckConfig(CheckConfigArgs),
+    Export(ExportArgs),
+    Ctl(CtlArgs),
+    Attach(AttachArgs),
+}
+
+/// run the control loop without the TUI, for systemd
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "run")]
+struct RunArgs {}
+
+/// read every sensor once and print it with its alarm status
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "sensors")]
+struct SensorsArgs {
+    /// print JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+}
+
+/// set the fans to a fixed speed and exit, the BMC keeps it until `auto`
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "set")]
+struct SetArgs {
+    /// fan speed in percent
+    #[argh(positional)]
+    speed: u8,
+    /// only this zone of the profile, by name or id
+    #[argh(option)]
+    zone: Option<String>,
+}
+
+/// hand the fans back to the BMC's automatic control
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "auto")]
+struct AutoArgs {}
+
+/// validate config.yaml and show the profile it selects
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "check-config")]
+struct CheckConfigArgs {}
+
+/// print the records of the data log between two times
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "export")]
+struct ExportArgs {
+    /// start, as YYYY-MM-DD[ HH:MM[:SS]] in local time or RFC 3339
+    #[argh(option)]
+    from: Option<String>,
+    /// end (exclusive), in the same form as --from
+    #[argh(option)]
+    to: Option<String>,
+    /// csv or jsonl, by default the format of the log
+    #[argh(option)]
+    format: Option<smartfan::config::DataFormat>,
+}
+
+/// query or command a running smartfan through its control socket
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "ctl")]
+struct CtlArgs {
+    /// control socket, by default control_socket from config.yaml or smartfan.sock
+    #[argh(option)]
+    socket: Option<String>,
+    /// print the response as JSON
+    #[argh(switch)]
+    json: bool,
+    #[argh(subcommand)]
+    action: CtlAction,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum CtlAction {
+    Status(StatusArgs),
+    Override(OverrideArgs),
+    Pause(PauseArgs),
+    Resume(ResumeArgs),
+    Profile(ProfileArgs),
+    Reload(ReloadArgs),
+}
+
+/// current readings, duty and profile
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "status")]
+struct StatusArgs {}
+
+/// run every fan at a fixed speed for a while
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "override")]
+struct OverrideArgs {
+    /// fan speed in percent
+    #[argh(positional)]
+    speed: u8,
+    /// minutes until automatic control resumes, at most 1440
+    #[argh(positional)]
+    minutes: u64,
+}
+
+/// stop sending fan commands and let the BMC control the fans
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "pause")]
+struct PauseArgs {}
+
+/// back to automatic control, ending a pause or override
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "resume")]
+struct ResumeArgs {}
+
+/// switch to another fan profile
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "profile")]
+struct ProfileArgs {
+    /// profile name
+    #[argh(positional)]
+    name: String,
+}
+
+/// read config.yaml again, except the connection settings
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "reload")]
+struct ReloadArgs {}
+
+/// show the TUI of a running smartfan, e.g. one started with `run`
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "attach")]
+struct AttachArgs {
+    /// control socket, by default control_socket from config.yaml or smartfan.sock
+    #[argh(option)]
+    socket: Option<String>,
+}
+
+/// Runs the one-shot subcommands, None for the others.
+fn oneshot(command: &Command) -> Option<std::io::Result<()>> {
+    if !matches!(command, Command::Sensors(_) | Command::Set(_) | Command::Auto(_) | Command::CheckConfig(_) | Command::Export(_)) {
+        return None;
+    }
+    // 警告写到 stderr，结果写到 stdout
+    let _ = simple_logger::init_with_level(Level::Warn);
+    match command {
+        Command::Sensors(args) => Some(smartfan::cli::sensors(args.json)),
+        Command::Set(args) => Some(smartfan::cli::set(args.speed, args.zone.as_deref())),
+        Command::Auto(_) => Some(smartfan::cli::auto()),
+        Command::CheckConfig(_) => Some(smartfan::cli::check_config()),
+        Command::Export(args) => Some(smartfan::cli::export(args.from.as_deref(), args.to.as_deref(), args.format)),
+        _ => None,
+    }
+}
+
+/// Sends one request and prints the answer. Exits non-zero when it was refused.
+fn ctl(args: CtlArgs) -> Result<(), Box<dyn Error>> {
+    use smartfan::control::Request;
+
+    let request = match args.action {
+        CtlAction::Status(_) => Request::Status,
+        CtlAction::Override(o) => Request::Override { speed: o.speed, minutes: o.minutes },
+        CtlAction::Pause(_) => Request::Pause,
+        CtlAction::Resume(_) => Request::Resume,
+        CtlAction::Profile(p) => Request::Profile { name: p.name },
+        CtlAction::Reload(_) => Request::Reload,
+    };
+    let path = args.socket.unwrap_or_else(smartfan::control::socket_path);
+    let response = smartfan::control::request(&path, &request)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else if let Some(status) = &response.status {
+        println!("{}", status);
+    } else if let Some(message) = &response.message {
+        println!("{}", message);
+    }
+    if !response.ok {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::init_with_level(Level::Error).unwrap();
+    let cli: Cli = argh::from_env();
 
     // ipmitool enterprise-numbers -> ${HOME}/.local/usr/share/misc/enterprise-numbers
     #[cfg(target_os = "windows")]
     {
+        use std::env;
         let exe_path = env::current_exe()?;
         let home_dir = exe_path.parent().unwrap().to_str().unwrap();
         env::set_var("HOME", home_dir);
     }
 
+    if let Some(result) = cli.command.as_ref().and_then(oneshot) {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Ctl(args)) = cli.command {
+        return ctl(args);
+    }
+    if cli.daemon || matches!(cli.command, Some(Command::Run(_))) {
+        // RUST_LOG 可以调整日志级别
+        simple_logger::SimpleLogger::new().with_level(LevelFilter::Info).env().init()?;
+        // 错误已经写入日志
+        if smartfan::daemon::run().await.is_err() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    simple_logger::init_with_level(Level::Error).unwrap();
+
     let (tx, rx) = mpsc::channel::<smartfan::Message>(100);
     let (ui_tx, ui_rx) = mpsc::channel::<smartfan::UIMessage>(100);
 
-    let _ = tokio::task::spawn(async {
+    if let Some(Command::Attach(args)) = cli.command {
+        let path = args.socket.unwrap_or_else(smartfan::control::socket_path);
+        // 阻塞的 socket 读写放在单独的线程，TUI 退出后它随之结束
+        std::thread::spawn(move || smartfan::control::attach(&path, tx, ui_rx));
+        return smartfan::tui::run_tui(cli.enhanced_graphics, rx, ui_tx);
+    }
+
+    let ipmi_loop = tokio::task::spawn(async {
         log::info!("initiating loop");
-        smartfan::init_loop(tx, ui_rx).await;
+        // 错误已经显示在 TUI 中
+        let _ = smartfan::init_loop(tx, ui_rx).await;
     });
 
-    // Ok(ipmi_loop.await?)
-    smartfan::tui::run_tui(rx, ui_tx)
+    let result = smartfan::tui::run_tui(cli.enhanced_graphics, rx, ui_tx);
+    // run_tui 返回时 ui_tx 已经释放，等循环把风扇交还给 BMC
+    let _ = tokio::time::timeout(std::time::Duration::from_secs(10), ipmi_loop).await;
+    result
 }
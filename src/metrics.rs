@@ -0,0 +1,282 @@
+//! Prometheus text exposition on `GET /metrics`: every sensor reading, the commanded duty per
+//! zone, the active profile and mode, IPMI call latency and errors, and the loop iterations.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::control::Reading;
+use crate::ipmi::identify::ServerIdentity;
+use crate::ipmi::IpmiTransport;
+use crate::profile::FanControl;
+use crate::sensor_result::SensorResult;
+
+#[derive(Debug, Default)]
+struct CallStats {
+    count: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+/// What the endpoint reports, updated by the loop and by [`Instrumented`].
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sensors: Vec<SensorResult>,
+    calls: BTreeMap<&'static str, CallStats>,
+    duties: Vec<(u8, u8)>,
+    profile: String,
+    mode: String,
+    setpoint: Option<f64>,
+    temperature: Option<f64>,
+    iterations: u64,
+    failed_iterations: u64,
+    last_iteration: f64,
+    last_success: f64,
+}
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+impl Metrics {
+    pub fn record_call(&mut self, call: &'static str, elapsed: Duration, ok: bool) {
+        let stats = self.calls.entry(call).or_default();
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+        if !ok {
+            stats.errors += 1;
+        }
+    }
+
+    pub fn record_sensors(&mut self, sensors: &[SensorResult]) {
+        self.sensors = sensors.to_vec();
+    }
+
+    /// One loop iteration; `reading` is None when it failed.
+    pub fn record_iteration(&mut self, reading: Option<&Reading>, fan_control: &FanControl, mode: &str, setpoint: Option<f64>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        self.iterations += 1;
+        self.last_iteration = now;
+        match reading {
+            Some(reading) => {
+                self.last_success = now;
+                self.temperature = Some(reading.temperature);
+            }
+            None => self.failed_iterations += 1,
+        }
+        self.duties = fan_control.duties();
+        self.profile = fan_control.profile().name.clone();
+        self.mode = mode.to_string();
+        self.setpoint = setpoint;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        family(&mut out, "smartfan_sensor_value", "gauge", "Sensor reading in its own unit.");
+        for s in self.sensors.iter().filter(|s| s.value.is_some()) {
+            let unit = s.unit.as_deref().unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "smartfan_sensor_value{{sensor=\"{}\",type=\"{}\",unit=\"{}\"}} {}",
+                escape(&s.sensor_name),
+                sensor_type(unit),
+                escape(unit),
+                s.value.unwrap_or_default()
+            );
+        }
+        family(&mut out, "smartfan_fan_duty_percent", "gauge", "Duty last commanded per zone.");
+        for (zone, duty) in &self.duties {
+            let _ = writeln!(out, "smartfan_fan_duty_percent{{zone=\"{}\"}} {}", zone, duty);
+        }
+        family(&mut out, "smartfan_info", "gauge", "Active fan profile and control mode.");
+        let _ = writeln!(out, "smartfan_info{{profile=\"{}\",mode=\"{}\"}} 1", escape(&self.profile), escape(&self.mode));
+        if let Some(temperature) = self.temperature {
+            family(&mut out, "smartfan_control_temperature_celsius", "gauge", "Temperature the fan speed was chosen for.");
+            let _ = writeln!(out, "smartfan_control_temperature_celsius {}", temperature);
+        }
+        if let Some(setpoint) = self.setpoint {
+            family(&mut out, "smartfan_pid_setpoint_celsius", "gauge", "Target temperature of the PID controller.");
+            let _ = writeln!(out, "smartfan_pid_setpoint_celsius {}", setpoint);
+        }
+        family(&mut out, "smartfan_ipmi_call_duration_seconds", "summary", "Time spent in BMC calls.");
+        for (call, stats) in &self.calls {
+            let _ = writeln!(out, "smartfan_ipmi_call_duration_seconds_sum{{call=\"{}\"}} {}", call, stats.seconds);
+            let _ = writeln!(out, "smartfan_ipmi_call_duration_seconds_count{{call=\"{}\"}} {}", call, stats.count);
+        }
+        family(&mut out, "smartfan_ipmi_call_errors_total", "counter", "BMC calls that failed.");
+        for (call, stats) in &self.calls {
+            let _ = writeln!(out, "smartfan_ipmi_call_errors_total{{call=\"{}\"}} {}", call, stats.errors);
+        }
+        for (name, kind, help, value) in [
+            ("smartfan_iterations_total", "counter", "Loop iterations.", self.iterations as f64),
+            ("smartfan_iteration_failures_total", "counter", "Loop iterations that failed to read or set.", self.failed_iterations as f64),
+            ("smartfan_last_iteration_timestamp_seconds", "gauge", "Unix time of the last loop iteration.", self.last_iteration),
+            ("smartfan_last_success_timestamp_seconds", "gauge", "Unix time of the last successful loop iteration.", self.last_success),
+        ] {
+            family(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// 按 ipmitool 的单位分类
+fn sensor_type(unit: &str) -> &'static str {
+    match unit {
+        "degrees C" => "temperature",
+        "RPM" => "fan",
+        "Watts" => "power",
+        "Volts" => "voltage",
+        "Amps" => "current",
+        _ => "other",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A transport that times every call and keeps the last sensor readings for the endpoint.
+pub struct Instrumented {
+    inner: Box<dyn IpmiTransport>,
+    metrics: SharedMetrics,
+}
+
+impl Instrumented {
+    pub fn new(inner: Box<dyn IpmiTransport>, metrics: SharedMetrics) -> Instrumented {
+        Instrumented { inner, metrics }
+    }
+
+    fn timed<T>(&mut self, call: &'static str, f: impl FnOnce(&mut dyn IpmiTransport) -> io::Result<T>) -> io::Result<T> {
+        let start = Instant::now();
+        let result = f(self.inner.as_mut());
+        self.metrics.lock().unwrap().record_call(call, start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+impl IpmiTransport for Instrumented {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let sensors = self.timed("sensors", |t| t.sensors())?;
+        self.metrics.lock().unwrap().record_sensors(&sensors);
+        Ok(sensors)
+    }
+
+    fn raw(&mut self, netfn: u8, cmd: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.timed("raw", |t| t.raw(netfn, cmd, data))
+    }
+
+    fn set_fan_duty(&mut self, zone: u8, duty: u8) -> io::Result<bool> {
+        self.timed("set_fan_duty", |t| t.set_fan_duty(zone, duty))
+    }
+
+    fn restore_fan_duty(&mut self) -> io::Result<()> {
+        self.timed("restore_fan_duty", |t| t.restore_fan_duty())
+    }
+
+    fn identify(&mut self) -> io::Result<ServerIdentity> {
+        self.timed("identify", |t| t.identify())
+    }
+
+    fn set_lower_thresholds(&mut self, sensor: &str, lower: [f64; 3]) -> io::Result<()> {
+        self.timed("set_lower_thresholds", |t| t.set_lower_thresholds(sensor, lower))
+    }
+}
+
+/// Serves `GET /metrics` on `listen` from a thread of its own.
+pub fn serve(listen: &str, metrics: SharedMetrics) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if let Err(e) = respond(stream, &metrics) {
+                log::debug!("metrics client: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: TcpStream, metrics: &SharedMetrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 跳过请求头
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) if path == "/metrics" || path.starts_with("/metrics?") => {
+            ("200 OK", metrics.lock().unwrap().render())
+        }
+        _ => ("404 Not Found", "smartfan serves /metrics\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::mock::MockTransport;
+    use crate::testutil;
+    use std::io::Read;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = SharedMetrics::default();
+        let mock = MockTransport::new();
+        mock.push_sensors(testutil::sensors(&[
+            "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "FAN1_Speed | 5400.000 | RPM | ok | na | na | na | na | na | na",
+            "PSU2_Status | na | discrete | ns | na | na | na | na | na | na",
+        ]));
+        mock.push_sensors_error("timeout");
+        let mut transport = Instrumented::new(Box::new(mock), metrics.clone());
+        transport.sensors().unwrap();
+        assert!(transport.sensors().is_err());
+
+        let dell = testutil::builtin_profile("dell-poweredge");
+        let mut fan_control = FanControl::new(dell);
+        fan_control.set_speed(&mut transport, 30, 0).unwrap();
+        metrics.lock().unwrap().record_iteration(None, &fan_control, "curve", None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        serve(&addr.to_string(), metrics.clone()).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "smartfan_sensor_value{sensor=\"CPU1_Temp\",type=\"temperature\",unit=\"degrees C\"} 52",
+            "smartfan_sensor_value{sensor=\"FAN1_Speed\",type=\"fan\",unit=\"RPM\"} 5400",
+            "smartfan_fan_duty_percent{zone=\"0\"} 30",
+            "smartfan_info{profile=\"dell-poweredge\",mode=\"curve\"} 1",
+            "smartfan_ipmi_call_duration_seconds_count{call=\"sensors\"} 2",
+            "smartfan_ipmi_call_errors_total{call=\"sensors\"} 1",
+            "smartfan_ipmi_call_errors_total{call=\"raw\"} 0",
+            "smartfan_iteration_failures_total 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!response.contains("PSU2_Status"));
+    }
+}
@@ -0,0 +1,377 @@
+//! Publishes the temperature, fan RPMs, power and commanded duty of every iteration to an MQTT
+//! broker, with retained Home Assistant discovery configs so the entities show up by themselves.
+//! The command topic takes a speed in percent (a timed override), `auto`, `pause`, or any
+//! control socket request as JSON.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::Level;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Map, Value};
+use tokio::sync::{mpsc::Sender, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::MqttConfig;
+use crate::control::{self, Reading, Request};
+use crate::Message;
+
+/// 断开后等这么久再重连
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    pub node: String,
+    pub state: String,
+    pub availability: String,
+    pub command: String,
+    pub discovery: String,
+}
+
+impl Topics {
+    pub fn new(config: &MqttConfig, host: &str) -> Topics {
+        let node = object_id(config.node_id.as_deref().unwrap_or(if host.is_empty() { "localhost" } else { host }));
+        let base = format!("{}/{}", config.topic_prefix.trim_end_matches('/'), node);
+        Topics {
+            state: format!("{}/state", base),
+            availability: format!("{}/availability", base),
+            command: format!("{}/set", base),
+            discovery: config.discovery_prefix.trim_end_matches('/').to_string(),
+            node,
+        }
+    }
+}
+
+/// Home Assistant 的 object id 只能包含字母、数字、下划线和连字符
+fn object_id(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// The state message: everything the discovered entities read through their value templates.
+pub fn state(reading: &Reading, duties: &[(u8, u8)], mode: &str) -> String {
+    let named = |values: &[(String, f64)]| values.iter().map(|(name, v)| (name.clone(), json!(v))).collect::<Map<_, _>>();
+    json!({
+        "temperature": reading.temperature,
+        "speed": reading.speed,
+        "mode": mode,
+        "fans": named(&reading.fans),
+        "power": named(&reading.power),
+        "duties": duties.iter().map(|(zone, duty)| (zone.to_string(), json!(duty))).collect::<Map<_, _>>(),
+    })
+    .to_string()
+}
+
+/// The retained discovery configs for a reading, as (topic, payload).
+pub fn discovery(topics: &Topics, reading: &Reading, duties: &[(u8, u8)]) -> Vec<(String, String)> {
+    let device = json!({
+        "identifiers": [format!("smartfan_{}", topics.node)],
+        "name": format!("smartfan {}", topics.node),
+        "manufacturer": "smartfan",
+    });
+    let entity = |component: &str, object: &str, name: &str, mut extra: Value| {
+        let unique_id = format!("smartfan_{}_{}", topics.node, object_id(object));
+        let config = extra.as_object_mut().expect("entity config is an object");
+        config.insert("name".to_string(), json!(name));
+        config.insert("unique_id".to_string(), json!(unique_id));
+        config.insert("availability_topic".to_string(), json!(topics.availability));
+        config.insert("device".to_string(), device.clone());
+        (format!("{}/{}/{}/{}/config", topics.discovery, component, topics.node, object_id(object)), extra.to_string())
+    };
+    let sensor = |object: &str, name: &str, template: String, unit: &str, class: Option<&str>| {
+        let mut extra = json!({
+            "state_topic": topics.state,
+            "value_template": template,
+            "unit_of_measurement": unit,
+            "state_class": "measurement",
+        });
+        if let Some(class) = class {
+            extra["device_class"] = json!(class);
+        }
+        entity("sensor", object, name, extra)
+    };
+    let mut configs = vec![
+        sensor("temperature", "Temperature", "{{ value_json.temperature }}".to_string(), "°C", Some("temperature")),
+        sensor("speed", "Fan speed", "{{ value_json.speed }}".to_string(), "%", None),
+        entity(
+            "number",
+            "speed_override",
+            "Fan speed override",
+            json!({
+                "command_topic": topics.command,
+                "state_topic": topics.state,
+                "value_template": "{{ value_json.speed }}",
+                "min": 0,
+                "max": 100,
+                "unit_of_measurement": "%",
+                "mode": "slider",
+            }),
+        ),
+        entity(
+            "button",
+            "auto",
+            "Resume automatic control",
+            json!({ "command_topic": topics.command, "payload_press": "auto" }),
+        ),
+    ];
+    for (name, _) in &reading.fans {
+        configs.push(sensor(&format!("fan_{}", name), name, format!("{{{{ value_json.fans['{}'] }}}}", name), "RPM", None));
+    }
+    for (name, _) in &reading.power {
+        configs.push(sensor(&format!("power_{}", name), name, format!("{{{{ value_json.power['{}'] }}}}", name), "W", Some("power")));
+    }
+    for (zone, _) in duties {
+        let template = format!("{{{{ value_json.duties['{}'] }}}}", zone);
+        configs.push(sensor(&format!("duty_{}", zone), &format!("Zone {} duty", zone), template, "%", None));
+    }
+    configs
+}
+
+/// The messages that take the retained discovery configs on the broker from the `previous` topics
+/// to `configs`: every config, then an empty retained payload for each entity that went away, which
+/// makes Home Assistant delete it.
+pub fn discovery_update(previous: &[String], configs: Vec<(String, String)>) -> Vec<(String, String)> {
+    let removed: Vec<(String, String)> = previous
+        .iter()
+        .filter(|topic| !configs.iter().any(|(t, _)| t == *topic))
+        .map(|topic| (topic.clone(), String::new()))
+        .collect();
+    configs.into_iter().chain(removed).collect()
+}
+
+/// Parses a message on the command topic.
+pub fn command(payload: &str, override_minutes: u64) -> Result<Request, String> {
+    let payload = payload.trim();
+    match payload {
+        "auto" | "resume" => return Ok(Request::Resume),
+        "pause" => return Ok(Request::Pause),
+        _ => {}
+    }
+    // Home Assistant 的 number 实体可能发送 60.0
+    if let Ok(speed) = payload.parse::<f64>() {
+        if !(0.0..=100.0).contains(&speed) {
+            return Err(format!("speed {} is out of 0-100", payload));
+        }
+        return Ok(Request::Override {
+            speed: speed.round() as u8,
+            minutes: override_minutes,
+        });
+    }
+    serde_json::from_str(payload).map_err(|e| format!("unknown command {:?}: {}", payload, e))
+}
+
+/// The connection to the broker. Its event loop runs as a task and hands commands to the loop
+/// through the control channel.
+pub struct Mqtt {
+    client: AsyncClient,
+    topics: Topics,
+    /// 已发布的发现配置的主题，实体变化时重新发布
+    discovered: Vec<String>,
+    /// 每次连上 broker 时置位：broker 可能重启过，丢了保留消息
+    reconnected: Arc<AtomicBool>,
+    /// 发现配置需要整体重发，直到发送成功
+    rediscover: bool,
+    task: JoinHandle<()>,
+}
+
+impl Mqtt {
+    pub fn start(config: &MqttConfig, host: &str, calls: Sender<control::Call>, send_to_ui: Sender<Message>) -> Mqtt {
+        let topics = Topics::new(config, host);
+        let mut options = MqttOptions::new(format!("smartfan-{}", topics.node), &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(&topics.availability, "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let reconnected = Arc::new(AtomicBool::new(false));
+        let task = {
+            let (client, topics, reconnected) = (client.clone(), topics.clone(), reconnected.clone());
+            let (broker, override_minutes) = (format!("{}:{}", config.host, config.port), config.override_minutes);
+            tokio::spawn(async move {
+                let log = |level, msg: String| send_to_ui.send(Message::build_log(level, msg));
+                let mut connected = true;
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            connected = true;
+                            reconnected.store(true, Ordering::Relaxed);
+                            let _ = log(Level::Info, format!("MQTT connected to {}", broker)).await;
+                            let _ = client.try_publish(&topics.availability, QoS::AtLeastOnce, true, "online");
+                            let _ = client.try_subscribe(&topics.command, QoS::AtLeastOnce);
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == topics.command => {
+                            let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                            match command(&payload, override_minutes) {
+                                // 结果由控制循环记录
+                                Ok(request) => {
+                                    let (reply, _) = oneshot::channel();
+                                    if calls.send((request, reply)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                Err(e) => {
+                                    let _ = log(Level::Warn, format!("MQTT: {}", e)).await;
+                                }
+                            }
+                        }
+                        Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            // 只在第一次失败时记录，重连由下一次 poll 完成
+                            if connected {
+                                connected = false;
+                                let _ = log(Level::Warn, format!("MQTT {} unavailable: {}", broker, e)).await;
+                            }
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                        }
+                    }
+                }
+            })
+        };
+        Mqtt {
+            client,
+            topics,
+            discovered: vec![],
+            reconnected,
+            rediscover: false,
+            task,
+        }
+    }
+
+    /// Publishes the state of an iteration, and the discovery configs after every (re)connect and
+    /// when the entities changed. Never waits for the broker: while it is unreachable the messages
+    /// are dropped.
+    pub fn publish(&mut self, reading: &Reading, duties: &[(u8, u8)], mode: &str) {
+        self.rediscover |= self.reconnected.swap(false, Ordering::Relaxed);
+        let configs = discovery(&self.topics, reading, duties);
+        let topics: Vec<String> = configs.iter().map(|(topic, _)| topic.clone()).collect();
+        if self.rediscover || topics != self.discovered {
+            let messages = discovery_update(&self.discovered, configs);
+            let sent = messages.into_iter().all(|(topic, payload)| self.client.try_publish(topic, QoS::AtLeastOnce, true, payload).is_ok());
+            if sent {
+                self.discovered = topics;
+                self.rediscover = false;
+            }
+        }
+        if let Err(e) = self.client.try_publish(&self.topics.state, QoS::AtMostOnce, false, state(reading, duties, mode)) {
+            log::debug!("MQTT state not sent: {}", e);
+        }
+    }
+
+    /// Marks the entities unavailable and disconnects.
+    pub async fn stop(self) {
+        let _ = self.client.try_publish(&self.topics.availability, QoS::AtLeastOnce, true, "offline");
+        let _ = self.client.try_disconnect();
+        if tokio::time::timeout(Duration::from_secs(2), self.task).await.is_err() {
+            log::debug!("MQTT did not disconnect in time");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn reading() -> Reading {
+        testutil::reading()
+            .fans(&[("FAN1", 5400.0), ("FAN2", 5280.0)])
+            .power(&[("Pwr Consumption", 168.0)])
+            .build()
+    }
+
+    fn config() -> MqttConfig {
+        serde_yaml::from_str("host: localhost").unwrap()
+    }
+
+    #[test]
+    fn test_mqtt_messages() {
+        let topics = Topics::new(&config(), "10.0.0.2");
+        assert_eq!(topics.state, "smartfan/10_0_0_2/state");
+        assert_eq!(topics.command, "smartfan/10_0_0_2/set");
+
+        let state: Value = serde_json::from_str(&state(&reading(), &[(0, 30)], "curve")).unwrap();
+        assert_eq!(state["fans"]["FAN2"], json!(5280.0));
+        assert_eq!(state["power"]["Pwr Consumption"], json!(168.0));
+        assert_eq!(state["duties"]["0"], json!(30));
+
+        let configs = discovery(&topics, &reading(), &[(0, 30)]);
+        let topics_of: Vec<&str> = configs.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(
+            topics_of,
+            vec![
+                "homeassistant/sensor/10_0_0_2/temperature/config",
+                "homeassistant/sensor/10_0_0_2/speed/config",
+                "homeassistant/number/10_0_0_2/speed_override/config",
+                "homeassistant/button/10_0_0_2/auto/config",
+                "homeassistant/sensor/10_0_0_2/fan_fan1/config",
+                "homeassistant/sensor/10_0_0_2/fan_fan2/config",
+                "homeassistant/sensor/10_0_0_2/power_pwr_consumption/config",
+                "homeassistant/sensor/10_0_0_2/duty_0/config",
+            ]
+        );
+        let power: Value = serde_json::from_str(&configs[6].1).unwrap();
+        assert_eq!(power["value_template"], "{{ value_json.power['Pwr Consumption'] }}");
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["unique_id"], "smartfan_10_0_0_2_power_pwr_consumption");
+        assert_eq!(power["availability_topic"], "smartfan/10_0_0_2/availability");
+
+        // FAN2 消失后清除它的保留配置
+        let previous: Vec<String> = configs.iter().map(|(t, _)| t.clone()).collect();
+        let mut one_fan = reading();
+        one_fan.fans.truncate(1);
+        let update = discovery_update(&previous, discovery(&topics, &one_fan, &[(0, 30)]));
+        assert_eq!(update.len(), 8);
+        assert_eq!(update[7], ("homeassistant/sensor/10_0_0_2/fan_fan2/config".to_string(), String::new()));
+        assert!(update[..7].iter().all(|(_, payload)| !payload.is_empty()));
+
+        assert_eq!(command("60.0", 30), Ok(Request::Override { speed: 60, minutes: 30 }));
+        assert_eq!(command("auto", 30), Ok(Request::Resume));
+        assert_eq!(command(r#"{"cmd":"profile","name":"dell-poweredge"}"#, 30), Ok(Request::Profile { name: "dell-poweredge".to_string() }));
+        assert!(command("120", 30).is_err());
+        assert!(command("faster", 30).is_err());
+    }
+
+    /// 需要本机的 Mosquitto：cargo test -- --ignored test_mosquitto
+    #[tokio::test]
+    #[ignore]
+    async fn test_mosquitto() {
+        let config = config();
+        let (calls_tx, mut calls) = tokio::sync::mpsc::channel(8);
+        let (send_to_ui, _ui) = tokio::sync::mpsc::channel(64);
+        let mut mqtt = Mqtt::start(&config, "mosquitto-test", calls_tx, send_to_ui);
+
+        // 另一个客户端订阅状态并发送命令
+        let (observer, mut eventloop) = AsyncClient::new(MqttOptions::new("smartfan-test-observer", "localhost", 1883), 16);
+        observer.subscribe("smartfan/mosquitto-test/#", QoS::AtLeastOnce).await.unwrap();
+        observer.subscribe("homeassistant/sensor/mosquitto-test/temperature/config", QoS::AtLeastOnce).await.unwrap();
+        let received = tokio::spawn(async move {
+            let (mut state, mut discovery) = (None, None);
+            while state.is_none() || discovery.is_none() {
+                if let Event::Incoming(Packet::Publish(p)) = eventloop.poll().await.unwrap() {
+                    let payload = String::from_utf8_lossy(&p.payload).to_string();
+                    match p.topic.as_str() {
+                        "smartfan/mosquitto-test/state" => state = Some(payload),
+                        t if t.ends_with("/config") => discovery = Some(payload),
+                        _ => {}
+                    }
+                }
+            }
+            (state.unwrap(), discovery.unwrap(), eventloop)
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        mqtt.publish(&reading(), &[(0, 30)], "curve");
+        let (state, discovery, mut eventloop) = tokio::time::timeout(Duration::from_secs(5), received).await.unwrap().unwrap();
+        assert!(state.contains("\"FAN1\":5400.0"));
+        assert!(discovery.contains("\"device_class\":\"temperature\""));
+
+        observer.publish("smartfan/mosquitto-test/set", QoS::AtLeastOnce, false, "45").await.unwrap();
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let (request, _) = tokio::time::timeout(Duration::from_secs(5), calls.recv()).await.unwrap().unwrap();
+        assert_eq!(request, Request::Override { speed: 45, minutes: 30 });
+        mqtt.stop().await;
+    }
+}
@@ -0,0 +1,122 @@
+//! systemd `Type=notify` support: READY=1 after the first good reading, STATUS= with the
+//! temperature and duty, and WATCHDOG=1 for every loop iteration, including failed ones while
+//! the fail-safe holds the fans at full speed: the watchdog only catches a hung loop.
+//! Does nothing when NOTIFY_SOCKET is not set, or outside unix.
+
+use std::io;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<UnixDatagram>,
+    ready: bool,
+}
+
+impl Notifier {
+    /// Connects to `$NOTIFY_SOCKET` when systemd has set it.
+    pub fn from_env() -> Notifier {
+        #[cfg(unix)]
+        if let Ok(path) = std::env::var("NOTIFY_SOCKET") {
+            match Notifier::connect(&path) {
+                Ok(notifier) => return notifier,
+                Err(e) => log::warn!("Failed to connect to NOTIFY_SOCKET {}: {}", path, e),
+            }
+        }
+        Notifier {
+            #[cfg(unix)]
+            socket: None,
+            ready: false,
+        }
+    }
+
+    /// A path, or an abstract socket name starting with `@`.
+    #[cfg(unix)]
+    pub fn connect(path: &str) -> io::Result<Notifier> {
+        let socket = UnixDatagram::unbound()?;
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                socket.connect_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)?
+            }
+            _ => socket.connect(path)?,
+        }
+        Ok(Notifier {
+            socket: Some(socket),
+            ready: false,
+        })
+    }
+
+    /// A successful iteration: ready (the first time), the current status and a watchdog ping.
+    pub fn alive(&mut self, temp: f64, speed: u8) {
+        self.ping(&format!("temp {}℃, fans {}%", temp, speed));
+    }
+
+    /// A successful read while control is paused through the control socket.
+    pub fn paused(&mut self, temp: f64) {
+        self.ping(&format!("temp {}℃, paused, the BMC controls the fans", temp));
+    }
+
+    fn ping(&mut self, status: &str) {
+        let ready = if self.ready { "" } else { "READY=1\n" };
+        if self.send(&format!("{}STATUS={}\nWATCHDOG=1", ready, status)).is_ok() {
+            self.ready = true;
+        }
+    }
+
+    /// A failed iteration: not ready if it is the first, but the watchdog is pinged because the
+    /// loop is alive and the fail-safe has the fans. A restart would not bring the BMC back.
+    pub fn failed(&mut self, reason: &str) {
+        let _ = self.send(&format!("STATUS={}\nWATCHDOG=1", reason));
+    }
+
+    pub fn stopping(&mut self) {
+        let _ = self.send("STOPPING=1\nSTATUS=handing the fans back to the BMC");
+    }
+
+    #[cfg(unix)]
+    fn send(&self, state: &str) -> io::Result<()> {
+        let Some(socket) = &self.socket else {
+            return Ok(());
+        };
+        socket.send(state.as_bytes()).map(|_| ()).inspect_err(|e| {
+            log::warn!("sd_notify failed: {}", e);
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _state: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("smartfan-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        let recv = || {
+            let mut buf = [0; 256];
+            let n = systemd.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        };
+
+        notifier.alive(52.0, 25);
+        assert_eq!(recv(), "READY=1\nSTATUS=temp 52℃, fans 25%\nWATCHDOG=1");
+        notifier.failed("sensor read failed");
+        assert_eq!(recv(), "STATUS=sensor read failed\nWATCHDOG=1");
+        notifier.alive(53.5, 30);
+        assert_eq!(recv(), "STATUS=temp 53.5℃, fans 30%\nWATCHDOG=1");
+        notifier.paused(50.0);
+        assert_eq!(recv(), "STATUS=temp 50℃, paused, the BMC controls the fans\nWATCHDOG=1");
+        notifier.stopping();
+        assert!(recv().starts_with("STOPPING=1\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
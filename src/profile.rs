@@ -0,0 +1,759 @@
+//! Vendor profiles: the raw commands that take over the fans of a server model and how its
+//! fan zones are laid out. Built-in profiles live in `profiles/*.yaml`; more can be added
+//! under `profiles:` in config.yaml.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ControllerMode, ZoneConfig};
+use crate::ipmi::identify::ServerIdentity;
+use crate::ipmi::IpmiTransport;
+use crate::sensor_result::SensorResult;
+
+const BUILTIN: &[&str] = &[
+    include_str!("../profiles/lenovo-hr650x.yaml"),
+    include_str!("../profiles/dell-poweredge.yaml"),
+    include_str!("../profiles/supermicro.yaml"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// 匹配 server_model 的关键字，不区分大小写
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Get Device ID 返回的 IANA 企业编号
+    #[serde(default)]
+    pub manufacturer_ids: Vec<u32>,
+    /// 进入手动模式前发送一次的设置，例如会保存在 BMC 里的选项；重发转速时不再发送
+    #[serde(default)]
+    pub setup: Vec<RawCommand>,
+    /// 第一次设置转速前发送，关闭 BMC 的自动调速；重发转速时也一起发送
+    #[serde(default)]
+    pub manual_mode: Vec<RawCommand>,
+    pub set_duty: RawCommand,
+    /// 交还给 BMC 自动调速
+    #[serde(default)]
+    pub auto_mode: Vec<RawCommand>,
+    /// 一次设置全部风扇的 zone 编号
+    #[serde(default)]
+    pub all_zones: Option<u8>,
+    /// 启动时写入各 zone 风扇传感器的下限阈值 (lnr, lc, lnc)，避免低转速被 BMC 当成故障
+    #[serde(default)]
+    pub lower_fan_thresholds: Option<[f64; 3]>,
+    /// PID 控制输出的转速范围
+    #[serde(default)]
+    pub min_duty: u8,
+    #[serde(default = "default_max_duty")]
+    pub max_duty: u8,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+fn default_max_duty() -> u8 {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Zone {
+    pub id: u8,
+    #[serde(default)]
+    pub name: String,
+    /// 这个 zone 冷却的 CPU，从 1 开始
+    #[serde(default)]
+    pub cpu: Option<usize>,
+    /// CPU 不在位时使用的转速
+    #[serde(default)]
+    pub idle_duty: Option<u8>,
+    /// 属于这个 zone 的风扇传感器，正则表达式
+    #[serde(default)]
+    pub sensors: Vec<String>,
+}
+
+impl Zone {
+    pub fn matches(&self, sensor_name: &str) -> bool {
+        self.sensors
+            .iter()
+            .any(|p| Regex::new(p).is_ok_and(|re| re.is_match(sensor_name)))
+    }
+}
+
+/// A raw request written the way `ipmitool raw` takes it, e.g. `0x2e 0x30 0x00 {zone} {duty}`.
+/// Bytes are hex with a `0x` prefix or decimal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RawCommand {
+    netfn: u8,
+    cmd: u8,
+    data: Vec<Token>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Byte(u8),
+    Zone,
+    Duty,
+}
+
+impl RawCommand {
+    /// Returns (netfn, cmd, data) with the placeholders filled in.
+    pub fn render(&self, zone: u8, duty: u8) -> (u8, u8, Vec<u8>) {
+        let data = self
+            .data
+            .iter()
+            .map(|t| match t {
+                Token::Byte(b) => *b,
+                Token::Zone => zone,
+                Token::Duty => duty,
+            })
+            .collect();
+        (self.netfn, self.cmd, data)
+    }
+
+    pub fn send(&self, transport: &mut dyn IpmiTransport, zone: u8, duty: u8) -> io::Result<Vec<u8>> {
+        let (netfn, cmd, data) = self.render(zone, duty);
+        transport.raw(netfn, cmd, &data)
+    }
+}
+
+fn parse_byte(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl TryFrom<String> for RawCommand {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut tokens = s.split_whitespace().map(|t| match t {
+            "{zone}" => Ok(Token::Zone),
+            "{duty}" => Ok(Token::Duty),
+            t => parse_byte(t).map(Token::Byte).ok_or_else(|| format!("invalid byte {:?} in raw command {:?}", t, s)),
+        });
+        let mut header = || match tokens.next() {
+            Some(Ok(Token::Byte(b))) => Ok(b),
+            Some(Err(e)) => Err(e),
+            _ => Err(format!("raw command {:?} must start with netfn and cmd bytes", s)),
+        };
+        let netfn = header()?;
+        let cmd = header()?;
+        Ok(RawCommand {
+            netfn,
+            cmd,
+            data: tokens.collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl fmt::Display for RawCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:02x} 0x{:02x}", self.netfn, self.cmd)?;
+        for t in &self.data {
+            match t {
+                Token::Byte(b) => write!(f, " 0x{:02x}", b)?,
+                Token::Zone => write!(f, " {{zone}}")?,
+                Token::Duty => write!(f, " {{duty}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<RawCommand> for String {
+    fn from(c: RawCommand) -> String {
+        c.to_string()
+    }
+}
+
+impl Profile {
+    pub fn builtin() -> Vec<Profile> {
+        BUILTIN
+            .iter()
+            .map(|yaml| serde_yaml::from_str(yaml).expect("built-in profile is valid"))
+            .collect()
+    }
+
+    pub fn matches(&self, server_model: &str) -> bool {
+        let server_model = server_model.to_lowercase();
+        self.models.iter().any(|m| server_model.contains(&m.to_lowercase()))
+    }
+
+    /// True when one of the server's FRU names matches `models` and, if both sides have one,
+    /// the manufacturer ID is one of `manufacturer_ids`. Redfish does not report the ID.
+    pub fn matches_identity(&self, identity: &ServerIdentity) -> bool {
+        let vendor = match identity.manufacturer_id {
+            Some(id) => self.manufacturer_ids.is_empty() || self.manufacturer_ids.contains(&id),
+            None => true,
+        };
+        vendor && identity.names().iter().any(|name| self.matches(name))
+    }
+
+    /// Finds a zone by name or id.
+    pub fn zone(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .find(|z| z.name.eq_ignore_ascii_case(name) || z.id.to_string() == name)
+    }
+
+    /// The (zone, duty) pairs to send, and whether the zones of missing CPUs are among them.
+    /// Zones listed in `zone_speeds` get their own duty, the rest get `speed`. Zones of missing
+    /// CPUs get their idle duty, but only while `idle_zones_set` is false.
+    fn assignments(
+        &self,
+        speed: u8,
+        zone_speeds: &[(u8, u8)],
+        cpu_num: usize,
+        idle_zones_set: bool,
+    ) -> (Vec<(u8, u8)>, bool) {
+        let idle = |z: &Zone| cpu_num > 0 && z.idle_duty.is_some() && z.cpu.is_some_and(|c| c > cpu_num);
+        let duty = |z: &Zone| zone_speeds.iter().find(|(id, _)| *id == z.id).map_or(speed, |(_, d)| *d);
+        let uniform = self.zones.iter().all(|z| duty(z) == speed);
+        if uniform && !self.zones.iter().any(idle) {
+            let assignments = match self.all_zones {
+                Some(all) => vec![(all, speed)],
+                None if self.zones.is_empty() => vec![(0, speed)],
+                None => self.zones.iter().map(|z| (z.id, speed)).collect(),
+            };
+            return (assignments, false);
+        }
+        let mut assignments: Vec<(u8, u8)> = self.zones.iter().filter(|z| !idle(z)).map(|z| (z.id, duty(z))).collect();
+        if idle_zones_set || !self.zones.iter().any(idle) {
+            return (assignments, false);
+        }
+        assignments.extend(self.zones.iter().filter(|z| idle(z)).map(|z| (z.id, z.idle_duty.unwrap_or(speed))));
+        (assignments, true)
+    }
+
+    /// Applies the zones of config.yaml: new member fans for known zones, and zones with an `id`
+    /// that the profile does not have.
+    fn apply_zones(&mut self, zones: &[ZoneConfig]) {
+        for config in zones {
+            let existing = self.zones.iter_mut().find(|z| {
+                z.name.eq_ignore_ascii_case(&config.name) || z.id.to_string() == config.name || Some(z.id) == config.id
+            });
+            match (existing, config.id) {
+                (Some(zone), _) => {
+                    if !config.fans.is_empty() {
+                        zone.sensors = config.fans.clone();
+                    }
+                }
+                (None, Some(id)) => self.zones.push(Zone {
+                    id,
+                    name: config.name.clone(),
+                    cpu: None,
+                    idle_duty: None,
+                    sensors: config.fans.clone(),
+                }),
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn validate(&self, config: &Config) -> io::Result<()> {
+        for pattern in self.zones.iter().flat_map(|z| &z.sensors) {
+            Regex::new(pattern).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid sensor pattern {:?} in profile {}: {}", pattern, self.name, e),
+                )
+            })?;
+        }
+        if let Some(z) = config.zones.iter().find(|z| self.zone(&z.name).is_none()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("风扇配置 {} 没有 zone {}/profile {} has no zone {:?}", self.name, z.name, self.name, z.name),
+            ));
+        }
+        // PID 输出限制在 min_duty..=max_duty
+        if config.controller == ControllerMode::Pid && (self.min_duty > self.max_duty || self.max_duty > 100) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "风扇配置 {} 的 PID 转速范围无效/profile {} needs min_duty {} <= max_duty {} <= 100 for controller: pid",
+                    self.name, self.name, self.min_duty, self.max_duty
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Picks the profile for the server the BMC reported in `identity`.
+///
+/// Raw fan commands are only sent to hardware a profile recognizes. With `force_profile` the
+/// profile named by `profile`, or else the first whose `models` match `server_model`, is used
+/// anyway. Profiles from the config shadow built-in ones with the same name.
+pub fn select(config: &Config, identity: Option<&ServerIdentity>) -> io::Result<Profile> {
+    select_named(config, config.profile.as_deref(), identity)
+}
+
+/// Like [`select`], with `name` in place of the configured `profile`. The same checks apply.
+pub fn select_named(config: &Config, name: Option<&str>, identity: Option<&ServerIdentity>) -> io::Result<Profile> {
+    let mut profiles = config.profiles.clone();
+    profiles.extend(Profile::builtin().into_iter().filter(|b| config.profiles.iter().all(|p| p.name != b.name)));
+    let detected = identity.and_then(|id| profiles.iter().find(|p| p.matches_identity(id)));
+    let model = identity.map_or("unknown".to_string(), |id| id.to_string());
+
+    let profile = match (name, detected) {
+        (None, Some(detected)) => Some(detected.clone()),
+        (Some(name), Some(detected)) if detected.name.eq_ignore_ascii_case(name) => Some(detected.clone()),
+        _ if !config.force_profile => {
+            let reason = match detected {
+                Some(detected) => format!(
+                    "检测到 {}，应使用 {} 而不是 {}/detected {}, which needs profile {} rather than {}",
+                    model,
+                    detected.name,
+                    name.unwrap_or_default(),
+                    model,
+                    detected.name,
+                    name.unwrap_or_default()
+                ),
+                None => format!("不支持的服务器 {}/unsupported server {}", model, model),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{}. 确认命令适用后设置 force_profile: true/set force_profile: true if the commands are known to work", reason),
+            ));
+        }
+        (Some(name), _) => profiles.into_iter().find(|p| p.name.eq_ignore_ascii_case(name)),
+        (None, None) => profiles.into_iter().find(|p| p.matches(&config.server_model)),
+    };
+    let profile = profile.ok_or_else(|| {
+        let names = Profile::builtin().into_iter().map(|p| p.name).collect::<Vec<_>>().join(", ");
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "没有适合 {} 的风扇配置，请设置 profile 或在 profiles 中添加/no fan profile for {:?}, set `profile` or add one under `profiles` (built-in: {})",
+                config.server_model, config.server_model, names
+            ),
+        )
+    })?;
+    let mut profile = profile;
+    profile.apply_zones(&config.zones);
+    profile.validate(config)?;
+    Ok(profile)
+}
+
+/// 转速没变时最多连续跳过这么多次写入，之后照样重发一次：BMC 重启或被别的工具改过转速后，
+/// 默认 15 秒的间隔下约 5 分钟内恢复
+const RESEND_AFTER: u32 = 20;
+
+/// A profile plus what has already been sent to the BMC.
+pub struct FanControl {
+    profile: Profile,
+    manual_mode_set: bool,
+    /// 通过传输层自己的请求写过转速，例如 Redfish
+    transport_set: bool,
+    idle_zones_set: bool,
+    thresholds_set: bool,
+    /// 每个 zone 最后一次写入的转速，没有变化时不再发送
+    sent: HashMap<u8, u8>,
+    /// 每个 zone 连续跳过的写入次数
+    skipped: HashMap<u8, u32>,
+}
+
+impl FanControl {
+    pub fn new(profile: Profile) -> FanControl {
+        FanControl {
+            profile,
+            manual_mode_set: false,
+            transport_set: false,
+            idle_zones_set: false,
+            thresholds_set: false,
+            sent: HashMap::new(),
+            skipped: HashMap::new(),
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// The duty last written to `zone`.
+    pub fn duty(&self, zone: u8) -> Option<u8> {
+        self.sent.get(&zone).copied()
+    }
+
+    /// Every zone with the duty last written to it, by zone id.
+    pub fn duties(&self) -> Vec<(u8, u8)> {
+        let mut duties: Vec<(u8, u8)> = self.sent.iter().map(|(zone, duty)| (*zone, *duty)).collect();
+        duties.sort();
+        duties
+    }
+
+    /// Sets every zone for `speed`, entering manual mode first if needed.
+    pub fn set_speed(&mut self, transport: &mut dyn IpmiTransport, speed: u8, cpu_num: usize) -> io::Result<()> {
+        self.set_speeds(transport, speed, &[], cpu_num)
+    }
+
+    /// Like [`FanControl::set_speed`], but the zones in `zone_speeds` (id, duty) follow their own curve.
+    pub fn set_speeds(
+        &mut self,
+        transport: &mut dyn IpmiTransport,
+        speed: u8,
+        zone_speeds: &[(u8, u8)],
+        cpu_num: usize,
+    ) -> io::Result<()> {
+        let (assignments, sets_idle) = self.profile.assignments(speed, zone_speeds, cpu_num, self.idle_zones_set);
+        // 全部 zone 一起设置（例如全速保护）时 idle zone 也被改写，之后要重新设回 idle_duty
+        let overwrites_idle = !sets_idle
+            && assignments.iter().any(|(id, _)| {
+                Some(*id) == self.profile.all_zones || self.profile.zones.iter().any(|z| z.id == *id && z.idle_duty.is_some())
+            });
+        for (zone, duty) in assignments {
+            self.set_zone(transport, zone, duty)?;
+        }
+        if sets_idle {
+            self.idle_zones_set = true;
+        } else if overwrites_idle {
+            self.idle_zones_set = false;
+        }
+        Ok(())
+    }
+
+    /// Sets one zone, by name or id, and leaves the others alone.
+    pub fn set_zone_speed(&mut self, transport: &mut dyn IpmiTransport, zone: &str, duty: u8) -> io::Result<()> {
+        let id = self.profile.zone(zone).map(|z| z.id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} 没有 zone {}/profile {} has no zone {}", self.profile.name, zone, self.profile.name, zone),
+            )
+        })?;
+        self.set_zone(transport, id, duty)
+    }
+
+    /// 优先使用传输层自己的风扇请求（例如 Redfish），否则发送配置里的 raw 命令。
+    /// 转速没变时跳过，但每 RESEND_AFTER 次连同 manual_mode 重发一次（setup 除外）
+    fn set_zone(&mut self, transport: &mut dyn IpmiTransport, zone: u8, duty: u8) -> io::Result<()> {
+        let resend = self.sent.get(&zone) == Some(&duty);
+        if resend {
+            let skipped = self.skipped.entry(zone).or_default();
+            if *skipped < RESEND_AFTER {
+                *skipped += 1;
+                return Ok(());
+            }
+        }
+        if transport.set_fan_duty(zone, duty)? {
+            self.transport_set = true;
+        } else {
+            // BMC 重启后可能回到了自动模式，重发时再关闭一次
+            if !self.manual_mode_set || resend {
+                let setup = if self.manual_mode_set { &[][..] } else { &self.profile.setup[..] };
+                for c in setup.iter().chain(&self.profile.manual_mode) {
+                    c.send(transport, zone, duty)?;
+                }
+                self.manual_mode_set = true;
+            }
+            self.profile.set_duty.send(transport, zone, duty)?;
+        }
+
+        if Some(zone) == self.profile.all_zones {
+            self.sent.clear();
+            self.sent.extend(self.profile.zones.iter().map(|z| (z.id, duty)));
+            self.skipped.clear();
+        } else if let Some(all) = self.profile.all_zones {
+            self.sent.remove(&all);
+        }
+        self.sent.insert(zone, duty);
+        self.skipped.remove(&zone);
+        Ok(())
+    }
+
+    /// Writes the profile's lower fan thresholds to every fan sensor of its zones, once.
+    /// Returns the sensors that were changed.
+    pub fn apply_fan_thresholds(
+        &mut self,
+        transport: &mut dyn IpmiTransport,
+        sensors: &[SensorResult],
+    ) -> io::Result<Vec<String>> {
+        let Some(lower) = self.profile.lower_fan_thresholds else {
+            return Ok(vec![]);
+        };
+        if self.thresholds_set {
+            return Ok(vec![]);
+        }
+        let fans: Vec<String> = sensors
+            .iter()
+            .filter(|s| s.unit.as_deref() == Some("RPM") && self.profile.zones.iter().any(|z| z.matches(&s.sensor_name)))
+            .map(|s| s.sensor_name.clone())
+            .collect();
+        for fan in &fans {
+            transport.set_lower_thresholds(fan, lower)?;
+        }
+        self.thresholds_set = true;
+        Ok(fans)
+    }
+
+    /// Hands the fans back to the BMC even if this process never took them, e.g. after `smartfan set`.
+    pub fn force_auto(&mut self, transport: &mut dyn IpmiTransport) -> io::Result<()> {
+        self.manual_mode_set = true;
+        self.restore_auto(transport)
+    }
+
+    /// Whether [`FanControl::restore_auto`] can give the fans back to the BMC: the profile has an
+    /// auto mode command, or manual mode was never entered.
+    pub fn can_restore_auto(&self) -> bool {
+        !self.manual_mode_set || !self.profile.auto_mode.is_empty()
+    }
+
+    /// Hands the fans back to the BMC: undoes the transport's own fan settings, then sends the
+    /// profile's auto mode commands if manual mode was entered.
+    /// A profile without an auto mode command cannot hand them back: every fan is set to 100%
+    /// instead of staying at the last duty, and the result is an error.
+    pub fn restore_auto(&mut self, transport: &mut dyn IpmiTransport) -> io::Result<()> {
+        if self.transport_set {
+            transport.restore_fan_duty()?;
+            self.transport_set = false;
+            self.sent.clear();
+        }
+        if !self.manual_mode_set {
+            return Ok(());
+        }
+        if !self.can_restore_auto() {
+            // 否则风扇停在最后的转速，idle_duty 的 zone 可能只有 2%
+            match self.profile.all_zones {
+                Some(all) => self.set_zone(transport, all, 100)?,
+                None => {
+                    let zones: Vec<u8> = self.profile.zones.iter().map(|z| z.id).collect();
+                    for zone in zones {
+                        self.set_zone(transport, zone, 100)?;
+                    }
+                }
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} 没有交还 BMC 的命令，风扇保持 100%/profile {} has no auto mode command, fans left at 100%",
+                    self.profile.name, self.profile.name
+                ),
+            ));
+        }
+        for c in &self.profile.auto_mode {
+            c.send(transport, 0, 0)?;
+        }
+        self.manual_mode_set = false;
+        self.idle_zones_set = false;
+        self.sent.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::mock::MockTransport;
+    use crate::testutil;
+
+    fn config(extra: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            "mode: out-band
+server_model: Lenovo HR650X
+ipmi: {{host: bmc, username: admin, password: admin}}
+fan_speeds: []
+{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn identity(manufacturer_id: u32, manufacturer: &str, product: &str) -> ServerIdentity {
+        ServerIdentity {
+            manufacturer_id: Some(manufacturer_id),
+            manufacturer: manufacturer.to_string(),
+            product: product.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_raw_command() {
+        let c = RawCommand::try_from("0x30 0x70 0x66 0x01 {zone} {duty}".to_string()).unwrap();
+        assert_eq!(c.render(1, 40), (0x30, 0x70, vec![0x66, 0x01, 1, 40]));
+        assert_eq!(c.to_string(), "0x30 0x70 0x66 0x01 {zone} {duty}");
+        assert_eq!(RawCommand::try_from("0x2e 0x30 00 12".to_string()).unwrap().render(0, 0).2, vec![0, 12]);
+
+        assert!(RawCommand::try_from("0x30".to_string()).is_err());
+        assert!(RawCommand::try_from("{zone} 0x30".to_string()).is_err());
+        assert!(RawCommand::try_from("0x30 0x30 0x100".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_dell_profile() {
+        let profile = select(&config(""), Some(&identity(674, "DELL", "PowerEdge R730"))).unwrap();
+        assert_eq!(profile.name, "dell-poweredge");
+
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut control = FanControl::new(profile);
+        control.set_speed(&mut transport, 20, 1).unwrap();
+        control.set_speed(&mut transport, 25, 2).unwrap();
+        control.restore_auto(&mut transport).unwrap();
+
+        let requests = mock.requests();
+        // 第三方 PCIe 散热响应：进入手动时关闭，交还时打开
+        let pcie = |on: u8| (0x30, 0xce, vec![0x00, 0x16, 0x05, 0x00, 0x00, 0x00, 0x05, 0x00, on, 0x00, 0x00]);
+        assert_eq!(requests.len(), 6);
+        assert_eq!(requests[0], pcie(0x01));
+        assert_eq!(requests[1], (0x30, 0x30, vec![0x01, 0x00]));
+        assert_eq!(requests[2], (0x30, 0x30, vec![0x02, 0xff, 20]));
+        assert_eq!(requests[3], (0x30, 0x30, vec![0x02, 0xff, 25]));
+        assert_eq!(requests[4], (0x30, 0x30, vec![0x01, 0x01]));
+        assert_eq!(requests[5], pcie(0x00));
+    }
+
+    #[test]
+    fn test_resend_unchanged_duty() {
+        let profile = select(&config(""), Some(&identity(674, "DELL", "PowerEdge R730"))).unwrap();
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut control = FanControl::new(profile);
+        control.set_speed(&mut transport, 20, 1).unwrap();
+        let sent = mock.requests().len();
+
+        // 没变的转速跳过 RESEND_AFTER 次，然后重发一次，以防 BMC 重启后回到了自动模式
+        for _ in 0..RESEND_AFTER {
+            control.set_speed(&mut transport, 20, 1).unwrap();
+        }
+        assert_eq!(mock.requests().len(), sent);
+        control.set_speed(&mut transport, 20, 1).unwrap();
+        // 只重发关闭自动调速和转速，保存在 iDRAC 里的 PCIe 设置不重写
+        assert_eq!(mock.requests()[sent..], [(0x30, 0x30, vec![0x01, 0x00]), (0x30, 0x30, vec![0x02, 0xff, 20])]);
+        control.set_speed(&mut transport, 20, 1).unwrap();
+        assert_eq!(mock.requests().len(), sent + 2);
+    }
+
+    #[test]
+    fn test_supermicro_zones() {
+        let supermicro = config(
+            "zones:
+  - name: peripheral
+    fan_speeds:
+      - temp_range: [0, 100]
+        speed: 60
+",
+        );
+        let x11 = identity(10876, "Supermicro", "X11SPi-TF");
+        let profile = select(&supermicro, Some(&x11)).unwrap();
+        assert_eq!(profile.name, "supermicro");
+        assert_eq!(profile.zone("peripheral").unwrap().id, 1);
+        assert!(profile.zone("cpu").unwrap().matches("FAN2"));
+        assert!(!profile.zone("cpu").unwrap().matches("FANA"));
+
+        let sensors = testutil::sensors(&[
+            "FAN1 | 1400.000 | RPM | ok | 300.000 | 500.000 | 700.000 | 25300.000 | 25400.000 | 25500.000",
+            "FANA | 900.000 | RPM | ok | 300.000 | 500.000 | 700.000 | 25300.000 | 25400.000 | 25500.000",
+            "CPU Temp | 40.000 | degrees C | ok | 0.000 | 0.000 | 0.000 | 80.000 | 85.000 | 90.000",
+        ]);
+
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut control = FanControl::new(profile);
+        assert_eq!(control.apply_fan_thresholds(&mut transport, &sensors).unwrap(), vec!["FAN1", "FANA"]);
+        assert!(control.apply_fan_thresholds(&mut transport, &sensors).unwrap().is_empty());
+        assert_eq!(mock.thresholds()[1], ("FANA".to_string(), [0.0, 100.0, 200.0]));
+
+        control.set_speeds(&mut transport, 30, &[(1, 60)], 1).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![
+                (0x30, 0x45, vec![0x01, 0x01]),
+                (0x30, 0x70, vec![0x66, 0x01, 0, 30]),
+                (0x30, 0x70, vec![0x66, 0x01, 1, 60]),
+            ]
+        );
+
+        let unknown = config("zones: [{name: nope, fan_speeds: []}]");
+        assert_eq!(select(&unknown, Some(&x11)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_config_zones() {
+        let zones = config(
+            "zones:
+  - name: cpu
+    fans: ['^FAN[12]$']
+  - name: disks
+    id: 2
+    fans: ['^FAN[34]$']
+    curve: [[30, 20], [50, 100]]
+",
+        );
+        let x11 = identity(10876, "Supermicro", "X11SPi-TF");
+        let profile = select(&zones, Some(&x11)).unwrap();
+        assert!(!profile.zone("cpu").unwrap().matches("FAN3"));
+        assert!(profile.zone("disks").unwrap().matches("FAN3"));
+        assert_eq!(profile.zone("2").unwrap().name, "disks");
+
+        // 每个 zone 单独设置
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut control = FanControl::new(profile);
+        control.set_speeds(&mut transport, 30, &[(1, 40), (2, 80)], 1).unwrap();
+        assert_eq!(
+            mock.requests()[1..],
+            [
+                (0x30, 0x70, vec![0x66, 0x01, 0, 30]),
+                (0x30, 0x70, vec![0x66, 0x01, 1, 40]),
+                (0x30, 0x70, vec![0x66, 0x01, 2, 80]),
+            ]
+        );
+        assert_eq!(control.duty(2), Some(80));
+    }
+
+    #[test]
+    fn test_select() {
+        let hr650x = identity(19046, "Lenovo", "ThinkSystem HR650X");
+        assert_eq!(select(&config(""), Some(&hr650x)).unwrap().name, "lenovo-hr650x");
+
+        // 不认识的硬件，或者与检测结果不符的 profile，都需要 force_profile
+        let other = identity(19046, "Lenovo", "ThinkSystem SR650");
+        assert_eq!(select(&config(""), Some(&other)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(select(&config(""), None).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        let dell = config("profile: dell-poweredge");
+        assert_eq!(select(&dell, Some(&hr650x)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(select(&config("force_profile: true"), None).unwrap().name, "lenovo-hr650x");
+        let dell = config("profile: dell-poweredge\nforce_profile: true");
+        assert_eq!(select(&dell, Some(&hr650x)).unwrap().name, "dell-poweredge");
+
+        let err = select(&config("profile: nope\nforce_profile: true"), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("lenovo-hr650x"));
+
+        let custom = config(
+            "profile: lenovo-hr650x
+force_profile: true
+profiles:
+  - name: lenovo-hr650x
+    manual_mode: [0x2e 0x31 0x01]
+    set_duty: 0x2e 0x30 0x01 {zone} {duty}
+    auto_mode: [0x2e 0x31 0x00]
+",
+        );
+        let profile = select(&custom, Some(&hr650x)).unwrap();
+        assert!(profile.zones.is_empty());
+
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut control = FanControl::new(profile);
+        control.set_speed(&mut transport, 30, 2).unwrap();
+        control.set_speed(&mut transport, 35, 2).unwrap();
+        // 转速没变时不再发送
+        control.set_speed(&mut transport, 35, 2).unwrap();
+        control.restore_auto(&mut transport).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![
+                (0x2e, 0x31, vec![0x01]),
+                (0x2e, 0x30, vec![0x01, 0, 30]),
+                (0x2e, 0x30, vec![0x01, 0, 35]),
+                (0x2e, 0x31, vec![0x00]),
+            ]
+        );
+    }
+}
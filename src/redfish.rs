@@ -0,0 +1,560 @@
+//! Redfish backend: thermal and power readings from `/redfish/v1/Chassis/*`, fan settings
+//! through an OEM request.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::config::RedfishFanControl;
+use crate::ipmi::identify::ServerIdentity;
+use crate::ipmi::IpmiTransport;
+use crate::sensor_result::{SensorResult, Thresholds};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// 模板中 {duty} 的占位值
+const DUTY_MARK: &str = "__smartfan_duty__";
+
+/// Talks to a BMC over its Redfish REST API with basic authentication.
+pub struct Redfish {
+    agent: ureq::Agent,
+    base_url: String,
+    authorization: String,
+    fan_control: Option<RedfishFanControl>,
+    chassis: Option<Vec<String>>,
+    /// 改过的 zone 和第一次写入前的值，读不到时为空
+    originals: BTreeMap<u8, Option<Value>>,
+}
+
+impl Redfish {
+    /// `base_url` is the BMC root such as `https://10.0.0.2`. Most BMCs ship a self-signed
+    /// certificate, so verification is off unless `verify_tls` is set.
+    pub fn new(
+        base_url: &str,
+        username: &str,
+        password: &str,
+        verify_tls: bool,
+        fan_control: Option<RedfishFanControl>,
+    ) -> Redfish {
+        let config = ureq::Agent::config_builder()
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .http_status_as_error(false)
+            .tls_config(
+                ureq::tls::TlsConfig::builder()
+                    .disable_verification(!verify_tls)
+                    .build(),
+            )
+            .build();
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        Redfish {
+            agent: ureq::Agent::new_with_config(config),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            authorization: format!("Basic {}", credentials),
+            fan_control,
+            chassis: None,
+            originals: BTreeMap::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            format!("{}{}", self.base_url, path)
+        }
+    }
+
+    fn get(&self, path: &str) -> io::Result<Value> {
+        let mut rsp = self
+            .agent
+            .get(&self.url(path))
+            .header("Authorization", &self.authorization)
+            .header("Accept", "application/json")
+            .call()
+            .map_err(http_error)?;
+        if !rsp.status().is_success() {
+            return Err(status_error("GET", path, rsp.status().as_u16()));
+        }
+        rsp.body_mut().read_json::<Value>().map_err(http_error)
+    }
+
+    fn send(&self, method: &str, path: &str, body: &Value) -> io::Result<()> {
+        let url = self.url(path);
+        let request = match method.to_uppercase().as_str() {
+            "POST" => self.agent.post(&url),
+            "PUT" => self.agent.put(&url),
+            _ => self.agent.patch(&url),
+        };
+        let rsp = request
+            .header("Authorization", &self.authorization)
+            .send_json(body)
+            .map_err(http_error)?;
+        if !rsp.status().is_success() {
+            return Err(status_error(method, path, rsp.status().as_u16()));
+        }
+        Ok(())
+    }
+
+    fn chassis(&mut self) -> io::Result<Vec<String>> {
+        if let Some(chassis) = &self.chassis {
+            return Ok(chassis.clone());
+        }
+        let chassis = members(&self.get("/redfish/v1/Chassis")?);
+        self.chassis = Some(chassis.clone());
+        Ok(chassis)
+    }
+
+    fn read_chassis(&self, path: &str, out: &mut Vec<SensorResult>) -> io::Result<()> {
+        let chassis = self.get(path)?;
+
+        if let Some(thermal) = link(&chassis, "Thermal") {
+            let thermal = self.get(&thermal)?;
+            array(&thermal, "Temperatures").iter().filter_map(temperature).for_each(|s| out.push(s));
+            array(&thermal, "Fans").iter().filter_map(fan).for_each(|s| out.push(s));
+        } else if let Some(subsystem) = link(&chassis, "ThermalSubsystem") {
+            let subsystem = self.get(&subsystem)?;
+            if let Some(metrics) = link(&subsystem, "ThermalMetrics") {
+                let metrics = self.get(&metrics)?;
+                array(&metrics, "TemperatureReadingsCelsius")
+                    .iter()
+                    .filter_map(temperature_reading)
+                    .for_each(|s| out.push(s));
+            }
+            if let Some(fans) = link(&subsystem, "Fans") {
+                for fan_path in members(&self.get(&fans)?) {
+                    if let Some(s) = subsystem_fan(&self.get(&fan_path)?) {
+                        out.push(s);
+                    }
+                }
+            }
+        }
+
+        if let Some(power) = link(&chassis, "Power") {
+            let power = self.get(&power)?;
+            array(&power, "PowerControl").iter().filter_map(power_control).for_each(|s| out.push(s));
+        } else if let Some(metrics) = link(&chassis, "EnvironmentMetrics") {
+            let metrics = self.get(&metrics)?;
+            if let Some(watts) = metrics["PowerWatts"]["Reading"].as_f64() {
+                out.push(reading("System Power", Some(watts), "Watts", None, empty_thresholds()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks for a known OEM fan setting when none is configured.
+    /// iDRAC exposes the minimum fan speed as a manager attribute.
+    fn discover_fan_control(&mut self) -> io::Result<RedfishFanControl> {
+        for manager in members(&self.get("/redfish/v1/Managers")?) {
+            let json = self.get(&manager)?;
+            if !json["Oem"]["Dell"].is_null() {
+                let control = RedfishFanControl {
+                    method: "PATCH".to_string(),
+                    path: format!("{}/Attributes", manager),
+                    body: r#"{"Attributes": {"ThermalSettings.1.MinimumFanSpeed": {duty}}}"#.to_string(),
+                };
+                self.fan_control = Some(control.clone());
+                return Ok(control);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "BMC 没有已知的 Redfish 风扇控制接口，请配置 redfish.fan_control/no known Redfish OEM fan action, set redfish.fan_control",
+        ))
+    }
+}
+
+/// The fan request body for `zone`, with `{duty}` left as a marker, and the JSON pointer to it.
+fn fan_body(control: &RedfishFanControl, zone: u8) -> io::Result<(Value, Option<String>)> {
+    let mark = format!("\"{}\"", DUTY_MARK);
+    let body = control
+        .body
+        .replace("{zone}", &zone.to_string())
+        .replace("\"{duty}\"", &mark)
+        .replace("{duty}", &mark);
+    let body: Value = serde_json::from_str(&body).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("redfish.fan_control.body is not valid JSON: {}", e))
+    })?;
+    let pointer = find_mark(&body, String::new());
+    Ok((body, pointer))
+}
+
+fn find_mark(json: &Value, pointer: String) -> Option<String> {
+    match json {
+        Value::String(s) if s == DUTY_MARK => Some(pointer),
+        Value::Object(map) => map
+            .iter()
+            .find_map(|(k, v)| find_mark(v, format!("{}/{}", pointer, k.replace('~', "~0").replace('/', "~1")))),
+        Value::Array(items) => items.iter().enumerate().find_map(|(i, v)| find_mark(v, format!("{}/{}", pointer, i))),
+        _ => None,
+    }
+}
+
+impl Redfish {
+    fn fan_control(&mut self) -> io::Result<RedfishFanControl> {
+        match self.fan_control.clone() {
+            Some(control) => Ok(control),
+            None => self.discover_fan_control(),
+        }
+    }
+
+    /// Sends the fan request for `zone` with `value` in place of `{duty}`.
+    fn send_fan(&self, control: &RedfishFanControl, zone: u8, value: Value) -> io::Result<()> {
+        let (mut body, pointer) = fan_body(control, zone)?;
+        if let Some(slot) = pointer.and_then(|p| body.pointer_mut(&p)) {
+            *slot = value;
+        }
+        self.send(&control.method, &control.path, &body)
+    }
+}
+
+impl IpmiTransport for Redfish {
+    fn sensors(&mut self) -> io::Result<Vec<SensorResult>> {
+        let mut sensors = vec![];
+        for chassis in self.chassis()? {
+            self.read_chassis(&chassis, &mut sensors)?;
+        }
+        Ok(sensors)
+    }
+
+    fn raw(&mut self, _netfn: u8, _cmd: u8, _data: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Redfish transport cannot send raw IPMI commands",
+        ))
+    }
+
+    fn identify(&mut self) -> io::Result<ServerIdentity> {
+        let Some(system) = members(&self.get("/redfish/v1/Systems")?).into_iter().next() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Redfish service lists no systems"));
+        };
+        let system = self.get(&system)?;
+        let text = |key: &str| system[key].as_str().unwrap_or_default().trim().to_string();
+        Ok(ServerIdentity {
+            manufacturer: text("Manufacturer"),
+            product: text("Model"),
+            ..Default::default()
+        })
+    }
+
+    fn set_fan_duty(&mut self, zone: u8, duty: u8) -> io::Result<bool> {
+        let control = self.fan_control()?;
+        if !self.originals.contains_key(&zone) {
+            // 例如 iDRAC 的 MinimumFanSpeed 会一直保存，先读出原来的值，退出时写回
+            let (_, pointer) = fan_body(&control, zone)?;
+            let original = pointer.and_then(|p| self.get(&control.path).ok()?.pointer(&p).cloned());
+            if original.is_none() {
+                log::warn!("Cannot read the current fan setting from {}, it will not be restored", control.path);
+            }
+            self.originals.insert(zone, original);
+        }
+        self.send_fan(&control, zone, json!(duty))?;
+        Ok(true)
+    }
+
+    fn restore_fan_duty(&mut self) -> io::Result<()> {
+        let Some(control) = self.fan_control.clone() else {
+            return Ok(());
+        };
+        let mut unknown = vec![];
+        for (zone, original) in self.originals.clone() {
+            match original {
+                Some(value) => self.send_fan(&control, zone, value)?,
+                None => unknown.push(zone.to_string()),
+            }
+            self.originals.remove(&zone);
+        }
+        if !unknown.is_empty() {
+            return Err(io::Error::other(format!(
+                "原来的风扇设置未知，保持最后的值/original fan setting of zone {} unknown, left at the last duty",
+                unknown.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn http_error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::Io(e) => e,
+        e => io::Error::other(format!("Redfish request failed: {}", e)),
+    }
+}
+
+fn status_error(method: &str, path: &str, status: u16) -> io::Error {
+    let kind = match status {
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        404 => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("Redfish {} {} returned HTTP {}", method, path, status))
+}
+
+fn link(json: &Value, key: &str) -> Option<String> {
+    json[key]["@odata.id"].as_str().map(str::to_string)
+}
+
+fn members(json: &Value) -> Vec<String> {
+    array(json, "Members")
+        .iter()
+        .filter_map(|m| m["@odata.id"].as_str().map(str::to_string))
+        .collect()
+}
+
+fn array<'a>(json: &'a Value, key: &str) -> &'a [Value] {
+    json[key].as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+fn absent(json: &Value) -> bool {
+    json["Status"]["State"].as_str() == Some("Absent")
+}
+
+/// Redfish Health 映射成 ipmitool 的状态列
+fn health(json: &Value) -> Option<String> {
+    match json["Status"]["Health"].as_str()? {
+        "OK" => Some("ok".to_string()),
+        "Warning" => Some("nc".to_string()),
+        "Critical" => Some("cr".to_string()),
+        _ => None,
+    }
+}
+
+fn thresholds(json: &Value) -> Thresholds {
+    let t = |key: &str| json[key].as_f64();
+    Thresholds {
+        lnr: t("LowerThresholdFatal"),
+        lc: t("LowerThresholdCritical"),
+        lnc: t("LowerThresholdNonCritical"),
+        unc: t("UpperThresholdNonCritical"),
+        uc: t("UpperThresholdCritical"),
+        unr: t("UpperThresholdFatal"),
+    }
+}
+
+fn empty_thresholds() -> Thresholds {
+    Thresholds {
+        lnr: None,
+        lc: None,
+        lnc: None,
+        unc: None,
+        uc: None,
+        unr: None,
+    }
+}
+
+fn reading(name: &str, value: Option<f64>, unit: &str, status: Option<String>, thresholds: Thresholds) -> SensorResult {
+    SensorResult {
+        sensor_name: name.to_string(),
+        value,
+        unit: Some(unit.to_string()),
+        status,
+        thresholds,
+    }
+}
+
+fn temperature(json: &Value) -> Option<SensorResult> {
+    if absent(json) {
+        return None;
+    }
+    Some(reading(
+        json["Name"].as_str()?,
+        json["ReadingCelsius"].as_f64(),
+        "degrees C",
+        health(json),
+        thresholds(json),
+    ))
+}
+
+fn fan(json: &Value) -> Option<SensorResult> {
+    if absent(json) {
+        return None;
+    }
+    let name = json["Name"].as_str().or(json["FanName"].as_str())?;
+    let unit = match json["ReadingUnits"].as_str() {
+        Some("Percent") => "percent",
+        _ => "RPM",
+    };
+    Some(reading(name, json["Reading"].as_f64(), unit, health(json), thresholds(json)))
+}
+
+fn temperature_reading(json: &Value) -> Option<SensorResult> {
+    let name = json["DeviceName"]
+        .as_str()
+        .or_else(|| json["DataSourceUri"].as_str()?.rsplit('/').next())?;
+    Some(reading(name, json["Reading"].as_f64(), "degrees C", None, empty_thresholds()))
+}
+
+fn subsystem_fan(json: &Value) -> Option<SensorResult> {
+    if absent(json) {
+        return None;
+    }
+    let name = json["Name"].as_str()?;
+    let speed = &json["SpeedPercent"];
+    match speed["SpeedRPM"].as_f64() {
+        Some(rpm) => Some(reading(name, Some(rpm), "RPM", health(json), empty_thresholds())),
+        None => Some(reading(name, speed["Reading"].as_f64(), "percent", health(json), empty_thresholds())),
+    }
+}
+
+fn power_control(json: &Value) -> Option<SensorResult> {
+    let name = json["Name"].as_str().unwrap_or("System Power");
+    let watts = json["PowerConsumedWatts"].as_f64()?;
+    Some(reading(name, Some(watts), "Watts", None, empty_thresholds()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, HttpStub};
+
+    fn thermal_bmc() -> HttpStub {
+        HttpStub::start(vec![
+            ("/redfish/v1/Chassis", r#"{"Members": [{"@odata.id": "/redfish/v1/Chassis/1"}]}"#.to_string()),
+            (
+                "/redfish/v1/Chassis/1",
+                r#"{"Thermal": {"@odata.id": "/redfish/v1/Chassis/1/Thermal"},
+                    "Power": {"@odata.id": "/redfish/v1/Chassis/1/Power"}}"#
+                    .to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/Thermal",
+                r#"{"Temperatures": [
+                        {"Name": "CPU1 Temp", "ReadingCelsius": 48, "UpperThresholdCritical": 95, "Status": {"State": "Enabled", "Health": "OK"}},
+                        {"Name": "CPU2 Temp", "ReadingCelsius": null, "Status": {"State": "Absent"}}],
+                    "Fans": [{"Name": "Fan 1 Front Tach", "Reading": 5400, "ReadingUnits": "RPM", "LowerThresholdCritical": 500, "Status": {"Health": "Warning"}}]}"#
+                    .to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/Power",
+                r#"{"PowerControl": [{"Name": "Server Power Control", "PowerConsumedWatts": 182}]}"#.to_string(),
+            ),
+            ("/redfish/v1/Managers", r#"{"Members": [{"@odata.id": "/redfish/v1/Managers/iDRAC.Embedded.1"}]}"#.to_string()),
+            ("/redfish/v1/Managers/iDRAC.Embedded.1", r#"{"Oem": {"Dell": {}}}"#.to_string()),
+            (
+                "/redfish/v1/Managers/iDRAC.Embedded.1/Attributes",
+                r#"{"Attributes": {"ThermalSettings.1.MinimumFanSpeed": 20}}"#.to_string(),
+            ),
+            ("/redfish/v1/Systems", r#"{"Members": [{"@odata.id": "/redfish/v1/Systems/System.Embedded.1"}]}"#.to_string()),
+            (
+                "/redfish/v1/Systems/System.Embedded.1",
+                r#"{"Manufacturer": "Dell Inc.", "Model": "PowerEdge R730"}"#.to_string(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_thermal_and_power() {
+        let bmc = thermal_bmc();
+        let mut redfish = Redfish::new(&bmc.url(), "root", "calvin", false, None);
+        let sensors = redfish.sensors().unwrap();
+
+        assert_eq!(sensors.len(), 3);
+        assert_eq!(sensors[0].sensor_name, "CPU1 Temp");
+        assert_eq!(sensors[0].value, Some(48.0));
+        assert_eq!(sensors[0].status.as_deref(), Some("ok"));
+        assert_eq!(sensors[0].thresholds.uc, Some(95.0));
+        assert_eq!(sensors[1].unit.as_deref(), Some("RPM"));
+        assert_eq!(sensors[1].status.as_deref(), Some("nc"));
+        assert_eq!(sensors[1].thresholds.lc, Some(500.0));
+        assert_eq!(sensors[2].value, Some(182.0));
+
+        assert_eq!(crate::sensor::get_max_temperature(&sensors), 48.0);
+        assert_eq!(crate::sensor::get_fans_speed(&sensors), vec![("Fan 1 Front Tach".to_string(), 5400.0)]);
+        assert_eq!(crate::sensor::get_power(&sensors).len(), 1);
+
+        let auth = bmc.requests()[0].header("Authorization").unwrap().to_string();
+        assert_eq!(auth, "Basic cm9vdDpjYWx2aW4=");
+
+        assert_eq!(redfish.identify().unwrap().to_string(), "Dell Inc. PowerEdge R730");
+    }
+
+    #[test]
+    fn test_thermal_subsystem() {
+        let bmc = HttpStub::start(vec![
+            ("/redfish/v1/Chassis", r#"{"Members": [{"@odata.id": "/redfish/v1/Chassis/1"}]}"#.to_string()),
+            (
+                "/redfish/v1/Chassis/1",
+                r#"{"ThermalSubsystem": {"@odata.id": "/redfish/v1/Chassis/1/ThermalSubsystem"},
+                    "EnvironmentMetrics": {"@odata.id": "/redfish/v1/Chassis/1/EnvironmentMetrics"}}"#
+                    .to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/ThermalSubsystem",
+                r#"{"ThermalMetrics": {"@odata.id": "/redfish/v1/Chassis/1/ThermalSubsystem/ThermalMetrics"},
+                    "Fans": {"@odata.id": "/redfish/v1/Chassis/1/ThermalSubsystem/Fans"}}"#
+                    .to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/ThermalSubsystem/ThermalMetrics",
+                r#"{"TemperatureReadingsCelsius": [{"DeviceName": "CPU1 Temp", "Reading": 51.5}]}"#.to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/ThermalSubsystem/Fans",
+                r#"{"Members": [{"@odata.id": "/redfish/v1/Chassis/1/ThermalSubsystem/Fans/0"}]}"#.to_string(),
+            ),
+            (
+                "/redfish/v1/Chassis/1/ThermalSubsystem/Fans/0",
+                r#"{"Name": "Fan 0", "SpeedPercent": {"Reading": 40, "SpeedRPM": 6100}}"#.to_string(),
+            ),
+            ("/redfish/v1/Chassis/1/EnvironmentMetrics", r#"{"PowerWatts": {"Reading": 210}}"#.to_string()),
+        ]);
+        let mut redfish = Redfish::new(&bmc.url(), "admin", "admin", false, None);
+        let sensors = redfish.sensors().unwrap();
+
+        let values: Vec<(&str, Option<f64>)> = sensors.iter().map(|s| (s.sensor_name.as_str(), s.value)).collect();
+        assert_eq!(values, vec![("CPU1 Temp", Some(51.5)), ("Fan 0", Some(6100.0)), ("System Power", Some(210.0))]);
+    }
+
+    #[test]
+    fn test_set_fan_duty() {
+        let bmc = thermal_bmc();
+
+        // iDRAC 自动发现
+        let mut redfish = Redfish::new(&bmc.url(), "root", "calvin", false, None);
+        assert!(redfish.set_fan_duty(0, 35).unwrap());
+        let patch = bmc.requests().into_iter().find(|r| r.method == "PATCH").unwrap();
+        assert_eq!(patch.path, "/redfish/v1/Managers/iDRAC.Embedded.1/Attributes");
+        let body: Value = serde_json::from_str(&patch.body).unwrap();
+        assert_eq!(body["Attributes"]["ThermalSettings.1.MinimumFanSpeed"], 35);
+
+        // 配置的 OEM 请求
+        let control = RedfishFanControl {
+            method: "POST".to_string(),
+            path: "/redfish/v1/Chassis/1/Actions/Oem/FanControl".to_string(),
+            body: r#"{"Zone": {zone}, "DutyPercent": {duty}}"#.to_string(),
+        };
+        let mut redfish = Redfish::new(&bmc.url(), "root", "calvin", false, Some(control));
+        redfish.set_fan_duty(2, 50).unwrap();
+        let post = bmc.requests().into_iter().find(|r| r.method == "POST").unwrap();
+        let body: Value = serde_json::from_str(&post.body).unwrap();
+        assert_eq!(body, serde_json::json!({"Zone": 2, "DutyPercent": 50}));
+        // 动作接口读不到原来的值
+        assert!(redfish.restore_fan_duty().is_err());
+    }
+
+    #[test]
+    fn test_restore_fan_duty() {
+        let bmc = thermal_bmc();
+        let dell = testutil::builtin_profile("dell-poweredge");
+        let mut redfish = Redfish::new(&bmc.url(), "root", "calvin", false, None);
+        let mut fan_control = crate::profile::FanControl::new(dell);
+        fan_control.set_speed(&mut redfish, 35, 2).unwrap();
+        fan_control.set_speed(&mut redfish, 40, 2).unwrap();
+        fan_control.restore_auto(&mut redfish).unwrap();
+
+        // 第一次写入前读出的 20 在交还时写回
+        let patches: Vec<Value> = bmc
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "PATCH")
+            .map(|r| serde_json::from_str(&r.body).unwrap())
+            .collect();
+        let speeds: Vec<&Value> = patches.iter().map(|b| &b["Attributes"]["ThermalSettings.1.MinimumFanSpeed"]).collect();
+        assert_eq!(speeds, vec![35, 40, 20]);
+        // 已经恢复，再次交还不发送
+        fan_control.restore_auto(&mut redfish).unwrap();
+        assert_eq!(bmc.requests().iter().filter(|r| r.method == "PATCH").count(), 3);
+    }
+}
@@ -0,0 +1,231 @@
+//! Fail-safe policy: full speed whenever the readings cannot be trusted, and the BMC's own
+//! fan control back when smartfan stops, however it stops.
+
+use std::mem;
+
+use derive_more::Display;
+use log::Level;
+
+use crate::config::Config;
+use crate::ipmi::IpmiTransport;
+use crate::profile::FanControl;
+use crate::sensor;
+use crate::sensor_result::SensorResult;
+
+/// Why the fans are at full speed.
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum Fault {
+    #[display("{} consecutive sensor read failures", _0)]
+    ReadFailures(u32),
+    #[display("no CPU temperature reading")]
+    NoCpuTemperature,
+    #[display("{} at {} reached its critical threshold {}", _0, _1, _2)]
+    OverCritical(String, f64, f64),
+}
+
+impl Fault {
+    /// The same fault for logging purposes: the counter and readings may change while it lasts.
+    fn same(&self, other: &Fault) -> bool {
+        match (self, other) {
+            (Fault::OverCritical(a, ..), Fault::OverCritical(b, ..)) => a == b,
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Display)]
+pub enum SafetyEvent {
+    #[display("Fail-safe engaged, fans at 100%: {}", _0)]
+    Engaged(Fault),
+    #[display("Fail-safe released, was: {}", _0)]
+    Released(Fault),
+    #[display("Fan control handed back to the BMC")]
+    AutoRestored,
+    #[display("Failed to restore automatic fan control: {}", _0)]
+    RestoreFailed(String),
+}
+
+impl SafetyEvent {
+    pub fn level(&self) -> Level {
+        match self {
+            SafetyEvent::Engaged(_) | SafetyEvent::RestoreFailed(_) => Level::Error,
+            SafetyEvent::Released(_) => Level::Warn,
+            SafetyEvent::AutoRestored => Level::Info,
+        }
+    }
+}
+
+/// Tracks the current fault. Every change is returned as an event, once.
+#[derive(Debug)]
+pub struct Safety {
+    max_read_failures: u32,
+    read_failures: u32,
+    fault: Option<Fault>,
+}
+
+impl Safety {
+    pub fn new(max_read_failures: u32) -> Safety {
+        Safety {
+            max_read_failures,
+            read_failures: 0,
+            fault: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Safety {
+        Safety::new(config.safety.max_read_failures)
+    }
+
+    /// True while the fans must run at full speed.
+    pub fn engaged(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    /// A failed sensor read. Faults once `max_read_failures` reads in a row have failed.
+    pub fn read_failed(&mut self) -> Option<SafetyEvent> {
+        self.read_failures += 1;
+        if self.read_failures < self.max_read_failures {
+            return None;
+        }
+        self.set(Some(Fault::ReadFailures(self.read_failures)))
+    }
+
+    /// A successful read: faults when no CPU temperature is reported or a sensor is at or
+    /// above its upper critical threshold.
+    pub fn check(&mut self, sensor_results: &[SensorResult]) -> Option<SafetyEvent> {
+        self.read_failures = 0;
+        let critical = sensor_results.iter().find_map(|x| match (x.value, x.thresholds.uc) {
+            (Some(v), Some(uc)) if v >= uc => Some(Fault::OverCritical(x.sensor_name.clone(), v, uc)),
+            _ => None,
+        });
+        let fault = if !sensor::has_cpu_temperature(sensor_results) {
+            Some(Fault::NoCpuTemperature)
+        } else {
+            critical
+        };
+        self.set(fault)
+    }
+
+    fn set(&mut self, fault: Option<Fault>) -> Option<SafetyEvent> {
+        let event = match (&self.fault, &fault) {
+            (None, None) => None,
+            (Some(old), Some(new)) if old.same(new) => None,
+            (_, Some(new)) => Some(SafetyEvent::Engaged(new.clone())),
+            (Some(old), None) => Some(SafetyEvent::Released(old.clone())),
+        };
+        self.fault = fault;
+        event
+    }
+}
+
+/// Owns the transport and fan control while the loop runs, and hands the fans back to the
+/// BMC when dropped: after a normal exit, on SIGINT or SIGTERM, and while unwinding from a panic.
+pub struct AutoRestore {
+    pub transport: Box<dyn IpmiTransport>,
+    pub fan_control: FanControl,
+}
+
+impl AutoRestore {
+    /// Hands the fans back now. Profiles without an auto mode command leave them at 100%,
+    /// which is reported as a failure.
+    pub fn restore(&mut self) -> SafetyEvent {
+        match self.fan_control.restore_auto(self.transport.as_mut()) {
+            Ok(()) => SafetyEvent::AutoRestored,
+            Err(e) => SafetyEvent::RestoreFailed(e.to_string()),
+        }
+    }
+}
+
+impl Drop for AutoRestore {
+    fn drop(&mut self) {
+        // UI 可能已经退出，只能写日志
+        let event = self.restore();
+        log::log!(event.level(), "{}", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::mock::MockTransport;
+    use crate::testutil;
+
+    #[test]
+    fn test_transitions() {
+        let ok = testutil::sensors(&["CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000"]);
+        let hot = testutil::sensors(&[
+            "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "PCH_Temp | 91.000 | degrees C | cr | na | na | na | 85.000 | 90.000 | 95.000",
+        ]);
+        let no_cpu = testutil::sensors(&["CPU1_Temp | na | degrees C | ns | na | na | na | na | na | na"]);
+        let mut safety = Safety::new(3);
+
+        assert_eq!(safety.check(&ok), None);
+        assert!(safety.read_failed().is_none());
+        assert!(safety.read_failed().is_none());
+        assert_eq!(safety.read_failed(), Some(SafetyEvent::Engaged(Fault::ReadFailures(3))));
+        // 同一个故障只记录一次
+        assert_eq!(safety.read_failed(), None);
+        assert!(safety.engaged());
+
+        assert_eq!(safety.check(&ok), Some(SafetyEvent::Released(Fault::ReadFailures(4))));
+        assert_eq!(
+            safety.check(&hot),
+            Some(SafetyEvent::Engaged(Fault::OverCritical("PCH_Temp".to_string(), 91.0, 90.0)))
+        );
+        assert_eq!(safety.check(&hot), None);
+        assert_eq!(safety.check(&no_cpu), Some(SafetyEvent::Engaged(Fault::NoCpuTemperature)));
+        assert!(!safety.check(&ok).unwrap().to_string().is_empty());
+        assert!(!safety.engaged());
+    }
+
+    #[test]
+    fn test_restore_on_panic() {
+        let mock = MockTransport::new();
+        let dell = testutil::builtin_profile("dell-poweredge");
+        let result = std::panic::catch_unwind(|| {
+            let mut fans = AutoRestore {
+                transport: Box::new(mock.clone()),
+                fan_control: FanControl::new(dell),
+            };
+            fans.fan_control.set_speed(fans.transport.as_mut(), 30, 1).unwrap();
+            panic!("loop died");
+        });
+        assert!(result.is_err());
+        let requests = mock.requests();
+        assert_eq!(requests[requests.len() - 2], (0x30, 0x30, vec![0x01, 0x01]));
+        assert_eq!(requests[requests.len() - 1].1, 0xce);
+    }
+
+    #[test]
+    fn test_restore_on_panic_without_auto_mode() {
+        let mock = MockTransport::new();
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let result = std::panic::catch_unwind(|| {
+            let mut fans = AutoRestore {
+                transport: Box::new(mock.clone()),
+                fan_control: FanControl::new(hr650x),
+            };
+            // 单 CPU 时 4-6 号风扇为 2%
+            fans.fan_control.set_speed(fans.transport.as_mut(), 30, 1).unwrap();
+            assert!(mock.requests().contains(&(0x2e, 0x30, vec![0x00, 4, 2])));
+            panic!("loop died");
+        });
+        assert!(result.is_err());
+        // 没有交还命令，全部风扇 100%
+        assert_eq!(mock.requests().last(), Some(&(0x2e, 0x30, vec![0x00, 0, 100])));
+
+        let hr650x = testutil::builtin_profile("lenovo-hr650x");
+        let mut fans = AutoRestore {
+            transport: Box::new(MockTransport::new()),
+            fan_control: FanControl::new(hr650x),
+        };
+        assert_eq!(fans.restore(), SafetyEvent::AutoRestored);
+        fans.fan_control.set_speed(fans.transport.as_mut(), 30, 2).unwrap();
+        assert!(matches!(fans.restore(), SafetyEvent::RestoreFailed(_)));
+    }
+}
@@ -1,109 +1,133 @@
 use std::io;
-use std::io::ErrorKind;
-use crate::{config, Message};
-use std::process::Command;
-use tokio::sync::mpsc::Sender;
+use std::time::Instant;
+use crate::config;
+use crate::controller::Controller;
+use crate::curve::FanCurve;
+use crate::ipmi::IpmiTransport;
+use crate::profile::FanControl;
 use crate::sensor_result::SensorResult;
 use regex::Regex;
 
-pub fn get_power(sensor_results: &Vec<SensorResult>) -> Vec<(String,f64)> {
+pub fn get_power(sensor_results: &[SensorResult]) -> Vec<(String,f64)> {
     let mut data = Vec::new();
     sensor_results.iter()
-        .filter(|&x| x.sensor_name.contains("_Power"))
+        .filter(|&x| x.sensor_name.contains("_Power") || (x.unit.as_deref() == Some("Watts") && (x.sensor_name.contains("Power") || x.sensor_name.contains("Pwr"))))
         .for_each(|x| {
-            let mut val: f64 = 0.0;
-            if x.value.is_some() { val = x.value.unwrap() };
-            data.push((x.sensor_name.clone().replace("_Power", ""), val));
+            data.push((x.sensor_name.clone().replace("_Power", ""), x.value.unwrap_or(0.0)));
         });
     data
 }
 
-pub fn get_active_cpu_num(sensor_results: &Vec<SensorResult>) -> (usize, usize) {
+/// Dell 的 CPU 温度传感器只叫 `Temp`，按出现顺序对应 CPU1、CPU2
+fn is_dell_cpu_temp(sensor_name: &str) -> bool {
+    sensor_name == "Temp"
+}
+
+fn is_cpu_temp(sensor_name: &str) -> bool {
+    (sensor_name.contains("CPU") && sensor_name.contains("Temp")) || is_dell_cpu_temp(sensor_name)
+}
+
+pub fn get_active_cpu_num(sensor_results: &[SensorResult]) -> (usize, usize) {
     let mut num = 0;
     let mut max_num = 2;
+    let mut dell_cpu_id = 0;
     let cpu_re = Regex::new(r"(?i)(CPU|Processor|Proc)[_ ]?(\d+)").unwrap();
     sensor_results.iter()
         .filter(|&x|x.sensor_name.contains("Temp") && !x.sensor_name.contains("VR"))
         .for_each(|x| {
-            if let Some(caps) = cpu_re.captures(&x.sensor_name) {
-                if let Some(num_str) = caps.get(2) {
-                    let current_id: usize = num_str.as_str().parse().unwrap();
-                    max_num = max_num.max(current_id);
-                    match x.value {
-                        Some(v) => {
-                            if v > 0.0 {
-                                num = num.max(current_id);
-                            }
-                        }
-                        None => ()
-                    }
+            let current_id = if is_dell_cpu_temp(&x.sensor_name) {
+                dell_cpu_id += 1;
+                dell_cpu_id
+            } else if let Some(num_str) = cpu_re.captures(&x.sensor_name).and_then(|caps| caps.get(2)) {
+                num_str.as_str().parse().unwrap()
+            } else {
+                return;
+            };
+            max_num = max_num.max(current_id);
+            if let Some(v) = x.value {
+                if v > 0.0 {
+                    num = num.max(current_id);
                 }
             }
         });
     (num, max_num)
 }
 
-pub fn get_max_temperature(sensor_results: &Vec<SensorResult>) -> f64 {
+/// True when at least one CPU reports a temperature.
+pub fn has_cpu_temperature(sensor_results: &[SensorResult]) -> bool {
+    sensor_results
+        .iter()
+        .any(|x| is_cpu_temp(&x.sensor_name) && x.value.is_some_and(|v| v > 0.0))
+}
+
+pub fn get_max_temperature(sensor_results: &[SensorResult]) -> f64 {
     let mut max_temp = 0.0;
     sensor_results.iter()
-        .filter(|&x| {x.sensor_name.contains("CPU") && x.sensor_name.contains("Temp")})
+        .filter(|&x| is_cpu_temp(&x.sensor_name))
         .for_each(|x| {
-            if x.value.is_some() && x.value.unwrap() > max_temp {
-                max_temp = x.value.unwrap();
+            if let Some(v) = x.value {
+                if v > max_temp {
+                    max_temp = v;
+                }
             }
         });
     max_temp
 }
 
-pub fn get_fans_speed(sensor_results: &Vec<SensorResult>) -> Vec<(String,f64)> {
+pub(crate) fn is_fan(x: &SensorResult) -> bool {
+    (x.sensor_name.contains("FAN") && x.sensor_name.contains("Speed")) || x.unit.as_deref() == Some("RPM")
+}
+
+fn fan_label(sensor_name: &str) -> String {
+    sensor_name.replace("FAN", "").replace("_Speed", "").replace(" RPM", "")
+}
+
+pub fn get_fans_speed(sensor_results: &[SensorResult]) -> Vec<(String,f64)> {
     let mut fan_speeds = Vec::new();
     sensor_results.iter()
-        .filter(|&x| x.sensor_name.contains("FAN") && x.sensor_name.contains("Speed"))
+        .filter(|&x| is_fan(x))
         .for_each(|x| {
-            let mut speed: f64 = 0.0;
-            if x.value.is_some() { speed = x.value.unwrap() };
-            fan_speeds.push((x.sensor_name.clone().replace("FAN", "").replace("_Speed", ""), speed));
+            fan_speeds.push((fan_label(&x.sensor_name), x.value.unwrap_or(0.0)));
         });
     fan_speeds
 }
 
-pub fn get_all_sensor_data(ipmi_tool_cmd: &str) -> io::Result<Vec<SensorResult>> {
-    let cmd = format!("{} sensor", ipmi_tool_cmd);
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(&["/C", &cmd])
-            .output()?
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()?
-    };
-
-    if !output.status.success() {
-        let msg = format!(
-            "Error executing command: {}. Error: {}",
-            cmd,
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return Err(io::Error::new(ErrorKind::Other, msg));
-    }
+/// A zone's name, the duty last sent to it and its fans with their speed.
+pub type ZoneFans = (String, Option<u8>, Vec<(String, f64)>);
 
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let lines = output_str.lines();
-
-    // CPU1_Temp        | 34.000     | degrees C  | ok    | na        | na        | na        | 93.000    | 100.000   | 105.000
-    // CPU2_Temp        | 0.000      | degrees C  | ok    | na        | na        | na        | 100.000   | 102.000   | 104.000
-    // CPU1_VR_Temp     | 30.000     | degrees C  | ok    | na        | na        | na        | 112.000   | 123.000   | 133.000
-    // CPU2_VR_Temp     | 15.000     | degrees C  | ok    | na        | na        | na        | 112.000   | 123.000   | 133.000
-    let mut sensor_data = vec![];
-    for line in lines {
-        if let Ok(d) = SensorResult::from_line(line) {
-            sensor_data.push(d);
+/// Fans grouped by the zone they belong to. Fans outside every zone end up in a group called `-`.
+pub fn get_zone_fans(sensor_results: &[SensorResult], fan_control: &FanControl) -> Vec<ZoneFans> {
+    let profile = fan_control.profile();
+    if profile.zones.is_empty() {
+        let duty = fan_control.duty(profile.all_zones.unwrap_or(0));
+        return vec![("all".to_string(), duty, get_fans_speed(sensor_results))];
+    }
+    let mut groups: Vec<ZoneFans> = profile
+        .zones
+        .iter()
+        .map(|z| {
+            let name = if z.name.is_empty() { z.id.to_string() } else { z.name.clone() };
+            (name, fan_control.duty(z.id), vec![])
+        })
+        .collect();
+    let mut other = vec![];
+    for x in sensor_results.iter().filter(|&x| is_fan(x)) {
+        let fan = (fan_label(&x.sensor_name), x.value.unwrap_or(0.0));
+        match profile.zones.iter().position(|z| z.matches(&x.sensor_name)) {
+            Some(i) => groups[i].2.push(fan),
+            None => other.push(fan),
         }
     }
+    // 没有风扇的 zone 不显示
+    groups.retain(|(_, _, fans)| !fans.is_empty());
+    if !other.is_empty() {
+        groups.push(("-".to_string(), None, other));
+    }
+    groups
+}
 
-    Ok(sensor_data)
+pub fn get_all_sensor_data(transport: &mut dyn IpmiTransport) -> io::Result<Vec<SensorResult>> {
+    transport.sensors()
 }
 
 pub fn extract_temperature(temp_str: &str) -> Option<f64> {
@@ -113,62 +137,203 @@ pub fn extract_temperature(temp_str: &str) -> Option<f64> {
         .and_then(|s| s.parse::<f64>().ok())
 }
 
+/// 按服务器的风扇配置设置转速，只有一个 CPU 时另一个 CPU 的风扇使用 idle_duty。
+/// zone_speeds 中的 zone 使用各自的转速
 pub fn set_fan_speed(
     speed: u8,
-    ipmi_tool_cmd: &str,
+    zone_speeds: &[(u8, u8)],
+    transport: &mut dyn IpmiTransport,
     cpu_num: usize,
-    cpu2_fan_speed_set: &mut bool,
+    fan_control: &mut FanControl,
 ) -> io::Result<()> {
-    let delimiter = if cfg!(target_os = "windows") {
-        "&"
-    } else {
-        ";"
-    };
-
-    let cmd = if cpu_num == 1 {
-        let mut cmd = format!(
-            "{} raw 0x2e 0x30 00 01 {}{} {} raw 0x2e 0x30 00 02 {}{} {} raw 0x2e 0x30 00 03 {}",
-            ipmi_tool_cmd, speed, delimiter, ipmi_tool_cmd, speed, delimiter, ipmi_tool_cmd, speed
-        );
-
-        if !*cpu2_fan_speed_set {
-            cmd.push_str(&format!(
-                "{} {} raw 0x2e 0x30 00 04 02 {}{} raw 0x2e 0x30 00 05 02 {} {} raw 0x2e 0x30 00 06 02",
-                delimiter, ipmi_tool_cmd, delimiter, ipmi_tool_cmd, delimiter, ipmi_tool_cmd
-            ));
-            *cpu2_fan_speed_set = true;
-        }
+    fan_control.set_speeds(transport, speed, zone_speeds, cpu_num)
+}
 
-        cmd
-    } else {
-        format!("{} raw 0x2e 0x30 00 00 {}", ipmi_tool_cmd, speed)
-    };
-
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(&["/C", &cmd])
-            .output()
-            .expect("Failed to execute command")
-    } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&cmd)
-            .output()
-            .expect("Failed to execute command")
-    };
-
-    if !output.status.success() {
-        return Err(io::Error::new(ErrorKind::Other, format!("Error executing command: {}. Error: {}", cmd, String::from_utf8_lossy(&output.stderr))));
+/// (zone id, speed) for every zone in `config.zones`, each following its own curve and inputs.
+/// Zones without a curve of their own use `fan_speeds`.
+pub fn get_zone_speeds(
+    sensor_results: &[SensorResult],
+    cpu_temp: f64,
+    config: &config::Config,
+    fan_control: &FanControl,
+    controller: &mut Controller,
+    now: Instant,
+) -> Vec<(u8, u8)> {
+    config
+        .zones
+        .iter()
+        .filter_map(|z| {
+            let zone = fan_control.profile().zone(&z.name)?;
+            let curve = if z.fan_curve.is_empty() { &config.fan_curve } else { &z.fan_curve };
+            let speed = get_inputs_speed(&z.name, curve, &z.inputs, sensor_results, cpu_temp, controller, now);
+            Some((zone.id, speed))
+        })
+        .collect()
+}
+
+/// The main speed: the PID output or the main curve, raised by any of `config.inputs`.
+pub fn get_main_speed(
+    config: &config::Config,
+    sensor_results: &[SensorResult],
+    cpu_temp: f64,
+    controller: &mut Controller,
+    now: Instant,
+) -> u8 {
+    if controller.setpoint().is_none() {
+        return get_inputs_speed("", &config.fan_curve, &config.inputs, sensor_results, cpu_temp, controller, now);
+    }
+    // PID 按 CPU 温度调节，inputs 只能在此基础上升速
+    let speed = controller.update_main(&config.fan_curve, cpu_temp, now);
+    if config.inputs.is_empty() {
+        return speed;
     }
+    speed.max(get_inputs_speed("", &config.fan_curve, &config.inputs, sensor_results, cpu_temp, controller, now))
+}
 
-    Ok(())
+/// The highest output of the curves fed by `inputs`. Without inputs, or when none of their
+/// sensors has a reading, `curve` follows the hottest CPU.
+pub fn get_inputs_speed(
+    key: &str,
+    curve: &FanCurve,
+    inputs: &[config::InputConfig],
+    sensor_results: &[SensorResult],
+    cpu_temp: f64,
+    controller: &mut Controller,
+    now: Instant,
+) -> u8 {
+    let speeds: Vec<u8> = inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, input)| {
+            let temp = get_input_temperature(sensor_results, input)?;
+            let input_curve = if input.fan_curve.is_empty() { curve } else { &input.fan_curve };
+            Some(controller.update(&format!("{}/{}", key, i), input_curve, temp, now))
+        })
+        .collect();
+    match speeds.into_iter().max() {
+        Some(speed) => speed,
+        None => controller.update(key, curve, cpu_temp, now),
+    }
 }
 
-pub fn get_fan_speed(temp: f64, fan_speeds: &[config::FanSpeed]) -> u8 {
-    for fan_speed in fan_speeds {
-        if fan_speed.temp_range[0] <= temp && temp < fan_speed.temp_range[1] {
-            return fan_speed.speed;
-        }
+/// The hottest sensor matching `input.sensor`, scaled by its weight and offset.
+pub fn get_input_temperature(sensor_results: &[SensorResult], input: &config::InputConfig) -> Option<f64> {
+    let re = Regex::new(&input.sensor).ok()?;
+    sensor_results
+        .iter()
+        .filter(|x| re.is_match(&x.sensor_name))
+        .filter_map(|x| x.value)
+        .map(|v| v * input.weight + input.offset)
+        .max_by(|a, b| a.total_cmp(b))
+}
+
+pub fn get_fan_speed(temp: f64, fan_curve: &FanCurve) -> u8 {
+    fan_curve.speed(temp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipmi::mock::MockTransport;
+    use crate::testutil;
+
+    const NETFN_LENOVO_OEM: u8 = 0x2e;
+    const CMD_SET_FAN_SPEED: u8 = 0x30;
+
+    fn hr650x() -> FanControl {
+        FanControl::new(testutil::builtin_profile("lenovo-hr650x"))
+    }
+
+    #[test]
+    fn test_set_fan_speed_single_cpu() {
+        let mock = MockTransport::new();
+        let mut transport = mock.clone();
+        let mut fan_control = hr650x();
+
+        set_fan_speed(30, &[], &mut transport, 1, &mut fan_control).unwrap();
+        let zones: Vec<(u8, u8)> = mock.requests().iter().map(|(_, _, d)| (d[1], d[2])).collect();
+        assert_eq!(zones, vec![(1, 30), (2, 30), (3, 30), (4, 2), (5, 2), (6, 2)]);
+
+        // CPU2 的风扇只需要设置一次
+        mock.clear_requests();
+        set_fan_speed(40, &[], &mut transport, 1, &mut fan_control).unwrap();
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_dell_sensor_names() {
+        let sensors = testutil::sensors(&[
+            "Inlet Temp | 24.000 | degrees C | ok | na | -7.000 | 3.000 | 38.000 | 42.000 | na",
+            "Exhaust Temp | 61.000 | degrees C | ok | na | 3.000 | 8.000 | 70.000 | 75.000 | na",
+            "Temp | 48.000 | degrees C | ok | na | 3.000 | 8.000 | 83.000 | 88.000 | na",
+            "Temp | 0.000 | degrees C | ok | na | 3.000 | 8.000 | 83.000 | 88.000 | na",
+            "Fan1 RPM | 3600.000 | RPM | ok | na | 360.000 | 600.000 | na | na | na",
+            "Pwr Consumption | 112.000 | Watts | ok | na | na | na | 896.000 | 980.000 | na",
+        ]);
+
+        assert_eq!(get_max_temperature(&sensors), 48.0);
+        assert_eq!(get_active_cpu_num(&sensors), (1, 2));
+        assert_eq!(get_fans_speed(&sensors), vec![("Fan1".to_string(), 3600.0)]);
+        assert_eq!(get_power(&sensors), vec![("Pwr Consumption".to_string(), 112.0)]);
+    }
+
+    #[test]
+    fn test_inputs() {
+        let sensors = testutil::sensors(&[
+            "CPU1_Temp | 45.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+            "NVMe0_Temp | 48.000 | degrees C | ok | na | na | na | 70.000 | 75.000 | na",
+            "NVMe1_Temp | 52.000 | degrees C | ok | na | na | na | 70.000 | 75.000 | na",
+            "Inlet_Temp | na | degrees C | na | na | na | na | na | na | na",
+        ]);
+        let input = |yaml: &str| -> config::InputConfig { serde_yaml::from_str(yaml).unwrap() };
+        let curve = FanCurve {
+            fan_speeds: vec![],
+            curve: vec![(40.0, 20), (80.0, 100)],
+        };
+        let mut controller = Controller::default();
+        let now = Instant::now();
+
+        let nvme = input("{sensor: ^NVMe, offset: 20}");
+        assert_eq!(get_input_temperature(&sensors, &nvme), Some(72.0));
+        assert_eq!(get_input_temperature(&sensors, &input("{sensor: Inlet_Temp}")), None);
+
+        // 硬盘比 CPU 热，由硬盘决定转速
+        let inputs = vec![input("{sensor: CPU1_Temp}"), nvme];
+        assert_eq!(get_inputs_speed("", &curve, &inputs, &sensors, 45.0, &mut controller, now), 84);
+        // 输入自己的曲线
+        let inputs = vec![input("{sensor: CPU1_Temp}"), input("{sensor: NVMe1_Temp, weight: 0.5, curve: [[20, 90], [30, 100]]}")];
+        assert_eq!(get_inputs_speed("z", &curve, &inputs, &sensors, 45.0, &mut controller, now), 96);
+        // 都没有读数时按 CPU 温度
+        let inputs = vec![input("{sensor: Inlet_Temp}")];
+        assert_eq!(get_inputs_speed("y", &curve, &inputs, &sensors, 45.0, &mut controller, now), 30);
+    }
+
+    #[test]
+    fn test_zone_fans() {
+        let lines: Vec<String> = (1..=6)
+            .map(|i| format!("FAN{}_Speed | {}.000 | RPM | ok | na | na | na | na | na | na", i, 3000 + i * 100))
+            .chain(["SYS_FAN | 900.000 | RPM | ok | na | na | na | na | na | na".to_string()])
+            .collect();
+        let sensors = testutil::sensors(&lines);
+        let mut transport = MockTransport::new();
+        let mut fan_control = hr650x();
+        set_fan_speed(30, &[(2, 50)], &mut transport, 1, &mut fan_control).unwrap();
+
+        let zones = get_zone_fans(&sensors, &fan_control);
+        assert_eq!(zones.len(), 7);
+        assert_eq!(zones[0], ("FAN1".to_string(), Some(30), vec![("1".to_string(), 3100.0)]));
+        assert_eq!(zones[1].1, Some(50));
+        assert_eq!(zones[5].1, Some(2));
+        assert_eq!(zones[6], ("-".to_string(), None, vec![("SYS_".to_string(), 900.0)]));
+    }
+
+    #[test]
+    fn test_set_fan_speed_error() {
+        let mock = MockTransport::new();
+        mock.push_completion_code(NETFN_LENOVO_OEM, CMD_SET_FAN_SPEED, 0xd4);
+        let mut transport = mock.clone();
+        let mut fan_control = hr650x();
+        assert!(set_fan_speed(30, &[], &mut transport, 2, &mut fan_control).is_err());
+        assert_eq!(mock.requests(), vec![(NETFN_LENOVO_OEM, CMD_SET_FAN_SPEED, vec![0, 0, 30])]);
     }
-    100
 }
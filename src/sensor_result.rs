@@ -1,7 +1,6 @@
-use std::str::FromStr;
 use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SensorResult {
     pub sensor_name: String,
     pub value: Option<f64>,       // 当前值（"na" 转换为 None）
@@ -10,7 +9,7 @@ pub struct SensorResult {
     pub thresholds: Thresholds,  // 封装所有阈值
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Thresholds {
     pub lnr: Option<f64>,  // Lower Non-Recoverable
     pub lc: Option<f64>,   // Lower Critical
@@ -0,0 +1,333 @@
+//! Pushes every iteration to InfluxDB or Graphite, for sites without Prometheus. Each sink
+//! buffers the points it could not deliver and retries them with the next iteration, from a
+//! thread of its own so an endpoint that is down never holds up the loop.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::Level;
+use tokio::sync::mpsc::Sender;
+
+use crate::config::{SinkConfig, SinkKind};
+use crate::control::Reading;
+use crate::Message;
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One measurement of an iteration, e.g. a sensor value or the duty of a zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    /// sensor、duty 或 loop
+    pub measurement: &'static str,
+    /// 区分同一 measurement 的标签，例如 ("sensor", "CPU1_Temp")
+    pub key: Option<(&'static str, String)>,
+    pub fields: Vec<(&'static str, f64)>,
+    /// unix 秒
+    pub time: i64,
+}
+
+/// The points for one iteration: every sensor with a value, the duty of each zone and the
+/// temperature, speed and setpoint of the loop.
+pub fn points(time: i64, reading: &Reading, duties: &[(u8, u8)], setpoint: Option<f64>) -> Vec<Point> {
+    let mut points: Vec<Point> = reading
+        .sensors
+        .iter()
+        .filter_map(|s| {
+            Some(Point {
+                measurement: "sensor",
+                key: Some(("sensor", s.sensor_name.clone())),
+                fields: vec![("value", s.value?)],
+                time,
+            })
+        })
+        .collect();
+    points.extend(duties.iter().map(|(zone, duty)| Point {
+        measurement: "duty",
+        key: Some(("zone", zone.to_string())),
+        fields: vec![("duty", *duty as f64)],
+        time,
+    }));
+    let mut fields = vec![("temperature", reading.temperature)];
+    if let Some(speed) = reading.speed {
+        fields.push(("speed", speed as f64));
+    }
+    if let Some(setpoint) = setpoint {
+        fields.push(("setpoint", setpoint));
+    }
+    points.push(Point {
+        measurement: "loop",
+        key: None,
+        fields,
+        time,
+    });
+    points
+}
+
+pub trait Sink: Send {
+    /// 用于日志，例如 influx http://10.0.0.5:8086
+    fn name(&self) -> String;
+    fn write(&mut self, points: &[Point]) -> io::Result<()>;
+}
+
+/// InfluxDB v2 line protocol over HTTP.
+pub struct Influx {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+    host: String,
+}
+
+impl Influx {
+    pub fn new(url: &str, org: &str, bucket: &str, token: Option<String>, host: &str) -> Influx {
+        let config = ureq::Agent::config_builder()
+            .timeout_global(Some(WRITE_TIMEOUT))
+            .http_status_as_error(false)
+            .build();
+        let query = |s: &str| s.replace('%', "%25").replace('&', "%26").replace(' ', "%20").replace('#', "%23");
+        Influx {
+            agent: ureq::Agent::new_with_config(config),
+            url: format!("{}/api/v2/write?org={}&bucket={}&precision=s", url.trim_end_matches('/'), query(org), query(bucket)),
+            token,
+            host: host.to_string(),
+        }
+    }
+
+    fn line(&self, point: &Point) -> String {
+        // 标签中的逗号、等号和空格需要转义
+        let escape = |s: &str| s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ");
+        let mut line = format!("smartfan_{},host={}", point.measurement, escape(&self.host));
+        if let Some((tag, value)) = &point.key {
+            line += &format!(",{}={}", tag, escape(value));
+        }
+        let fields: Vec<String> = point.fields.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        format!("{} {} {}", line, fields.join(","), point.time)
+    }
+}
+
+impl Sink for Influx {
+    fn name(&self) -> String {
+        format!("influx {}", self.url.split("/api/").next().unwrap_or_default())
+    }
+
+    fn write(&mut self, points: &[Point]) -> io::Result<()> {
+        let body: String = points.iter().map(|p| self.line(p) + "\n").collect();
+        let mut request = self.agent.post(&self.url).header("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", &format!("Token {}", token));
+        }
+        let response = request.send(&body).map_err(|e| match e {
+            ureq::Error::Io(e) => e,
+            e => io::Error::other(e.to_string()),
+        })?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            status => Err(io::Error::other(format!("HTTP {}", status))),
+        }
+    }
+}
+
+/// Graphite plaintext protocol: `prefix.host.measurement[.key].field value time`.
+pub struct Graphite {
+    address: String,
+    prefix: String,
+}
+
+impl Graphite {
+    pub fn new(address: &str, prefix: &str, host: &str) -> Graphite {
+        let mut prefix = prefix.trim_end_matches('.').to_string();
+        if !prefix.is_empty() {
+            prefix.push('.');
+        }
+        Graphite {
+            address: address.to_string(),
+            prefix: prefix + &path_part(host),
+        }
+    }
+
+    fn lines(&self, point: &Point) -> String {
+        let mut path = format!("{}.{}", self.prefix, point.measurement);
+        if let Some((_, value)) = &point.key {
+            path = path + "." + &path_part(value);
+        }
+        point.fields.iter().map(|(name, value)| format!("{}.{} {} {}\n", path, name, value, point.time)).collect()
+    }
+}
+
+/// Graphite 路径中的点表示层级，其他符号也不安全
+fn path_part(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+impl Sink for Graphite {
+    fn name(&self) -> String {
+        format!("graphite {}", self.address)
+    }
+
+    fn write(&mut self, points: &[Point]) -> io::Result<()> {
+        let addr = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", self.address)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, WRITE_TIMEOUT)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let body: String = points.iter().map(|p| self.lines(p)).collect();
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+/// A sink with the points it has not delivered yet.
+pub struct Buffered {
+    sink: Box<dyn Sink>,
+    pending: VecDeque<Point>,
+    batch_size: usize,
+    buffer_size: usize,
+    /// 上次发送失败，恢复时清除
+    failing: bool,
+}
+
+impl Buffered {
+    pub fn new(sink: Box<dyn Sink>, batch_size: usize, buffer_size: usize) -> Buffered {
+        Buffered {
+            sink,
+            pending: VecDeque::new(),
+            batch_size: batch_size.max(1),
+            buffer_size,
+            failing: false,
+        }
+    }
+
+    pub fn from_config(config: &SinkConfig, host: &str) -> Buffered {
+        let sink: Box<dyn Sink> = match &config.kind {
+            SinkKind::Influx { url, org, bucket, token } => Box::new(Influx::new(url, org, bucket, token.clone(), host)),
+            SinkKind::Graphite { address, prefix } => Box::new(Graphite::new(address, prefix, host)),
+        };
+        Buffered::new(sink, config.batch_size, config.buffer_size)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Queues `points`, dropping the oldest beyond `buffer_size`. Returns how many were dropped.
+    pub fn push(&mut self, points: Vec<Point>) -> usize {
+        self.pending.extend(points);
+        let dropped = self.pending.len().saturating_sub(self.buffer_size);
+        self.pending.drain(..dropped);
+        dropped
+    }
+
+    /// Sends the queue in batches of `batch_size` and stops at the first failure; what was not
+    /// sent stays queued for the next flush.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.pending.len().min(self.batch_size);
+            let batch: Vec<Point> = self.pending.iter().take(n).cloned().collect();
+            self.sink.write(&batch)?;
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+/// Starts the sinks on a thread that delivers whatever the loop sends it.
+pub fn spawn(config: &[SinkConfig], host: &str, send_to_ui: Sender<Message>) -> mpsc::Sender<Vec<Point>> {
+    let host = if host.is_empty() { "localhost" } else { host };
+    let mut sinks: Vec<Buffered> = config.iter().map(|c| Buffered::from_config(c, host)).collect();
+    let (tx, rx) = mpsc::channel::<Vec<Point>>();
+    std::thread::spawn(move || {
+        let log = |level, msg: String| {
+            let _ = send_to_ui.blocking_send(Message::build_log(level, msg));
+        };
+        for points in rx {
+            for sink in &mut sinks {
+                let dropped = sink.push(points.clone());
+                if dropped > 0 {
+                    log::debug!("{}: dropped {} points", sink.sink.name(), dropped);
+                }
+                // 只在状态变化时记录，避免每次都刷屏
+                match (sink.flush(), sink.failing) {
+                    (Ok(()), true) => {
+                        log(Level::Info, format!("Sink {} is back", sink.sink.name()));
+                        sink.failing = false;
+                    }
+                    (Err(e), false) => {
+                        log(Level::Warn, format!("Sink {} unavailable, buffering: {}", sink.sink.name(), e));
+                        sink.failing = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, HttpStub};
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn reading() -> Reading {
+        testutil::reading()
+            .sensors(&[
+                "CPU1_Temp | 52.000 | degrees C | ok | na | na | na | 93.000 | 100.000 | 105.000",
+                "PSU2_Status | na | discrete | ns | na | na | na | na | na | na",
+            ])
+            .build()
+    }
+
+    #[test]
+    fn test_sinks() {
+        let points = points(1_790_000_000, &reading(), &[(0, 30), (1, 45)], None);
+        assert_eq!(points.len(), 4);
+
+        // InfluxDB：第一次返回 500，点留在缓存里，下一次一起发送
+        let server = HttpStub::start(vec![]);
+        server.respond_with(&["500 Internal Server Error"]);
+        let mut influx = Buffered::new(Box::new(Influx::new(&server.url(), "lab", "fans", Some("secret".to_string()), "bmc 1")), 3, 100);
+        influx.push(points.clone());
+        assert!(influx.flush().is_err());
+        assert_eq!(influx.pending(), 4);
+        influx.flush().unwrap();
+        assert_eq!(influx.pending(), 0);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].path, "/api/v2/write?org=lab&bucket=fans&precision=s");
+        assert_eq!(requests[1].header("Authorization"), Some("Token secret"));
+        assert_eq!(
+            requests[1].body,
+            "smartfan_sensor,host=bmc\\ 1,sensor=CPU1_Temp value=52 1790000000\n\
+             smartfan_duty,host=bmc\\ 1,zone=0 duty=30 1790000000\n\
+             smartfan_duty,host=bmc\\ 1,zone=1 duty=45 1790000000\n"
+        );
+        assert_eq!(requests[2].body, "smartfan_loop,host=bmc\\ 1 temperature=52,speed=30 1790000000\n");
+
+        // Graphite：端口没有监听时缓存，超出 buffer_size 丢弃最早的
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut graphite = Buffered::new(Box::new(Graphite::new(&address, "lab.", "10.0.0.2")), 10, 3);
+        assert_eq!(graphite.push(points.clone()), 1);
+        assert!(graphite.flush().is_err());
+        assert_eq!(graphite.pending(), 3);
+        let listener = TcpListener::bind(&address).unwrap();
+        graphite.flush().unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        assert_eq!(
+            received,
+            "lab.10_0_0_2.duty.0.duty 30 1790000000\n\
+             lab.10_0_0_2.duty.1.duty 45 1790000000\n\
+             lab.10_0_0_2.loop.temperature 52 1790000000\n\
+             lab.10_0_0_2.loop.speed 30 1790000000\n"
+        );
+    }
+}
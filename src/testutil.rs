@@ -0,0 +1,225 @@
+//! Local network stand-ins and fixtures shared by the tests.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::control::Reading;
+use crate::profile::Profile;
+use crate::sensor_result::SensorResult;
+
+/// Parses `ipmitool sensor` lines.
+pub(crate) fn sensors<S: AsRef<str>>(lines: &[S]) -> Vec<SensorResult> {
+    lines.iter().map(|l| SensorResult::from_line(l.as_ref()).unwrap()).collect()
+}
+
+/// A built-in profile by name, e.g. `lenovo-hr650x`.
+pub(crate) fn builtin_profile(name: &str) -> Profile {
+    Profile::builtin().into_iter().find(|p| p.name == name).unwrap()
+}
+
+/// Builds a [`Reading`]: 52℃ at 30% with one active CPU, and no fans, power or sensors until they
+/// are added.
+pub(crate) struct ReadingBuilder(Reading);
+
+pub(crate) fn reading() -> ReadingBuilder {
+    ReadingBuilder(Reading {
+        time: String::new(),
+        temperature: 52.0,
+        speed: Some(30),
+        zone_speeds: vec![],
+        cpus: (1, 1),
+        fans: vec![],
+        zones: vec![],
+        power: vec![],
+        alarms: vec![],
+        fan_faults: vec![],
+        sensors: vec![],
+    })
+}
+
+impl ReadingBuilder {
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.0.temperature = temperature;
+        self
+    }
+
+    pub fn speed(mut self, speed: u8) -> Self {
+        self.0.speed = Some(speed);
+        self
+    }
+
+    /// `ipmitool sensor` lines.
+    pub fn sensors(mut self, lines: &[&str]) -> Self {
+        self.0.sensors = sensors(lines);
+        self
+    }
+
+    pub fn fans(mut self, fans: &[(&str, f64)]) -> Self {
+        self.0.fans = fans.iter().map(|(name, rpm)| (name.to_string(), *rpm)).collect();
+        self
+    }
+
+    pub fn power(mut self, power: &[(&str, f64)]) -> Self {
+        self.0.power = power.iter().map(|(name, watts)| (name.to_string(), *watts)).collect();
+        self
+    }
+
+    pub fn build(self) -> Reading {
+        self.0
+    }
+}
+
+/// A request seen by [`HttpStub`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Minimal HTTP/1.1 server answering GETs from canned bodies and everything else with `{}`,
+/// using the statuses queued by [`HttpStub::respond_with`] first and `200 OK` after them.
+/// Every request is recorded; unknown GET paths get a 404.
+pub(crate) struct HttpStub {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+    statuses: Arc<Mutex<VecDeque<String>>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl HttpStub {
+    pub fn start(routes: Vec<(&str, String)>) -> HttpStub {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes: Vec<(String, String)> = routes.into_iter().map(|(p, b)| (p.to_string(), b)).collect();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let statuses = Arc::new(Mutex::new(VecDeque::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let requests = requests.clone();
+            let statuses = statuses.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, _)) => serve(stream, &routes, &statuses, &requests).unwrap_or_default(),
+                        Err(_) => thread::sleep(Duration::from_millis(5)),
+                    }
+                }
+            })
+        };
+        HttpStub {
+            addr,
+            requests,
+            statuses,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answers the next requests other than GET with these statuses, e.g. `500 Internal Server Error`.
+    pub fn respond_with(&self, statuses: &[&str]) {
+        self.statuses.lock().unwrap().extend(statuses.iter().map(|s| s.to_string()));
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for HttpStub {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Records the request before answering, so it is visible as soon as the client has the response.
+fn serve(
+    stream: TcpStream,
+    routes: &[(String, String)],
+    statuses: &Mutex<VecDeque<String>>,
+    requests: &Mutex<Vec<HttpRequest>>,
+) -> Option<()> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(2))).ok()?;
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    let len = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).ok()?;
+
+    let route_path = path.split('?').next().unwrap_or_default();
+    let (status, rsp) = if method == "GET" {
+        match routes.iter().find(|(p, _)| p == route_path) {
+            Some((_, b)) => ("200 OK".to_string(), b.clone()),
+            None => ("404 Not Found".to_string(), "{}".to_string()),
+        }
+    } else {
+        let status = statuses.lock().unwrap().pop_front();
+        (status.unwrap_or_else(|| "200 OK".to_string()), "{}".to_string())
+    };
+    requests.lock().unwrap().push(HttpRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    });
+
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        rsp.len(),
+        rsp
+    );
+    let _ = stream.flush();
+    Some(())
+}
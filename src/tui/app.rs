@@ -1,4 +1,3 @@
-use std::ops::Add;
 use ratatui::widgets::ListState;
 use tokio::sync::mpsc::{Receiver, Sender};
 
@@ -8,6 +7,8 @@ const TASKS: [&str; 24] = [
     "Item20", "Item21", "Item22", "Item23", "Item24",
 ];
 
+// 演示数据，暂未使用
+#[allow(dead_code)]
 const LOGS: [(&str, &str); 26] = [
     ("Event1", "INFO"),
     ("Event2", "INFO"),
@@ -37,6 +38,7 @@ const LOGS: [(&str, &str); 26] = [
     ("Event26", "INFO"),
 ];
 
+#[allow(dead_code)]
 const EVENTS: [(&str, u64); 24] = [
     ("B1", 9),
     ("B2", 12),
@@ -132,9 +134,12 @@ pub struct Signals {
     pub data1: Vec<(String, f64)>,
     pub data2: Vec<(String, f64)>,
     pub window: [f64; 2],
+    /// data1、data2 开头有多少个从数据库加载的点，它们的间隔不是 15 秒
+    pub history_points: usize,
 }
 
 impl Signals {
+    #[allow(dead_code)]
     fn on_tick(&mut self) {
         self.window[0] += 1.0;
         self.window[1] += 1.0;
@@ -150,6 +155,9 @@ pub struct Server<'a> {
 
 pub struct App<'a> {
     pub title: &'a str,
+    pub server_model: Option<String>,
+    /// PID 模式的目标温度，显示在历史图上
+    pub setpoint: Option<f64>,
     pub should_quit: bool,
     pub tabs: TabsState<'a>,
     pub show_chart: bool,
@@ -162,6 +170,12 @@ pub struct App<'a> {
     pub signals: Signals,
     pub barchart_speed: Vec<(String, u64)>,
     pub barchart_temp: Vec<(String, u64)>,
+    /// 按 zone 分组的风扇转速：zone、转速%、各风扇 RPM
+    pub barchart_zones: Vec<crate::sensor::ZoneFans>,
+    /// 最近一次读数的告警级别
+    pub alarms: Vec<crate::alarm::Alarm>,
+    /// 故障的风扇，直到恢复前一直显示
+    pub fan_faults: Vec<crate::fan_check::FanFault>,
     pub servers: Vec<Server<'a>>,
     pub enhanced_graphics: bool,
     pub event_receiver_from_ipmi: Receiver<crate::Message>,
@@ -177,6 +191,8 @@ impl<'a> App<'a> {
     ) -> Self {
         App {
             title,
+            server_model: None,
+            setpoint: None,
             should_quit: false,
             tabs: TabsState::new(vec!["监控", "设置"]),
             show_chart: true,
@@ -187,11 +203,15 @@ impl<'a> App<'a> {
                 data1: vec![],
                 data2: vec![],
                 window: [0.0, 180.0],
+                history_points: 0,
             },
             speed_list: StatefulList::with_items(vec![]),
             temp_list: StatefulList::with_items(vec![]),
             watt_list: StatefulList::with_items(vec![]),
             barchart_temp: vec![],
+            barchart_zones: vec![],
+            alarms: vec![],
+            fan_faults: vec![],
             barchart_speed: vec![],
             servers: vec![
                 Server {
@@ -249,6 +269,13 @@ impl<'a> App<'a> {
             't' => {
                 self.show_chart = !self.show_chart;
             }
+            // 暂停和恢复自动控制，结果显示在日志中
+            'p' => {
+                let _ = self.ui_event_sender.try_send(crate::UIMessage::Control(crate::control::Request::Pause));
+            }
+            'r' => {
+                let _ = self.ui_event_sender.try_send(crate::UIMessage::Control(crate::control::Request::Resume));
+            }
             _ => {}
         }
     }
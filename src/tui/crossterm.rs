@@ -14,7 +14,6 @@ use ratatui::{
 use crate::{tui::app::App, tui::ui, Message};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::mpsc::error::TryRecvError;
-use chrono::Local;
 
 pub fn run(
     enhanced_graphics: bool,
@@ -85,7 +84,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App<'_>) -> io::Resu
                             }
                             app.logs.items.insert(0, (l, format!("{} {}", time, m)));
                         },
-                        Message::GotCpuAndFansSpeed(time_str, (cpu, max_cpu), fans) => {
+                        Message::GotCpuAndFansSpeed(_, _, fans) => {
                             app.barchart_temp.clear();
                             fans
                                 .iter()
@@ -95,6 +94,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App<'_>) -> io::Resu
                             let max = app.signals.window[1] - app.signals.window[0];
                             if app.signals.data1.len() > max as usize {
                                 app.signals.data1.remove(0);
+                                app.signals.history_points = app.signals.history_points.saturating_sub(1);
                             }
                             app.signals.data1.push((time_str.clone(), temp));
 
@@ -113,10 +113,38 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App<'_>) -> io::Resu
                             }
                             app.temp_list.items.insert(0, (time_str.clone(), temp));
                         },
-                        Message::Power(time_str, vec) => {
+                        Message::Power(_, vec) => {
                             app.watt_list.items.clear();
                             app.watt_list.items.extend(vec);
                         },
+                        Message::ServerModel(_, model) => {
+                            app.server_model = Some(model);
+                        },
+                        Message::ZoneFans(_, zones) => {
+                            app.barchart_zones = zones;
+                        },
+                        Message::Safety(time, event) => {
+                            if app.logs.items.len() > 50 {
+                                app.logs.items.pop();
+                            }
+                            app.logs.items.insert(0, (event.level(), format!("{} {}", time, event)));
+                        },
+                        Message::Alarms(_, alarms) => {
+                            app.alarms = alarms;
+                        },
+                        Message::FanFaults(_, faults) => {
+                            app.fan_faults = faults;
+                        },
+                        Message::Setpoint(_, setpoint) => {
+                            app.setpoint = Some(setpoint);
+                        },
+                        Message::History(points) => {
+                            let max = (app.signals.window[1] - app.signals.window[0]) as usize;
+                            let points = &points[points.len().saturating_sub(max)..];
+                            app.signals.data1 = points.iter().map(|(time, temp, _)| (time.clone(), *temp)).collect();
+                            app.signals.data2 = points.iter().map(|(time, _, speed)| (time.clone(), *speed)).collect();
+                            app.signals.history_points = points.len();
+                        },
                         _ => {}
                     }
                 }
@@ -1,4 +1,3 @@
-use std::fmt::format;
 use ratatui::{
     layout::{Constraint, Layout, Rect},
     style::{Color, Modifier, Style},
@@ -6,15 +5,19 @@ use ratatui::{
     text::{self, Span},
     widgets::{
         canvas::{self, Canvas, Circle, Map, MapResolution, Rectangle},
-        Axis, BarChart, Block, Cell, Chart, Dataset, List, ListItem, Paragraph, Row, Table, Tabs,
-        Wrap,
+        Axis, Bar, BarChart, BarGroup, Block, Cell, Chart, Dataset, GraphType, List, ListItem, Row, Table, Tabs,
     },
     Frame,
 };
 
+use crate::alarm::{Alarm, Severity};
 use crate::tui::app::App;
 
 pub fn draw(frame: &mut Frame, app: &mut App) {
+    let title = match &app.server_model {
+        Some(model) => format!("{} - {}", app.title, model),
+        None => app.title.to_string(),
+    };
     let chunks = Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).split(frame.area());
     let tabs = app
         .tabs
@@ -22,7 +25,7 @@ pub fn draw(frame: &mut Frame, app: &mut App) {
         .iter()
         .map(|t| text::Line::from(Span::styled(*t, Style::default().fg(Color::Green))))
         .collect::<Tabs>()
-        .block(Block::bordered().title(app.title))
+        .block(Block::bordered().title(title))
         .highlight_style(Style::default().fg(Color::Yellow))
         .select(app.tabs.index);
     frame.render_widget(tabs, chunks[0]);
@@ -41,7 +44,41 @@ fn draw_first_tab(frame: &mut Frame, app: &mut App, area: Rect) {
     ])
     .split(area);
     draw_charts(frame, app, chunks[0]);
-    draw_text(frame, app, chunks[1]);
+    let chunks = Layout::horizontal([Constraint::Percentage(65), Constraint::Percentage(35)]).split(chunks[1]);
+    draw_text(frame, app, chunks[0]);
+    draw_alarms(frame, app, chunks[1]);
+}
+
+fn draw_alarms(frame: &mut Frame, app: &mut App, area: Rect) {
+    let mut alarms: Vec<&Alarm> = app.alarms.iter().filter(|a| a.severity != Severity::Ok).collect();
+    alarms.sort_by_key(|a| std::cmp::Reverse(a.severity));
+    let fan_style = Style::default().fg(Color::White).bg(Color::Red).add_modifier(Modifier::BOLD);
+    let mut items: Vec<ListItem> = app
+        .fan_faults
+        .iter()
+        .map(|f| ListItem::new(Span::styled(format!("风扇故障/Fan failure: {}", f), fan_style)))
+        .collect();
+    items.extend(alarms
+        .iter()
+        .map(|a| {
+            let style = match a.severity {
+                Severity::NonRecoverable => Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD),
+                Severity::Critical => Style::default().fg(Color::Red),
+                _ => Style::default().fg(Color::Yellow),
+            };
+            let value = match a.value {
+                Some(v) => format!("{} {}", v, a.unit.as_deref().unwrap_or_default()),
+                None => "na".to_string(),
+            };
+            ListItem::new(text::Line::from(vec![
+                Span::styled(format!("{:<16}", a.severity.to_string()), style),
+                Span::raw(format!("{} {}", a.sensor, value)),
+            ]))
+        }));
+    let ok = app.alarms.len() - alarms.len();
+    items.push(ListItem::new(Span::styled(format!("{} ok", ok), Style::default().fg(Color::Green))));
+    let list = List::new(items).block(Block::bordered().title("告警/Alarms"));
+    frame.render_widget(list, area);
 }
 
 #[allow(clippy::too_many_lines)]
@@ -103,9 +140,24 @@ fn draw_charts(frame: &mut Frame, app: &mut App, area: Rect) {
             frame.render_stateful_widget(tasks, chunks[2], &mut app.speed_list.state);
         }
         let bar_chart_grouped_temp_data: &Vec<(&str, u64)> = &app.barchart_temp.iter().map(|(x, y)| (x.as_str(), *y)).collect();
-        let barchart = BarChart::default()
-            .block(Block::bordered().title("各风扇转速/Each Fan Speed(RPM)"))
-            .data(bar_chart_grouped_temp_data)
+        let mut barchart = BarChart::default()
+            .block(Block::bordered().title("各风扇转速/Each Fan Speed(RPM)"));
+        if app.barchart_zones.is_empty() {
+            barchart = barchart.data(bar_chart_grouped_temp_data);
+        }
+        // 按 zone 分组，组名显示 zone 的转速
+        for (zone, duty, fans) in &app.barchart_zones {
+            let bars: Vec<Bar> = fans
+                .iter()
+                .map(|(name, speed)| Bar::default().value(*speed as u64).label(text::Line::from(name.as_str())))
+                .collect();
+            let label = match duty {
+                Some(duty) => format!("{} {}%", zone, duty),
+                None => zone.clone(),
+            };
+            barchart = barchart.data(BarGroup::default().label(text::Line::from(label)).bars(&bars));
+        }
+        let barchart = barchart
             .bar_width(5)
             .bar_gap(2)
             .bar_set(if app.enhanced_graphics {
@@ -124,13 +176,18 @@ fn draw_charts(frame: &mut Frame, app: &mut App, area: Rect) {
         frame.render_widget(barchart, chunks[1]);
     }
     if app.show_chart {
+        // 从数据库加载的点间隔更长，显示最早的时间
+        let span = match app.signals.history_points {
+            0 => format!("共计约{:0}秒", (app.signals.window[1] - app.signals.window[0]) * 15.0),
+            _ => format!("自/since {}", app.signals.data1.first().map_or("", |(time, _)| time.as_str())),
+        };
         let x_labels = vec![
             Span::styled(
                 " ",
                 Style::default().add_modifier(Modifier::BOLD),
             ),
             // config.interval
-            Span::raw(format!("共计约{:0}秒", (&app.signals.window[1] - &app.signals.window[0]) * 15.0)),
+            Span::raw(span),
             Span::styled(
                 "现在/Now",
                 Style::default().add_modifier(Modifier::BOLD),
@@ -149,7 +206,13 @@ fn draw_charts(frame: &mut Frame, app: &mut App, area: Rect) {
         //     }
         // }
 
-        let datasets = vec![
+        // 目标温度画成一条水平线
+        let setpoint: Vec<(f64, f64)> = match (app.setpoint, temps.first(), temps.last()) {
+            (Some(y), Some(first), Some(last)) => vec![(first.0, y), (last.0, y)],
+            _ => vec![],
+        };
+
+        let mut datasets = vec![
             Dataset::default()
                 .name("转速%")
                 .marker(symbols::Marker::Dot)
@@ -165,6 +228,16 @@ fn draw_charts(frame: &mut Frame, app: &mut App, area: Rect) {
                 .style(Style::default().fg(Color::Yellow))
                 .data(d1),
         ];
+        if !setpoint.is_empty() {
+            datasets.push(
+                Dataset::default()
+                    .name("目标℃")
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(Color::Red))
+                    .data(&setpoint),
+            );
+        }
         let chart = Chart::new(datasets)
             .block(
                 Block::bordered().title(Span::styled(
@@ -196,14 +269,11 @@ fn draw_charts(frame: &mut Frame, app: &mut App, area: Rect) {
     }
 }
 
-fn get_data_for_chart(data: &Vec<(String, f64)>, window: &[f64; 2]) -> Vec<(f64, f64)> {
+fn get_data_for_chart(data: &[(String, f64)], window: &[f64; 2]) -> Vec<(f64, f64)> {
 
     let length = window[1] - window[0];
     let u_length = length as usize + 1;
-    let mut temp_data: Vec<(f64, f64)> = vec![(0., 0.); u_length];
-    for i in 0..u_length {
-        temp_data[i] = (i as f64, 0.0);
-    }
+    let mut temp_data: Vec<(f64, f64)> = (0..u_length).map(|i| (i as f64, 0.0)).collect();
 
     for (i, num) in data.iter().enumerate() {
         temp_data[u_length - data.len() + i] = ((u_length - data.len() + i) as f64, num.1);
@@ -214,7 +284,6 @@ fn get_data_for_chart(data: &Vec<(String, f64)>, window: &[f64; 2]) -> Vec<(f64,
 fn draw_text(frame: &mut Frame, app: &mut App, area: Rect) {
     let info_style = Style::default().fg(Color::Blue);
     let warning_style = Style::default().fg(Color::Yellow);
-    let error_style = Style::default().fg(Color::Magenta);
     let critical_style = Style::default().fg(Color::Red);
     let logs: Vec<ListItem> = app
         .logs